@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::sync::{ mpsc, Arc, Condvar, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+
+use crate::{
+    entity::{ MaterialData, TextureData },
+    mesh_data::MeshData,
+    logger::{ self, MessageEmitter }
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadState {
+    // A placeholder is in place until a worker thread finishes loading the asset
+    Loading,
+    Loaded,
+    // The placeholder (or, for reloads, the previous asset) stays in place, and the error is logged
+    Failed(String)
+}
+
+struct Slot<T> {
+    asset: RwLock<T>,
+    state: RwLock<LoadState>,
+    // Bumped every time a load or reload replaces the asset, so users can tell their derived data is stale
+    generation: AtomicUsize
+}
+
+// A shared, reference-counted asset. Cloning a handle is cheap; the asset is freed when the last handle drops.
+pub struct Handle<T> {
+    key: Option<Arc<str>>,
+    slot: Arc<Slot<T>>
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            slot: self.slot.clone()
+        }
+    }
+}
+
+impl<T> Handle<T> {
+    fn new(key: Option<Arc<str>>, asset: T, state: LoadState) -> Self {
+        Self {
+            key,
+            slot: Arc::new(Slot {
+                asset: RwLock::new(asset),
+                state: RwLock::new(state),
+                generation: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.slot.asset.read().unwrap()
+    }
+
+    // Anything derived from the asset (GPU buffers, levels of detail, ...) only notices changes made here after `touch`
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.slot.asset.write().unwrap()
+    }
+
+    pub fn touch(&self) {
+        self.slot.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn state(&self) -> LoadState {
+        self.slot.state.read().unwrap().clone()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.state() == LoadState::Loaded
+    }
+
+    pub fn generation(&self) -> usize {
+        self.slot.generation.load(Ordering::Acquire)
+    }
+
+    // The key the asset was loaded under, `None` for assets added directly
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    // Identifies the asset; unique for as long as a handle to it is alive, so usable as a cache key by holding on to one
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.slot) as usize
+    }
+
+    pub fn ptr_eq(&self, other: &Handle<T>) -> bool {
+        Arc::ptr_eq(&self.slot, &other.slot)
+    }
+
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.slot)
+    }
+}
+
+// Assets deduplicated by key (usually a file path). The store only keeps weak references,
+// so it never keeps an asset alive on its own.
+pub struct Assets<T> {
+    entries: Mutex<HashMap<Arc<str>, Weak<Slot<T>>>>
+}
+
+impl<T> Assets<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Returns the asset loaded under `key` if it is still alive, otherwise loads it with `load` on the calling thread
+    pub fn load(&self, key: &str, load: impl FnOnce() -> T) -> Handle<T> {
+        if let Some(handle) = self.get(key) {
+            return handle;
+        }
+
+        // Loading happens outside the lock, so loaders may load other assets from the same store
+        self.insert(key, load(), LoadState::Loaded).0
+    }
+
+    // Like `load`, but `load` runs on a worker thread and the handle holds `placeholder` until it finishes
+    pub fn load_async(&self, key: &str, placeholder: T, load: impl FnOnce() -> Result<T, String> + Send + 'static) -> Handle<T>
+    where
+        T: Send + Sync + 'static
+    {
+        if let Some(handle) = self.get(key) {
+            return handle;
+        }
+
+        let (handle, inserted) = self.insert(key, placeholder, LoadState::Loading);
+        if !inserted {
+            return handle;
+        }
+
+        queue_load(&handle, load);
+
+        handle
+    }
+
+    // Loads the asset again on a worker thread. The current asset stays in place until the new one is ready,
+    // and is kept if loading fails.
+    pub fn reload_async(&self, handle: &Handle<T>, load: impl FnOnce() -> Result<T, String> + Send + 'static)
+    where
+        T: Send + Sync + 'static
+    {
+        queue_load(handle, load);
+    }
+
+    // Every keyed asset that is still alive
+    pub fn handles(&self) -> Vec<Handle<T>> {
+        let entries = self.entries.lock().unwrap();
+
+        entries.iter()
+            .filter_map(|(key, slot)| slot.upgrade().map(|slot| Handle { key: Some(key.clone()), slot }))
+            .collect()
+    }
+
+    // Adds an asset that isn't backed by a file (generated meshes, materials, ...), which is never shared by key
+    pub fn add(&self, asset: T) -> Handle<T> {
+        Handle::new(None, asset, LoadState::Loaded)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Handle<T>> {
+        let entries = self.entries.lock().unwrap();
+        let (key, slot) = entries.get_key_value(key)?;
+
+        slot.upgrade().map(|slot| Handle { key: Some(key.clone()), slot })
+    }
+
+    // Number of keyed assets still alive; entries whose last handle dropped are pruned here
+    pub fn len(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, slot| slot.strong_count() > 0);
+
+        entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Background loads of this store's live assets. Unlike `progress`, loads queued by other stores don't count.
+    pub fn progress(&self) -> LoadProgress {
+        let entries = self.entries.lock().unwrap();
+        let mut progress = LoadProgress::default();
+
+        for slot in entries.values().filter_map(Weak::upgrade) {
+            progress.queued += 1;
+
+            match *slot.state.read().unwrap() {
+                LoadState::Loading => { },
+                LoadState::Loaded => progress.finished += 1,
+                LoadState::Failed(_) => {
+                    progress.finished += 1;
+                    progress.failed += 1;
+                }
+            }
+        }
+
+        progress
+    }
+
+    // Another thread may have loaded the same asset in the meantime, in which case the first one wins
+    fn insert(&self, key: &str, asset: T, state: LoadState) -> (Handle<T>, bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let key: Arc<str> = key.into();
+
+        if let Some(slot) = entries.get(&key).and_then(Weak::upgrade) {
+            return (Handle { key: Some(key), slot }, false);
+        }
+
+        let handle = Handle::new(Some(key.clone()), asset, state);
+        entries.insert(key, Arc::downgrade(&handle.slot));
+
+        (handle, true)
+    }
+}
+
+impl<T> Default for Assets<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn meshes() -> &'static Assets<MeshData> {
+    static MESHES: OnceLock<Assets<MeshData>> = OnceLock::new();
+    MESHES.get_or_init(Assets::new)
+}
+
+pub fn textures() -> &'static Assets<TextureData> {
+    static TEXTURES: OnceLock<Assets<TextureData>> = OnceLock::new();
+    TEXTURES.get_or_init(Assets::new)
+}
+
+pub fn materials() -> &'static Assets<MaterialData> {
+    static MATERIALS: OnceLock<Assets<MaterialData>> = OnceLock::new();
+    MATERIALS.get_or_init(Assets::new)
+}
+
+// Background loads across all stores
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadProgress {
+    pub queued: usize,
+    pub finished: usize,
+    pub failed: usize
+}
+
+impl LoadProgress {
+    pub fn is_done(&self) -> bool {
+        self.finished == self.queued
+    }
+
+    pub fn fraction(&self) -> f32 {
+        if self.queued == 0 { 1.0 } else { self.finished as f32 / self.queued as f32 }
+    }
+}
+
+// A load job returns whether it succeeded
+type LoadJob = Box<dyn FnOnce() -> bool + Send>;
+
+struct Loader {
+    jobs: Mutex<mpsc::Sender<LoadJob>>,
+    progress: Mutex<LoadProgress>,
+    progress_changed: Condvar
+}
+
+fn loader() -> &'static Loader {
+    static LOADER: OnceLock<Loader> = OnceLock::new();
+
+    LOADER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<LoadJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(2).clamp(1, 4);
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("asset-loader-{}", i))
+                .spawn(move || {
+                    // Loaders log warnings and failures, which the engine picks up on the main thread
+                    logger::forward_to_main_thread();
+
+                    loop {
+                        let job = match receiver.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break
+                        };
+
+                        let succeeded = job();
+                        let loader = loader();
+                        let mut progress = loader.progress.lock().unwrap();
+
+                        if !succeeded {
+                            progress.failed += 1;
+                        }
+
+                        progress.finished += 1;
+                        loader.progress_changed.notify_all();
+                    }
+                })
+                .unwrap();
+        }
+
+        Loader {
+            jobs: Mutex::new(sender),
+            progress: Mutex::new(LoadProgress::default()),
+            progress_changed: Condvar::new()
+        }
+    })
+}
+
+fn queue_load<T: Send + Sync + 'static>(handle: &Handle<T>, load: impl FnOnce() -> Result<T, String> + Send + 'static) {
+    // The worker only holds a weak reference, so an asset dropped while loading is discarded when it finishes
+    let slot = Arc::downgrade(&handle.slot);
+    let key = handle.key().unwrap_or("<unnamed>").to_string();
+
+    let job: LoadJob = Box::new(move || {
+        let result = load();
+        let slot = match slot.upgrade() {
+            Some(slot) => slot,
+            None => return result.is_ok()
+        };
+
+        match result {
+            Ok(asset) => {
+                *slot.asset.write().unwrap() = asset;
+                *slot.state.write().unwrap() = LoadState::Loaded;
+                slot.generation.fetch_add(1, Ordering::AcqRel);
+
+                true
+            },
+            Err(e) => {
+                logger::log_error(&format!("Unable to load '{}', keeping what was drawn in its place: {}", key, e), MessageEmitter::World);
+                *slot.state.write().unwrap() = LoadState::Failed(e);
+
+                false
+            }
+        }
+    });
+
+    let loader = loader();
+
+    loader.progress.lock().unwrap().queued += 1;
+    loader.jobs.lock().unwrap().send(job).unwrap();
+}
+
+pub fn progress() -> LoadProgress {
+    *loader().progress.lock().unwrap()
+}
+
+// Blocks until every queued load has finished, calling `on_progress` whenever a load completes
+pub fn wait_for_loads(mut on_progress: impl FnMut(LoadProgress)) {
+    let loader = loader();
+    let mut progress = loader.progress.lock().unwrap();
+
+    loop {
+        let current = *progress;
+        drop(progress);
+
+        on_progress(current);
+
+        if current.is_done() {
+            break;
+        }
+
+        progress = loader.progress.lock().unwrap();
+        while *progress == current {
+            progress = loader.progress_changed.wait(progress).unwrap();
+        }
+    }
+}
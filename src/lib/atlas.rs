@@ -0,0 +1,398 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use vulkano::format::Format;
+
+use crate::{
+    mesh_data::MeshData,
+    texture_formats::{ self, ColorSpace, DecodedImage }
+};
+
+// Where an image ended up in an atlas, in pixels from the top left corner
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtlasRegion {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+// The layout of a packed atlas, which is all a renderer needs to draw from it
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtlasDescription {
+    // Path of the atlas image, relative to the description
+    pub image: String,
+    pub width: u32,
+    pub height: u32,
+    pub regions: Vec<AtlasRegion>
+}
+
+pub struct Atlas {
+    pub description: AtlasDescription,
+    // Always R8G8B8A8, in the color space of the packed images
+    pub image: DecodedImage
+}
+
+// Packs images into a single texture, so that meshes and sprites using any of them can share one texture bind
+pub struct AtlasBuilder {
+    // Gap kept around each image, filled by extending its edges so that filtering and mipmapping don't
+    // pick up texels from its neighbours
+    pub padding: u32,
+    pub max_size: u32,
+    images: Vec<(String, DecodedImage)>
+}
+
+impl AtlasBuilder {
+    pub fn new(padding: u32, max_size: u32) -> Self {
+        Self {
+            padding,
+            max_size,
+            images: Vec::new()
+        }
+    }
+
+    // Images must decode to 8-bit RGBA in the same color space; block compressed and HDR images can't be packed
+    pub fn add(&mut self, name: &str, image: DecodedImage) -> Result<(), String> {
+        if texture_formats::with_color_space(image.format, ColorSpace::Linear) != Format::R8G8B8A8Unorm {
+            return Err(format!("Unable to pack '{}': {:?} images can't be packed", name, image.format));
+        }
+
+        if let Some((_, first)) = self.images.first() {
+            if first.format != image.format {
+                return Err(format!("Unable to pack '{}': the atlas holds {:?} images, not {:?}", name, first.format, image.format));
+            }
+        }
+
+        if image.width == 0 || image.height == 0 {
+            return Err(format!("Unable to pack '{}': the image is empty", name));
+        }
+
+        self.images.push((name.into(), image));
+
+        Ok(())
+    }
+
+    // Adds the image under its path, which is how submesh materials refer to their textures
+    pub fn add_file(&mut self, path: &str, color_space: ColorSpace) -> Result<(), String> {
+        let image = texture_formats::decode(path, color_space)?;
+
+        self.add(path, image)
+    }
+
+    // Splits a sprite sheet laid out in a grid into frames named "{name}_{index}", row by row
+    pub fn add_sprite_sheet(&mut self, name: &str, sheet: &DecodedImage, frame_width: u32, frame_height: u32) -> Result<usize, String> {
+        if frame_width == 0 || frame_height == 0 || sheet.width < frame_width || sheet.height < frame_height {
+            return Err(format!("Unable to split '{}' into {}x{} frames", name, frame_width, frame_height));
+        }
+
+        let (columns, rows) = (sheet.width / frame_width, sheet.height / frame_height);
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let frame = crop(sheet, column * frame_width, row * frame_height, frame_width, frame_height);
+                self.add(&format!("{}_{}", name, row * columns + column), frame)?;
+            }
+        }
+
+        Ok((columns * rows) as usize)
+    }
+
+    // Packs every image into the smallest power of two square (or 2:1 rectangle) that fits, up to `max_size`
+    pub fn build(&self, image_path: &str) -> Result<Atlas, String> {
+        let sizes: Vec<(u32, u32)> = self.images.iter()
+            .map(|(_, image)| (image.width + self.padding * 2, image.height + self.padding * 2))
+            .collect();
+
+        let area: u64 = sizes.iter().map(|&(w, h)| w as u64 * h as u64).sum();
+        let mut width = 1;
+        let mut height = 1;
+
+        while (width as u64 * height as u64) < area {
+            if width <= height { width *= 2 } else { height *= 2 }
+        }
+
+        let placements = loop {
+            if width > self.max_size || height > self.max_size {
+                return Err(format!("Unable to fit {} images into a {}x{} atlas", self.images.len(), self.max_size, self.max_size));
+            }
+
+            if let Some(placements) = pack(&sizes, width, height) {
+                break placements;
+            }
+
+            if width <= height { width *= 2 } else { height *= 2 }
+        };
+
+        let format = self.images.first().map_or(Format::R8G8B8A8Srgb, |(_, image)| image.format);
+        let mut pixels = vec![0; (width * height * 4) as usize];
+        let mut regions = Vec::with_capacity(self.images.len());
+
+        for ((name, image), (x, y)) in self.images.iter().zip(placements) {
+            let region = AtlasRegion {
+                name: name.clone(),
+                x: x + self.padding,
+                y: y + self.padding,
+                width: image.width,
+                height: image.height
+            };
+
+            blit(image, &mut pixels, width, &region, self.padding);
+            regions.push(region);
+        }
+
+        Ok(Atlas {
+            description: AtlasDescription {
+                image: image_path.into(),
+                width,
+                height,
+                regions
+            },
+            image: DecodedImage {
+                width,
+                height,
+                format,
+                bytes: pixels
+            }
+        })
+    }
+}
+
+impl Atlas {
+    // Writes the image as a PNG to the path in the description, and the description to `description_path`
+    pub fn save(&self, description_path: &str) -> Result<(), String> {
+        let image_path = Path::new(description_path).with_file_name(&self.description.image);
+
+        image::save_buffer(&image_path, &self.image.bytes, self.image.width, self.image.height, image::ColorType::Rgba8)
+            .map_err(|e| format!("Unable to write atlas image '{}': {}", image_path.display(), e))?;
+
+        self.description.save(description_path)
+    }
+}
+
+impl AtlasDescription {
+    // One line per entry: `atlas <width> <height> <image>`, then `region <x> <y> <width> <height> <name>` for each region
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut text = format!("atlas {} {} {}\n", self.width, self.height, self.image);
+
+        for r in &self.regions {
+            let _ = writeln!(text, "region {} {} {} {} {}", r.x, r.y, r.width, r.height, r.name);
+        }
+
+        fs::write(path, text).map_err(|e| format!("Unable to write atlas description '{}': {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read atlas description '{}': {}", path, e))?;
+        let mut description = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let error = || format!("Invalid atlas description '{}' (line {}): {}", path, number + 1, line);
+
+            // Names and paths come last, so they may contain spaces
+            let mut fields = line.splitn(6, ' ');
+
+            match fields.next() {
+                Some("atlas") => {
+                    let mut fields = line.splitn(4, ' ').skip(1);
+                    let width = fields.next().and_then(|f| f.parse().ok()).ok_or_else(error)?;
+                    let height = fields.next().and_then(|f| f.parse().ok()).ok_or_else(error)?;
+                    let image = fields.next().ok_or_else(error)?.to_string();
+
+                    description = Some(AtlasDescription { image, width, height, regions: Vec::new() });
+                },
+                Some("region") => {
+                    let mut number = || fields.next().and_then(|f| f.parse::<u32>().ok()).ok_or_else(error);
+                    let (x, y, width, height) = (number()?, number()?, number()?, number()?);
+                    let name = fields.next().ok_or_else(error)?.to_string();
+
+                    description.as_mut().ok_or_else(error)?.regions.push(AtlasRegion { name, x, y, width, height });
+                },
+                Some("") | None => { },
+                Some(_) => return Err(error())
+            }
+        }
+
+        description.ok_or_else(|| format!("Invalid atlas description '{}': missing atlas line", path))
+    }
+
+    pub fn region(&self, name: &str) -> Option<&AtlasRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    // Texture coordinates of a region as [u_min, v_min, u_max, v_max], e.g. for drawing it as a sprite
+    pub fn uv_rect(&self, region: &AtlasRegion) -> [f32; 4] {
+        [
+            region.x as f32 / self.width as f32,
+            region.y as f32 / self.height as f32,
+            (region.x + region.width) as f32 / self.width as f32,
+            (region.y + region.height) as f32 / self.height as f32
+        ]
+    }
+
+    // Maps a coordinate on the original image to the same texel in the atlas
+    pub fn map_uv(&self, region: &AtlasRegion, uv: [f32; 2]) -> [f32; 2] {
+        let [u_min, v_min, u_max, v_max] = self.uv_rect(region);
+
+        [u_min + uv[0] * (u_max - u_min), v_min + uv[1] * (v_max - v_min)]
+    }
+}
+
+impl MeshData {
+    // Points the UVs of every submesh whose diffuse texture was packed into `atlas` at its region, and the submesh at
+    // the atlas image. Submeshes then only differ by texture if some weren't packed, so `merge_submeshes` can join them.
+    // Tiling textures (UVs outside 0..1) are left on their own texture, as they would sample their neighbours.
+    pub fn remap_to_atlas(&mut self, atlas: &AtlasDescription, atlas_image: &str) -> usize {
+        let parts = self.parts();
+        let original = self.vertices.clone();
+        let mut assigned: Vec<Option<usize>> = vec![None; self.vertices.len()];
+        let mut remapped = 0;
+
+        let regions: Vec<Option<&AtlasRegion>> = parts.iter()
+            .map(|part| {
+                let region = part.material.as_ref().and_then(|m| m.diffuse_texture.as_ref()).and_then(|t| atlas.region(t))?;
+                let tiling = self.indices[part.first_index..part.first_index + part.index_count].iter()
+                    .any(|&i| original[i as usize].uv.iter().any(|c| !(-1e-4..=1.0 + 1e-4).contains(c)));
+
+                if tiling { None } else { Some(region) }
+            })
+            .collect();
+
+        // Parts that keep their texture keep their vertices too, so packed parts sharing them get their own copies
+        for (part_index, part) in parts.iter().enumerate().filter(|(i, _)| regions[*i].is_none()) {
+            for &index in &self.indices[part.first_index..part.first_index + part.index_count] {
+                assigned[index as usize].get_or_insert(part_index);
+            }
+        }
+
+        for (part_index, part) in parts.iter().enumerate() {
+            let region = match regions[part_index] {
+                Some(region) => region,
+                None => continue
+            };
+
+            for index in &mut self.indices[part.first_index..part.first_index + part.index_count] {
+                let source = *index as usize;
+                let mut vertex = source;
+
+                match assigned[vertex] {
+                    Some(owner) if owner == part_index => continue,
+                    // Shared with a submesh using another texture, so this one gets its own copy
+                    Some(_) => {
+                        vertex = self.vertices.len();
+                        self.vertices.push(original[source]);
+                        assigned.push(Some(part_index));
+                        *index = vertex as u32;
+                    },
+                    None => assigned[vertex] = Some(part_index)
+                }
+
+                self.vertices[vertex].uv = atlas.map_uv(region, original[source].uv);
+            }
+
+            if let Some(material) = self.submeshes.get_mut(part_index).and_then(|s| s.material.as_mut()) {
+                material.diffuse_texture = Some(atlas_image.into());
+            }
+
+            remapped += 1;
+        }
+
+        remapped
+    }
+}
+
+// Best short side fit MaxRects: each rectangle goes into the free space it fills most snugly, and the free space
+// is kept as a list of maximal, possibly overlapping rectangles. Returns the top left corner of each rectangle.
+fn pack(sizes: &[(u32, u32)], width: u32, height: u32) -> Option<Vec<(u32, u32)>> {
+    #[derive(Clone, Copy)]
+    struct Rect { x: u32, y: u32, w: u32, h: u32 }
+
+    impl Rect {
+        fn intersects(&self, o: &Rect) -> bool {
+            self.x < o.x + o.w && o.x < self.x + self.w && self.y < o.y + o.h && o.y < self.y + self.h
+        }
+
+        fn contains(&self, o: &Rect) -> bool {
+            o.x >= self.x && o.y >= self.y && o.x + o.w <= self.x + self.w && o.y + o.h <= self.y + self.h
+        }
+    }
+
+    // Larger rectangles first pack tighter
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse((sizes[i].0.max(sizes[i].1), sizes[i].0.min(sizes[i].1))));
+
+    let mut free = vec![Rect { x: 0, y: 0, w: width, h: height }];
+    let mut placements = vec![(0, 0); sizes.len()];
+
+    for i in order {
+        let (w, h) = sizes[i];
+
+        let placed = free.iter()
+            .filter(|f| f.w >= w && f.h >= h)
+            .min_by_key(|f| ((f.w - w).min(f.h - h), (f.w - w).max(f.h - h)))
+            .map(|f| Rect { x: f.x, y: f.y, w, h })?;
+
+        placements[i] = (placed.x, placed.y);
+
+        // Split every free rectangle the placement overlaps into the (up to four) parts around it
+        let mut split = Vec::new();
+
+        for f in free.drain(..) {
+            if !f.intersects(&placed) {
+                split.push(f);
+                continue;
+            }
+
+            if placed.x > f.x {
+                split.push(Rect { w: placed.x - f.x, ..f });
+            }
+            if placed.x + placed.w < f.x + f.w {
+                split.push(Rect { x: placed.x + placed.w, w: f.x + f.w - placed.x - placed.w, ..f });
+            }
+            if placed.y > f.y {
+                split.push(Rect { h: placed.y - f.y, ..f });
+            }
+            if placed.y + placed.h < f.y + f.h {
+                split.push(Rect { y: placed.y + placed.h, h: f.y + f.h - placed.y - placed.h, ..f });
+            }
+        }
+
+        // Drop free rectangles contained in others
+        free = split.iter().enumerate()
+            .filter(|&(a, r)| !split.iter().enumerate().any(|(b, o)| a != b && o.contains(r) && (!r.contains(o) || b < a)))
+            .map(|(_, r)| *r)
+            .collect();
+    }
+
+    Some(placements)
+}
+
+fn crop(image: &DecodedImage, x: u32, y: u32, width: u32, height: u32) -> DecodedImage {
+    let mut bytes = Vec::with_capacity((width * height * 4) as usize);
+
+    for row in y..y + height {
+        let start = ((row * image.width + x) * 4) as usize;
+        bytes.extend_from_slice(&image.bytes[start..start + (width * 4) as usize]);
+    }
+
+    DecodedImage { width, height, format: image.format, bytes }
+}
+
+// Copies `image` into `region`, extending its edge texels into the padding around it
+fn blit(image: &DecodedImage, pixels: &mut [u8], atlas_width: u32, region: &AtlasRegion, padding: u32) {
+    let p = padding as i64;
+
+    for y in -p..(image.height as i64 + p) {
+        for x in -p..(image.width as i64 + p) {
+            let sx = x.clamp(0, image.width as i64 - 1) as u32;
+            let sy = y.clamp(0, image.height as i64 - 1) as u32;
+            let source = ((sy * image.width + sx) * 4) as usize;
+
+            let dx = (region.x as i64 + x) as u32;
+            let dy = (region.y as i64 + y) as u32;
+            let destination = ((dy * atlas_width + dx) * 4) as usize;
+
+            pixels[destination..destination + 4].copy_from_slice(&image.bytes[source..source + 4]);
+        }
+    }
+}
@@ -1,13 +1,59 @@
-use cgmath::{ Matrix4, Vector3 };
+use cgmath::{ Matrix, Matrix3, Matrix4, SquareMatrix, Vector3 };
+
+use crate::entity::MaterialData;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub color: [f32; 3],
     pub normal: [f32; 3],
-    pub uv: [f32; 2]
+    pub uv: [f32; 2],
+    // xyz is the tangent, w is the handedness of the bitangent (+1 or -1)
+    pub tangent: [f32; 4]
+}
+vulkano::impl_vertex!(Vertex, position, color, normal, uv, tangent);
+
+// Per-instance attributes of instanced draws. Matrices are split into columns, since a vertex attribute holds at most four floats.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct InstanceData {
+    pub model_0: [f32; 4],
+    pub model_1: [f32; 4],
+    pub model_2: [f32; 4],
+    pub model_3: [f32; 4],
+    pub normals_0: [f32; 3],
+    pub normals_1: [f32; 3],
+    pub normals_2: [f32; 3],
+    pub instance_color: [f32; 3],
+    // Metallic, roughness, normal scale and occlusion strength
+    pub instance_material: [f32; 4],
+    pub instance_emissive: [f32; 3]
+}
+vulkano::impl_vertex!(InstanceData, model_0, model_1, model_2, model_3, normals_0, normals_1, normals_2, instance_color, instance_material, instance_emissive);
+
+impl InstanceData {
+    // An instance with a plain material of `color`
+    pub fn new(model: Matrix4<f32>, color: [f32; 3]) -> Self {
+        Self::with_material(model, &MaterialData::new(color))
+    }
+
+    // Only the material's factors vary per instance; its textures are shared by the whole draw
+    pub fn with_material(model: Matrix4<f32>, material: &MaterialData) -> Self {
+        let normals = normal_matrix(model);
+
+        Self {
+            model_0: model.x.into(),
+            model_1: model.y.into(),
+            model_2: model.z.into(),
+            model_3: model.w.into(),
+            normals_0: normals.x.into(),
+            normals_1: normals.y.into(),
+            normals_2: normals.z.into(),
+            instance_color: material.color,
+            instance_material: [material.metallic, material.roughness, material.normal_scale, material.occlusion_strength],
+            instance_emissive: material.emissive
+        }
+    }
 }
-vulkano::impl_vertex!(Vertex, position, color, normal, uv);
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct DummyVertex {
@@ -49,14 +95,73 @@ pub struct ModelBufferObject {
     pub normals: Matrix4<f32>
 }
 
+impl ModelBufferObject {
+    pub fn new(model: Matrix4<f32>) -> Self {
+        Self {
+            model,
+            normals: normal_matrix(model).into()
+        }
+    }
+}
+
+// Normals are transformed by the inverse transpose of the model's upper 3x3, so non-uniform scales don't skew them.
+// Translation doesn't apply to directions, and a degenerate (zero) scale falls back to the identity.
+pub fn normal_matrix(model: Matrix4<f32>) -> Matrix3<f32> {
+    let upper = Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+
+    upper.invert().unwrap_or_else(Matrix3::identity).transpose()
+}
+
 #[derive(Clone, Copy)]
 pub struct AmbientBufferObject {
     pub color: Vector3<f32>,
-    pub intensity: f32
+    pub intensity: f32,
+    // Spherical harmonics of the environment's irradiance, padded to vec4s
+    pub irradiance: [[f32; 4]; 9]
+}
+
+// Shared by the lighting passes
+#[derive(Clone, Copy)]
+pub struct CameraBufferObject {
+    // Reconstructs world positions from depth
+    pub inverse_view_projection: Matrix4<f32>,
+    pub view: Matrix4<f32>,
+    // w is unused
+    pub position: [f32; 4]
 }
 
 #[derive(Clone, Copy)]
 pub struct DirectionalBufferObject {
     pub position: [f32; 4],
-    pub color: Vector3<f32>
+    pub color: Vector3<f32>,
+    // 0 when the light casts no shadows
+    pub cascade_count: i32,
+    // Cascades are picked by the distance from the camera in view space
+    pub cascades: [Matrix4<f32>; 4],
+    pub splits: [f32; 4],
+    // x is the bias, y the size of a texel within a cascade
+    pub shadow: [f32; 4]
+}
+
+#[derive(Clone, Copy)]
+pub struct PointBufferObject {
+    // Places the light volume in clip space
+    pub volume: Matrix4<f32>,
+    // xyz is the position, w the range
+    pub position: [f32; 4],
+    // rgb is the color, a the intensity
+    pub color: [f32; 4]
+}
+
+#[derive(Clone, Copy)]
+pub struct SpotBufferObject {
+    pub volume: Matrix4<f32>,
+    pub position: [f32; 4],
+    pub direction: [f32; 4],
+    pub color: [f32; 4],
+    // x and y are the cosines of the inner and outer cone angles
+    pub cone: [f32; 4],
+    pub shadow_projection: Matrix4<f32>,
+    // x is 1 when the light casts shadows, y the bias and z the size of a shadow map texel
+    pub shadow: [f32; 4]
 }
@@ -40,8 +40,23 @@ impl Camera {
         );
     }
 
+    // Where the camera is in world space, taken from its view so it matches what was rendered
+    pub fn position(&self) -> Point3<f32> {
+        self.view.invert().map_or_else(Point3::origin, |inverse| Point3::from_vec(inverse.w.truncate()))
+    }
+
+    // Vertical field of view
+    pub fn fov(&self) -> Deg<f32> {
+        Deg(60.0)
+    }
+
+    // Distance to the near clipping plane
+    pub fn near(&self) -> f32 {
+        0.1
+    }
+
     pub fn get_vp_buffer(&self, dimensions: [u32; 2]) -> VPBufferObject {
-        let mut proj = cgmath::perspective(Rad::from(Deg(60.0)), dimensions[0] as f32 / dimensions[1] as f32, 0.1, 1000.0);
+        let mut proj = cgmath::perspective(Rad::from(self.fov()), dimensions[0] as f32 / dimensions[1] as f32, self.near(), 1000.0);
         proj.y.y *= -1.0;
 
         VPBufferObject {
@@ -10,8 +10,14 @@ use crate::{
     entity::*,
     input::Input,
     camera::Camera,
-    // logger::{ self, MessageEmitter }
+    buffer_objects::InstanceData,
+    lod::LodGroup,
+    terrain::Terrain,
+    assets::{ self, LoadProgress },
+    logger
 };
+#[cfg(feature = "hot-reload")]
+use crate::hot_reload::{ self, HotReloader };
 
 pub struct EngineTime {
     pub delta_time: f32,
@@ -124,10 +130,13 @@ pub struct Engine {
     pub debug_gui: DebugGui,
     initial_world: World,
     time: EngineTime,
+    #[cfg(feature = "hot-reload")]
+    hot_reloader: Option<HotReloader>,
 }
 
 impl Engine {
-    pub fn initialize(world: World, event_loop: &EventLoop<()>) -> Self {
+    pub fn initialize(mut world: World, event_loop: &EventLoop<()>) -> Self {
+        world.batch_static_meshes();
         let initial_world = world.clone();
 
         let renderer = if let Some(cameras) = initial_world.get_components_of_type::<Camera>() {
@@ -147,12 +156,22 @@ impl Engine {
             world,
             renderer,
             time,
-            debug_gui
+            debug_gui,
+            #[cfg(feature = "hot-reload")]
+            hot_reloader: HotReloader::new()
         }
     }
 
+    // Blocks until every queued asset has loaded, reporting progress along the way, then initializes the engine.
+    // Static batching only merges loaded meshes, so scenes relying on it should start this way.
+    pub fn initialize_with_loading(world: World, event_loop: &EventLoop<()>, on_progress: impl FnMut(LoadProgress)) -> Self {
+        assets::wait_for_loads(on_progress);
+
+        Self::initialize(world, event_loop)
+    }
+
     pub fn start(mut self, event_loop: EventLoop<()>) {
-        let mut gui = Gui::new(self.renderer.surface.clone(), self.renderer.queue.clone(), true);
+        let mut gui = Gui::new(self.renderer.surface().clone(), self.renderer.queue.clone(), true);
         let mut ctx = gui.context();
         self.debug_gui.configure_fonts(&mut ctx);
 
@@ -167,7 +186,7 @@ impl Engine {
             (l.init)(l.get_id(), &mut self.world)
         }
 
-        let window_size = self.renderer.surface.window().inner_size();
+        let window_size = self.renderer.surface().window().inner_size();
         let mut input = Input::new((window_size.width, window_size.height));
         
         event_loop.run(move |event, _, control_flow| {
@@ -189,29 +208,25 @@ impl Engine {
                     previous_frame_end.as_mut().take().unwrap().cleanup_finished();
                     frame_breakdown.restart();
 
-                    self.renderer.start(self.world.void_color);
-                    frame_breakdown.update_setup();
+                    // Warnings and failures of background loads, logged on their worker threads
+                    logger::flush_forwarded();
 
-                    let camera_id = self.world.get_first_component_of_type::<Camera>().unwrap().get_id();
-                    let transform = self.world.get_component_by_id::<Transform>(camera_id).unwrap().clone();
-                    let camera = self.world.get_component_by_id_mut::<Camera>(camera_id).unwrap();
-                    camera.calculate_view(&transform);
-                    self.renderer.update_camera(camera);
-                    
-                    let ids = self.world.get_all_ids().unwrap_or_default();
-                    for id in ids {
-                        if let Some(transform) = self.world.get_component_by_id::<Transform>(id) {
-                            if let Some(mesh) = self.world.get_component_by_id::<Mesh>(id) {
-                                self.renderer.geometry(
-                                    mesh, 
-                                    transform, 
-                                    self.world.get_component_by_id::<Material>(id),
-                                    self.initial_world.get_component_by_id_mut::<Texture>(id)
-                                );
+                    #[cfg(feature = "hot-reload")]
+                    if let Some(hot_reloader) = &self.hot_reloader {
+                        for changed in hot_reloader.changed_files() {
+                            if hot_reload::is_shader(&changed) {
+                                self.renderer.reload_shader(&changed);
+                            } else {
+                                hot_reload::reload_assets(&changed);
                             }
                         }
                     }
 
+                    self.renderer.start(self.world.void_color);
+                    frame_breakdown.update_setup();
+
+                    draw_geometry(&mut self.renderer, &mut self.world);
+
                     let mut update_data = UpdateData {
                         world: &mut self.world,
                         time: &self.time,
@@ -224,14 +239,14 @@ impl Engine {
                     }
                     frame_breakdown.update_object_loop();
 
-                    self.renderer.ambient();
+                    self.renderer.ambient(&self.world.ambient_light);
                     frame_breakdown.update_ambient();
 
-                    for i in 0..self.world.lights.len() {
-                        self.renderer.directional(&self.world.lights[i]);
-                    }
+                    self.renderer.lights(&self.world);
                     frame_breakdown.update_directional();
 
+                    self.renderer.tonemap(&self.world.tonemapping);
+
                     self.renderer.finish(&mut previous_frame_end, &mut gui);
                     frame_breakdown.update_draw_call();
                     
@@ -239,10 +254,94 @@ impl Engine {
                     input.update();
                 },
                 Event::RedrawRequested(_) => {
-                    self.renderer.surface.window().request_redraw();
+                    self.renderer.surface().window().request_redraw();
                 }
                 _ => ()
             }
         })
     }
 }
+
+// Updates the camera and levels of detail, then draws the shadow maps and every mesh in `world` into the geometry
+// buffers of the frame `renderer` has started
+pub fn draw_geometry(renderer: &mut Renderer, world: &mut World) {
+    let camera_id = world.get_first_component_of_type::<Camera>().unwrap().get_id();
+    let transform = world.get_component_by_id::<Transform>(camera_id).unwrap().clone();
+    let camera = world.get_component_by_id_mut::<Camera>(camera_id).unwrap();
+    camera.calculate_view(&transform);
+    let (camera_position, fov) = (transform.translation, camera.fov());
+    renderer.update_camera(camera);
+
+    let ids = world.get_all_ids().unwrap_or_default();
+    for id in ids {
+        if let Some(transform) = world.get_component_by_id::<Transform>(id).cloned() {
+            let lod_data = world.get_component_by_id_mut::<LodGroup>(id)
+                .and_then(|lod| lod.update(&transform, camera_position, fov));
+
+            // Terrain chunks switch LOD as the camera moves over them, so the terrain is remeshed when any does
+            let terrain_data = world.get_component_by_id_mut::<Terrain>(id)
+                .and_then(|terrain| terrain.update(&transform, camera_position));
+
+            if let Some(mesh) = world.get_component_by_id_mut::<Mesh>(id) {
+                if let Some(data) = lod_data {
+                    mesh.data = data;
+                }
+
+                if let Some(data) = terrain_data {
+                    *mesh.data.write() = data;
+                    mesh.data.touch();
+                }
+
+                mesh.load_submesh_textures();
+            }
+        }
+    }
+
+    renderer.shadows(world);
+
+    // Entities sharing a mesh and its textures are drawn with one instanced draw; unique meshes on their own.
+    // Textures only hold handles to shared data, so everything can be borrowed from the world at once.
+    for group in world.instance_groups() {
+        let first = group[0];
+        let mesh = world.get_component_by_id::<Mesh>(first).unwrap();
+        let texture = world.get_component_by_id::<Texture>(first);
+        let normal_map = world.get_component_by_id::<NormalMap>(first);
+
+        if group.len() == 1 {
+            renderer.geometry(
+                mesh, 
+                world.get_component_by_id::<Transform>(first).unwrap(), 
+                world.get_component_by_id::<Material>(first),
+                texture,
+                normal_map
+            );
+        } else {
+            let instances: Vec<InstanceData> = group.iter()
+                .map(|&id| {
+                    let model = world.get_component_by_id::<Transform>(id).unwrap().model_matrix();
+
+                    match world.get_component_by_id::<Material>(id) {
+                        Some(material) => InstanceData::with_material(model, &material.data.read()),
+                        None => InstanceData::new(model, [1.0; 3])
+                    }
+                })
+                .collect();
+
+            renderer.geometry_instanced(mesh, &instances, world.get_component_by_id::<Material>(first), texture, normal_map);
+        }
+    }
+}
+
+// Renders one frame of `world` with an offscreen renderer, without running any logic. Returns the RGBA pixels,
+// e.g. to compare sample scenes against golden images.
+pub fn render_offscreen(renderer: &mut Renderer, world: &mut World) -> Option<Vec<u8>> {
+    logger::flush_forwarded();
+    renderer.start(world.void_color);
+    draw_geometry(renderer, world);
+    renderer.ambient(&world.ambient_light);
+
+    renderer.lights(world);
+    renderer.tonemap(&world.tonemapping);
+
+    renderer.finish_headless()
+}
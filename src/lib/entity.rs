@@ -1,23 +1,28 @@
 use hephaestus_macros::*;
 use std::sync::Arc;
-use std::io::Cursor;
-use std::fs;
+use std::collections::HashMap;
+use std::path::Path;
 use downcast_rs::{ Downcast, impl_downcast };
 use cgmath::{ Vector3, Point3, Matrix4, Quaternion, Euler, Deg, Rad, Rotation3, Rotation, SquareMatrix, InnerSpace };
 use vulkano::image::{ ImageDimensions, ImmutableImage, view::ImageView };
-use vulkano::sampler::Sampler;
+use vulkano::sampler::{ Filter, MipmapMode, Sampler, SamplerAddressMode };
 use vulkano::sync::GpuFuture;
 use vulkano::device::{ Device, Queue };
 use vulkano::format::Format;
 use vulkano::buffer::{ BufferUsage, CpuAccessibleBuffer };
 
 use crate::{
-    mesh_data::{ MeshData, MeshType },
+    mesh_data::{ MeshData, MeshType, PrimitiveType },
+    terrain::{ Terrain, TerrainSettings },
+    lod::{ LodGroup, LodMetric },
+    assets::{ self, Handle },
+    texture_formats::{ self, ColorSpace },
     world::World,
     engine::EngineTime,
     input::Input,
     camera::Camera,
-    // logger::{ self, MessageEmitter }
+    light::Light,
+    logger::{ self, MessageEmitter }
 };
 
 pub trait Component: Downcast + ComponentClone { 
@@ -47,6 +52,12 @@ pub struct Entity {
     id: usize,
 }
 
+// Marks an entity whose mesh never moves, so it can be merged with others by `World::batch_static_meshes`
+#[derive(Clone, Component)]
+pub struct Static {
+    id: usize,
+}
+
 #[derive(Clone, Component)]
 pub struct Transform {
     id: usize,
@@ -159,99 +170,390 @@ pub struct UpdateData<'a> {
 #[derive(Clone, Component)]
 pub struct Mesh {
     id: usize,
-    pub data: MeshData,
-    pub mesh_type: MeshType
+    pub data: Handle<MeshData>,
+    pub mesh_type: MeshType,
+    // Diffuse maps referenced by the submesh materials, keyed by path
+    pub textures: HashMap<String, Texture>,
+    // Generation of `data` the submesh textures were requested for
+    textures_generation: Option<usize>,
+    // Used for the submesh textures
+    pub sampler: SamplerDesc,
+    // Whether the mesh is drawn into shadow maps, and whether shadows darken it
+    pub cast_shadows: bool,
+    pub receive_shadows: bool
 }
 
 impl Mesh {
+    pub fn batched(data: MeshData, textures: HashMap<String, Texture>) -> Self {
+        Self {
+            id: 0,
+            data: assets::meshes().add(data),
+            mesh_type: MeshType::Batched,
+            textures_generation: Some(0),
+            textures,
+            sampler: SamplerDesc::default(),
+            cast_shadows: true,
+            receive_shadows: true
+        }
+    }
+
     pub fn init(&mut self) {
         match self.mesh_type.clone() {
             MeshType::Model(path) => {
-                self.data = MeshData::load(&path);
+                // A cube stands in for the model until it has loaded
+                let placeholder = MeshData::generate(PrimitiveType::Cube);
+                let key = path.clone();
+                self.data = assets::meshes().load_async(&key, placeholder, move || MeshData::try_load(&path));
             },
             MeshType::Primitive(primitive_type) => {
-                self.data = MeshData::generate(primitive_type);
+                // Primitives with the same parameters are identical, so they are shared like files
+                let key = format!("primitive:{:?}", primitive_type);
+                self.data = assets::meshes().load(&key, || MeshData::generate(primitive_type));
+            },
+            MeshType::Terrain(settings) => {
+                self.data = assets::meshes().add(Terrain::new(settings).mesh(None));
+            },
+            MeshType::Batched => { }
+        }
+
+        self.textures.clear();
+        self.textures_generation = None;
+        self.load_submesh_textures();
+    }
+
+    // Submesh materials are only known once the mesh has loaded, so their textures are requested afterwards.
+    // Does nothing while the mesh is loading, or once the textures for the current mesh data have been requested.
+    pub fn load_submesh_textures(&mut self) {
+        let generation = self.data.generation();
+
+        if self.textures_generation == Some(generation) || !self.data.is_loaded() {
+            return;
+        }
+
+        self.textures_generation = Some(generation);
+
+        let texture_paths: Vec<String> = self.data.read().submeshes.iter()
+            .filter_map(|s| s.material.as_ref()?.diffuse_texture.clone())
+            .collect();
+
+        for path in texture_paths {
+            if self.textures.contains_key(&path) {
+                continue;
+            }
+
+            if Path::new(&path).exists() {
+                self.textures.insert(path.clone(), Texture::new(&path).with_sampler(self.sampler));
+            } else {
+                logger::log_warning(&format!("Unable to find diffuse texture '{}'", path), MessageEmitter::World);
             }
         }
     }
 }
 
+// Metallic-roughness material with the same parameters as a glTF material: each factor multiplies the matching texture
+// where there is one, and textures use glTF's channels (metallic in blue and roughness in green, occlusion in red)
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialData {
+    // Base color (baseColorFactor), also tinting the vertex colors
+    pub color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: [f32; 3],
+    // Scales the normal map's tangent-space x and y (normalTexture.scale)
+    pub normal_scale: f32,
+    // How much the occlusion map darkens ambient light (occlusionTexture.strength)
+    pub occlusion_strength: f32,
+    pub textures: MaterialTextures<String>
+}
+
+impl MaterialData {
+    // A rough, non-metallic material of a single color
+    pub fn new(color: [f32; 3]) -> Self {
+        Self {
+            color,
+            metallic: 0.0,
+            roughness: 0.5,
+            emissive: [0.0; 3],
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            textures: MaterialTextures::default()
+        }
+    }
+
+    // Roughness giving highlights like a Blinn-Phong specular exponent (e.g. the Ns of a .mtl material), for materials
+    // authored that way. GGX with alpha = roughness squared matches an exponent of 2 / alpha^2 - 2.
+    pub fn with_shininess(mut self, shininess: f32) -> Self {
+        self.roughness = (2.0 / (shininess.max(0.0) + 2.0)).powf(0.25);
+
+        self
+    }
+}
+
+// A material's textures: paths in `MaterialData`, loaded `Texture`s in `Material`. Base color and emissive textures
+// hold sRGB color, the others linear data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialTextures<T> {
+    pub base_color: Option<T>,
+    pub metallic_roughness: Option<T>,
+    pub normal: Option<T>,
+    pub occlusion: Option<T>,
+    pub emissive: Option<T>
+}
+
+impl<T> Default for MaterialTextures<T> {
+    fn default() -> Self {
+        Self {
+            base_color: None,
+            metallic_roughness: None,
+            normal: None,
+            occlusion: None,
+            emissive: None
+        }
+    }
+}
+
+impl<T> MaterialTextures<T> {
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        vec![&mut self.base_color, &mut self.metallic_roughness, &mut self.normal, &mut self.occlusion, &mut self.emissive]
+            .into_iter()
+            .flatten()
+    }
+}
+
+impl MaterialTextures<Texture> {
+    // Identifies the textures and their samplers, e.g. to tell which materials can share a draw
+    pub fn key(&self) -> Vec<Option<(usize, SamplerDesc)>> {
+        [&self.base_color, &self.metallic_roughness, &self.normal, &self.occlusion, &self.emissive].iter()
+            .map(|t| t.as_ref().map(|t| (t.data.id(), t.sampler)))
+            .collect()
+    }
+}
+
 #[derive(Clone, Component)]
 pub struct Material {
     id: usize,
-    pub color: [f32; 3]
+    pub data: Handle<MaterialData>,
+    // Loaded from the paths in `data` when the component is created
+    pub textures: MaterialTextures<Texture>
 }
 
-#[derive(Clone, Component)]
-pub struct Texture {
-    id: usize,
+impl Material {
+    pub fn new(data: Handle<MaterialData>) -> Self {
+        let paths = data.read().textures.clone();
+
+        let textures = MaterialTextures {
+            base_color: paths.base_color.as_deref().map(Texture::new),
+            metallic_roughness: paths.metallic_roughness.as_deref().map(Texture::new_linear),
+            normal: paths.normal.as_deref().map(Texture::new_linear),
+            occlusion: paths.occlusion.as_deref().map(Texture::new_linear),
+            emissive: paths.emissive.as_deref().map(Texture::new)
+        };
+
+        Self {
+            id: 0,
+            data,
+            textures
+        }
+    }
+
+    pub fn color(&self) -> [f32; 3] {
+        self.data.read().color
+    }
+}
+
+// Decoded pixels, shared between every `Texture` using the same file
+pub struct TextureData {
     pub path: String,
+    pub color_space: ColorSpace,
     bytes: Vec<u8>,
     dimensions: ImageDimensions,
-    buffer: Option<Arc<CpuAccessibleBuffer<[u8]>>>
+    // The format the pixels were decoded to, which depends on the file as well as `color_space`
+    pub format: Format,
+    // Uploaded the first time the texture is drawn. Reloading replaces the whole `TextureData`, and with it the image.
+    image: Option<Arc<ImageView<Arc<ImmutableImage>>>>
 }
 
-impl Texture {
-    fn init(&mut self) {
-        let png_bytes = fs::read(&self.path).unwrap(); 
-        let cursor = Cursor::new(png_bytes);
-        let decoder = png::Decoder::new(cursor);
-        let mut reader = decoder.read_info().unwrap();
-        let info = reader.info();
-
-        self.dimensions = ImageDimensions::Dim2d {
-            width: info.width,
-            height: info.height,
+impl TextureData {
+    pub fn load(path: &str, color_space: ColorSpace) -> Result<Self, String> {
+        let image = texture_formats::decode(path, color_space)?;
+
+        let dimensions = ImageDimensions::Dim2d {
+            width: image.width,
+            height: image.height,
             array_layers: 1
         };
 
-        self.bytes.resize((info.width * info.height * 4) as usize, 0);
-        reader.next_frame(&mut self.bytes).unwrap();
+        Ok(Self {
+            path: path.into(),
+            color_space,
+            bytes: image.bytes,
+            dimensions,
+            format: image.format,
+            image: None
+        })
+    }
+
+    // Shown while the texture loads: plain white for color textures, and a flat normal for
+    // linear ones, which are mostly normal maps
+    fn placeholder(path: &str, color_space: ColorSpace) -> Self {
+        let bytes = match color_space {
+            ColorSpace::Srgb => vec![255, 255, 255, 255],
+            ColorSpace::Linear => vec![128, 128, 255, 255]
+        };
+
+        Self {
+            path: path.into(),
+            color_space,
+            bytes,
+            dimensions: ImageDimensions::Dim2d { width: 1, height: 1, array_layers: 1 },
+            format: texture_formats::with_color_space(Format::R8G8B8A8Srgb, color_space),
+            image: None
+        }
+    }
+}
+
+// How a texture is filtered and wrapped when sampled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerDesc {
+    pub mag_filter: Filter,
+    pub min_filter: Filter,
+    pub mipmap_mode: MipmapMode,
+    pub address_mode: SamplerAddressMode,
+    // Clamped to what the device supports; 1.0 disables anisotropic filtering
+    pub max_anisotropy: f32
+}
+
+impl SamplerDesc {
+    // Nearest filtering without blending between mip levels, clamped at the edges, for pixel art
+    pub fn pixel_art() -> Self {
+        Self {
+            mag_filter: Filter::Nearest,
+            min_filter: Filter::Nearest,
+            mipmap_mode: MipmapMode::Nearest,
+            address_mode: SamplerAddressMode::ClampToEdge,
+            max_anisotropy: 1.0
+        }
     }
 
-    pub fn get_sampler(device: &Arc<Device>) -> Arc<Sampler> {
+    pub fn create(&self, device: &Arc<Device>) -> Arc<Sampler> {
+        let max_anisotropy = if device.enabled_features().sampler_anisotropy {
+            self.max_anisotropy.clamp(1.0, device.physical_device().properties().max_sampler_anisotropy)
+        } else {
+            1.0
+        };
+
+        // The maximum LOD isn't limited, so every mip level the image has is used
         Sampler::new(
             device.clone(),
-            vulkano::sampler::Filter::Linear,
-            vulkano::sampler::Filter::Linear,
-            vulkano::sampler::MipmapMode::Nearest,
-            vulkano::sampler::SamplerAddressMode::Repeat,
-            vulkano::sampler::SamplerAddressMode::Repeat,
-            vulkano::sampler::SamplerAddressMode::Repeat,
-            0.0, 1.0, 0.0, 0.0
+            self.mag_filter,
+            self.min_filter,
+            self.mipmap_mode,
+            self.address_mode,
+            self.address_mode,
+            self.address_mode,
+            0.0, max_anisotropy, 0.0, 1000.0
         ).unwrap()
     }
+}
 
-    pub unsafe fn get_buffer(&mut self, queue: &Arc<Queue>) -> (Arc<ImageView<Arc<ImmutableImage>>>, Box<dyn GpuFuture>) {
-        let buffer = if let Some(b) = &self.buffer {
-            b.clone()
-        } else {
+impl Default for SamplerDesc {
+    // Trilinear and anisotropic filtering, repeating outside of [0, 1]
+    fn default() -> Self {
+        Self {
+            mag_filter: Filter::Linear,
+            min_filter: Filter::Linear,
+            mipmap_mode: MipmapMode::Linear,
+            address_mode: SamplerAddressMode::Repeat,
+            max_anisotropy: 16.0
+        }
+    }
+}
+
+#[derive(Clone, Component)]
+pub struct Texture {
+    id: usize,
+    pub path: String,
+    pub data: Handle<TextureData>,
+    pub sampler: SamplerDesc
+}
+
+impl Texture {
+    pub fn new(path: &str) -> Self {
+        Self::with_color_space(path, ColorSpace::Srgb)
+    }
+
+    // For data textures (e.g. normal or roughness maps) that must not be gamma corrected when sampled
+    pub fn new_linear(path: &str) -> Self {
+        Self::with_color_space(path, ColorSpace::Linear)
+    }
+
+    pub fn with_color_space(path: &str, color_space: ColorSpace) -> Self {
+        // The same file can be used both as color and as data, which need separate uploads
+        let key = format!("{}:{:?}", path, color_space);
+        let file = path.to_string();
+
+        Self {
+            id: 0,
+            path: path.into(),
+            data: assets::textures().load_async(&key, TextureData::placeholder(path, color_space), move || TextureData::load(&file, color_space)),
+            sampler: SamplerDesc::default()
+        }
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerDesc) -> Self {
+        self.sampler = sampler;
+
+        self
+    }
+
+    pub fn get_sampler(&self, device: &Arc<Device>) -> Arc<Sampler> {
+        self.sampler.create(device)
+    }
+
+    // The image is uploaded once per file and shared by every texture using it. The upload is only recorded here and
+    // returned the first time, to be submitted along with the frame that draws the texture.
+    pub fn get_image(&self, queue: &Arc<Queue>) -> (Arc<ImageView<Arc<ImmutableImage>>>, Option<Box<dyn GpuFuture>>) {
+        let mut data = self.data.write();
+
+        if let Some(image) = &data.image {
+            return (image.clone(), None);
+        }
+
+        let buffer = unsafe {
             let buffer: Arc<CpuAccessibleBuffer<[u8]>> = CpuAccessibleBuffer::uninitialized_array(
                 queue.device().clone(),
-                (self.dimensions.width() * self.dimensions.height() * 4) as u64,
+                data.bytes.len() as u64,
                 BufferUsage::transfer_source(),
                 true
             ).unwrap();
 
             { // New scope to "fool" the borrow-checker (can't borrow `buffer` as mutable and immutable in the same scope)
                 let mut mapping = buffer.write().unwrap();
-                mapping.copy_from_slice(self.bytes.as_slice());
+                mapping.copy_from_slice(data.bytes.as_slice());
             }
 
-            self.buffer = Some(buffer.clone());
-
             buffer
         };
 
+        // The mip chain is generated on the GPU by blitting each level from the previous one, which block compressed formats don't support
+        let mipmaps = if texture_formats::can_blit(data.format) {
+            vulkano::image::MipmapsCount::Log2
+        } else {
+            vulkano::image::MipmapsCount::One
+        };
+
         let (image, future) = ImmutableImage::from_buffer(
             buffer,
-            self.dimensions,
-            vulkano::image::MipmapsCount::One,
-            Format::R8G8B8A8Srgb,
+            data.dimensions,
+            mipmaps,
+            data.format,
             queue.clone()
         ).unwrap();
 
-        (ImageView::new(image).unwrap(), future.boxed())
+        let view = ImageView::new(image).unwrap();
+        data.image = Some(view.clone());
+
+        (view, Some(future.boxed()))
     }
 
     pub fn get_null_buffer(queue: &Arc<Queue>) -> (Arc<ImageView<Arc<ImmutableImage>>>, Box<dyn GpuFuture>) {
@@ -265,6 +567,26 @@ impl Texture {
         
         (ImageView::new(image).unwrap(), future.boxed())
     }
+
+    // A flat tangent-space normal, used for meshes without a normal map
+    pub fn get_null_normal_buffer(queue: &Arc<Queue>) -> (Arc<ImageView<Arc<ImmutableImage>>>, Box<dyn GpuFuture>) {
+        let (image, future) = ImmutableImage::from_iter(
+            [128u8, 128, 255, 255].iter().cloned(),
+            ImageDimensions::Dim2d { width: 1, height: 1, array_layers: 1},
+            vulkano::image::MipmapsCount::One,
+            Format::R8G8B8A8Unorm,
+            queue.clone()
+        ).unwrap();
+        
+        (ImageView::new(image).unwrap(), future.boxed())
+    }
+}
+
+// Tangent-space normal map, sampled alongside the entity's `Texture` in the deferred pass
+#[derive(Clone, Component)]
+pub struct NormalMap {
+    id: usize,
+    pub texture: Texture
 }
 
 #[derive(Clone)]
@@ -313,8 +635,13 @@ impl EntityBuilder {
     pub fn mesh(mut self, mesh: MeshType) -> Self {
         let mut m = Mesh {
             id: 0,
-            data: MeshData::empty(),
-            mesh_type: mesh
+            data: assets::meshes().add(MeshData::empty()),
+            mesh_type: mesh,
+            textures: HashMap::new(),
+            textures_generation: None,
+            sampler: SamplerDesc::default(),
+            cast_shadows: true,
+            receive_shadows: true
         };
         m.init();
 
@@ -323,28 +650,130 @@ impl EntityBuilder {
         self
     }
 
-    pub fn material(mut self, color: [f32; 3]) -> Self {
-        let m = Material {
+    // Adds both the terrain's mesh and a `Terrain` component, which can be queried for heights
+    pub fn terrain(mut self, settings: TerrainSettings) -> Self {
+        let terrain = Terrain::new(settings.clone());
+        let m = Mesh {
             id: 0,
-            color
+            data: assets::meshes().add(terrain.mesh(None)),
+            mesh_type: MeshType::Terrain(settings),
+            textures: HashMap::new(),
+            textures_generation: Some(0),
+            sampler: SamplerDesc::default(),
+            cast_shadows: true,
+            receive_shadows: true
         };
 
+        self.components.push(Box::new(m));
+        self.components.push(Box::new(terrain));
+
+        self
+    }
+
+    // Generates one level of detail per threshold from the entity's mesh, so it must come after `mesh`
+    pub fn lod(mut self, metric: LodMetric, thresholds: &[f32]) -> Self {
+        let mesh = self.components.iter()
+            .find_map(|c| c.downcast_ref::<Mesh>());
+
+        match mesh {
+            Some(mesh) => {
+                let group = LodGroup::generate(&mesh.data, metric, thresholds);
+                self.components.push(Box::new(group));
+            },
+            None => {
+                logger::log_warning("Unable to generate levels of detail for an entity without a mesh", MessageEmitter::World);
+            }
+        }
+
+        self
+    }
+
+    // Opts the entity's mesh into static batching; it must not move after the world is handed to the engine
+    pub fn static_mesh(mut self) -> Self {
+        self.components.push(Box::new(Static { id: 0 }));
+
+        self
+    }
+
+    pub fn material(self, color: [f32; 3]) -> Self {
+        self.pbr_material(MaterialData::new(color))
+    }
+
+    pub fn pbr_material(mut self, material: MaterialData) -> Self {
+        let m = Material::new(assets::materials().add(material));
+
+        self.components.push(Box::new(m));
+
+        self
+    }
+
+    // Uses a material shared with other entities, so changing it affects all of them. Its textures are loaded
+    // from the paths it has now.
+    pub fn shared_material(mut self, material: &Handle<MaterialData>) -> Self {
+        let m = Material::new(material.clone());
+
         self.components.push(Box::new(m));
 
         self
     }
 
     pub fn texture(mut self, path: &str) -> Self {
-        let mut t = Texture {
+        let t = Texture::new(path);
+
+        self.components.push(Box::new(t));
+
+        self
+    }
+
+    // A texture holding data rather than color (e.g. a roughness map) should use `ColorSpace::Linear`
+    pub fn texture_with_color_space(mut self, path: &str, color_space: ColorSpace) -> Self {
+        let t = Texture::with_color_space(path, color_space);
+
+        self.components.push(Box::new(t));
+
+        self
+    }
+
+    // Applies to the entity's texture, normal map, material and submesh textures, so it must come after them
+    pub fn sampler(mut self, sampler: SamplerDesc) -> Self {
+        for component in self.components.iter_mut() {
+            if let Some(texture) = component.downcast_mut::<Texture>() {
+                texture.sampler = sampler;
+            } else if let Some(normal_map) = component.downcast_mut::<NormalMap>() {
+                normal_map.texture.sampler = sampler;
+            } else if let Some(mesh) = component.downcast_mut::<Mesh>() {
+                mesh.sampler = sampler;
+                mesh.textures.values_mut().for_each(|t| t.sampler = sampler);
+            } else if let Some(material) = component.downcast_mut::<Material>() {
+                material.textures.iter_mut().for_each(|t| t.sampler = sampler);
+            }
+        }
+
+        self
+    }
+
+    // Meshes cast and receive shadows by default. Applies to the entity's mesh, so it must come after it.
+    pub fn shadows(mut self, cast: bool, receive: bool) -> Self {
+        match self.components.iter_mut().find_map(|c| c.downcast_mut::<Mesh>()) {
+            Some(mesh) => {
+                mesh.cast_shadows = cast;
+                mesh.receive_shadows = receive;
+            },
+            None => {
+                logger::log_warning("Unable to set shadow flags on an entity without a mesh", MessageEmitter::World);
+            }
+        }
+
+        self
+    }
+
+    pub fn normal_map(mut self, path: &str) -> Self {
+        let n = NormalMap {
             id: 0,
-            path: path.into(),
-            bytes: Vec::new(),
-            dimensions: ImageDimensions::Dim2d { width: 0, height: 0, array_layers: 0 },
-            buffer: None
+            texture: Texture::new_linear(path)
         };
-        t.init();
 
-        self.components.push(Box::new(t));
+        self.components.push(Box::new(n));
 
         self
     }
@@ -356,5 +785,11 @@ impl EntityBuilder {
 
         self
     }
+
+    pub fn light(mut self, light: Light) -> Self {
+        self.components.push(Box::new(light));
+
+        self
+    }
 }
 
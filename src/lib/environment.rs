@@ -0,0 +1,153 @@
+use std::f32::consts::PI;
+use cgmath::{ InnerSpace, Vector3 };
+
+use crate::texture_formats::{ self, ColorSpace, DecodedImage };
+
+// Convolution of each band with the cosine lobe, divided by pi so the result is what a white diffuse surface reflects
+const BAND_FACTORS: [f32; 3] = [1.0, 2.0 / 3.0, 1.0 / 4.0];
+
+// Diffuse light arriving from a surrounding environment, as order 2 spherical harmonics: 9 RGB coefficients.
+// Irradiance is smooth enough for them to stay within a few percent of integrating the whole environment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Irradiance {
+    pub coefficients: [[f32; 3]; 9]
+}
+
+impl Irradiance {
+    // The same light from every direction
+    pub fn uniform(color: [f32; 3]) -> Self {
+        let mut coefficients = [[0.0; 3]; 9];
+        coefficients[0] = color.map(|c| c / basis(Vector3::unit_y())[0]);
+
+        Self { coefficients }
+    }
+
+    // Equirectangular environment (the top row looking straight up, +Y) from any format `texture_formats` decodes to
+    // uncompressed texels. LDR images are read as sRGB.
+    pub fn load(path: &str) -> Result<Self, String> {
+        Self::from_equirectangular(&texture_formats::decode(path, ColorSpace::Srgb)?)
+    }
+
+    // Cubemap environment from six square images, in the order +X, -X, +Y, -Y, +Z, -Z
+    pub fn load_cubemap(paths: [&str; 6]) -> Result<Self, String> {
+        let mut faces = Vec::with_capacity(6);
+
+        for path in paths.iter() {
+            faces.push(texture_formats::decode(path, ColorSpace::Srgb)?);
+        }
+
+        Self::from_cubemap(&faces)
+    }
+
+    pub fn from_equirectangular(image: &DecodedImage) -> Result<Self, String> {
+        let texels = image.linear_rgb().ok_or_else(|| format!("Environments can't be read from {:?} images", image.format))?;
+        let (width, height) = (image.width as usize, image.height as usize);
+
+        let samples = texels.iter().enumerate().map(|(i, &radiance)| {
+            let (x, y) = (i % width, i / width);
+            let phi = (x as f32 + 0.5) / width as f32 * 2.0 * PI;
+            let theta = (y as f32 + 0.5) / height as f32 * PI;
+
+            // Texels shrink towards the poles
+            let direction = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            (direction, theta.sin(), radiance)
+        });
+
+        Ok(Self::project(samples))
+    }
+
+    pub fn from_cubemap(faces: &[DecodedImage]) -> Result<Self, String> {
+        if faces.len() != 6 {
+            return Err(format!("Cubemaps have 6 faces, not {}", faces.len()));
+        }
+
+        let size = faces[0].width;
+        let mut samples = Vec::new();
+
+        for (face, image) in faces.iter().enumerate() {
+            if image.width != size || image.height != size {
+                return Err("Cubemap faces must be square and the same size".to_string());
+            }
+
+            let texels = image.linear_rgb().ok_or_else(|| format!("Environments can't be read from {:?} images", image.format))?;
+
+            for (i, radiance) in texels.into_iter().enumerate() {
+                let u = 2.0 * ((i as u32 % size) as f32 + 0.5) / size as f32 - 1.0;
+                let v = 2.0 * ((i as u32 / size) as f32 + 0.5) / size as f32 - 1.0;
+
+                let direction = match face {
+                    0 => Vector3::new(1.0, -v, -u),
+                    1 => Vector3::new(-1.0, -v, u),
+                    2 => Vector3::new(u, 1.0, v),
+                    3 => Vector3::new(u, -1.0, -v),
+                    4 => Vector3::new(u, -v, 1.0),
+                    _ => Vector3::new(-u, -v, -1.0)
+                };
+
+                // Texels towards the corners of a face cover less of the sphere
+                let weight = (1.0 + u * u + v * v).powf(-1.5);
+                samples.push((direction.normalize(), weight, radiance));
+            }
+        }
+
+        Ok(Self::project(samples.into_iter()))
+    }
+
+    // Light a white diffuse surface facing `normal` reflects
+    pub fn evaluate(&self, normal: Vector3<f32>) -> [f32; 3] {
+        let basis = basis(normal.normalize());
+        let mut color = [0.0; 3];
+
+        for (coefficient, b) in self.coefficients.iter().zip(basis.iter()) {
+            for channel in 0..3 {
+                color[channel] += coefficient[channel] * b;
+            }
+        }
+
+        color
+    }
+
+    // Integrates radiance samples (direction, relative solid angle, radiance) over the sphere, already convolved
+    // with the cosine lobe so evaluating them gives irradiance
+    fn project(samples: impl Iterator<Item = (Vector3<f32>, f32, [f32; 3])>) -> Self {
+        let mut coefficients = [[0.0; 3]; 9];
+        let mut total_weight = 0.0;
+
+        for (direction, weight, radiance) in samples {
+            for (coefficient, b) in coefficients.iter_mut().zip(basis(direction).iter()) {
+                for channel in 0..3 {
+                    coefficient[channel] += radiance[channel] * b * weight;
+                }
+            }
+
+            total_weight += weight;
+        }
+
+        // The weights only approximate each sample's solid angle, so they're normalized to cover the sphere exactly
+        let scale = 4.0 * PI / total_weight;
+
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            let band = match i { 0 => 0, 1..=3 => 1, _ => 2 };
+
+            for channel in coefficient.iter_mut() {
+                *channel *= scale * BAND_FACTORS[band];
+            }
+        }
+
+        Self { coefficients }
+    }
+}
+
+fn basis(d: Vector3<f32>) -> [f32; 9] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y)
+    ]
+}
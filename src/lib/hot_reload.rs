@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ channel, Receiver };
+use std::time::Duration;
+use notify::{ watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher };
+
+use crate::{
+    assets,
+    entity::TextureData,
+    mesh_data::MeshData,
+    logger::{ self, MessageEmitter }
+};
+
+pub const ASSET_DIRECTORY: &str = "models";
+pub const SHADER_DIRECTORY: &str = "src/shaders";
+
+// Watches the asset and shader directories for changes.
+// Meshes merged by static batching are copies, so they keep their original geometry until restarted.
+pub struct HotReloader {
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>
+}
+
+impl HotReloader {
+    pub fn new() -> Option<Self> {
+        let (sender, events) = channel();
+
+        // Editors often write a file in several steps, so events are debounced
+        let mut watcher = match watcher(sender, Duration::from_millis(250)) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                logger::log_error(&format!("Unable to start the file watcher: {}", e), MessageEmitter::Engine);
+                return None;
+            }
+        };
+
+        for directory in &[ASSET_DIRECTORY, SHADER_DIRECTORY] {
+            if let Err(e) = watcher.watch(directory, RecursiveMode::Recursive) {
+                logger::log_warning(&format!("Unable to watch '{}' for changes: {}", directory, e), MessageEmitter::Engine);
+            }
+        }
+
+        logger::log_debug("Watching assets and shaders for changes.", MessageEmitter::Engine);
+
+        Some(Self {
+            _watcher: watcher,
+            events
+        })
+    }
+
+    // Files written, created or moved into place since the last call
+    pub fn changed_files(&self) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = self.events.try_iter()
+            .filter_map(|event| match event {
+                DebouncedEvent::Write(path) | DebouncedEvent::Create(path) | DebouncedEvent::Rename(_, path) => Some(path),
+                _ => None
+            })
+            .collect();
+
+        changed.sort();
+        changed.dedup();
+
+        changed
+    }
+}
+
+pub fn is_shader(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "glsl")
+}
+
+// Reloads every mesh and texture loaded from `changed` in the background. The new data replaces the old in place,
+// so every handle sees it, and textures are uploaded again the next time they're drawn.
+pub fn reload_assets(changed: &Path) {
+    let is_material_library = changed.extension().is_some_and(|e| e == "mtl");
+
+    for handle in assets::meshes().handles() {
+        let path = match handle.key() {
+            Some(key) => key.to_string(),
+            None => continue
+        };
+
+        // Material libraries are read as part of the object files referencing them, which are assumed to sit alongside
+        let affected = if is_material_library {
+            same_file(directory(Path::new(&path)), directory(changed))
+        } else {
+            same_file(Path::new(&path), changed)
+        };
+
+        if affected {
+            logger::log_info(&format!("Reloading mesh '{}'", path), MessageEmitter::World);
+            assets::meshes().reload_async(&handle, move || MeshData::try_load(&path));
+        }
+    }
+
+    for handle in assets::textures().handles() {
+        let (path, color_space) = {
+            let data = handle.read();
+            (data.path.clone(), data.color_space)
+        };
+
+        if same_file(Path::new(&path), changed) {
+            logger::log_info(&format!("Reloading texture '{}'", path), MessageEmitter::World);
+            assets::textures().reload_async(&handle, move || TextureData::load(&path, color_space));
+        }
+    }
+}
+
+fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false
+    }
+}
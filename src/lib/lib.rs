@@ -10,6 +10,19 @@ pub mod logger;
 pub mod gui;
 pub mod entity;
 pub mod mesh_data;
+pub mod mesh_processing;
+pub mod mesh_export;
+pub mod wavefront;
+pub mod terrain;
+pub mod texture_formats;
+pub mod atlas;
+pub mod lod;
+pub mod environment;
+pub mod tonemap;
+pub mod assets;
+#[cfg(feature = "hot-reload")]
+pub mod hot_reload;
+pub mod spirv;
 pub mod input;
 
 #[cfg(test)]
@@ -54,5 +67,1198 @@ mod tests {
         assert_eq!(f.cross(r), u);
         assert_eq!(u.cross(f), r);
     }
-}
 
+    fn assert_outward_triangles(data: &mesh_data::MeshData, inside: impl Fn(cgmath::Vector3<f32>) -> cgmath::Vector3<f32>) {
+        use cgmath::{ InnerSpace, Vector3 };
+
+        assert!(!data.indices.is_empty());
+        assert_eq!(data.indices.len() % 3, 0);
+
+        for face in data.indices.chunks(3) {
+            let vertices: Vec<_> = face.iter().map(|&i| data.vertices[i as usize]).collect();
+            let p: Vec<Vector3<f32>> = vertices.iter().map(|v| Vector3::from(v.position)).collect();
+            let face_normal = (p[1] - p[0]).cross(p[2] - p[0]);
+
+            assert!(face_normal.magnitude() > 1e-8, "degenerate triangle {:?}", face);
+
+            for (v, &position) in vertices.iter().zip(&p) {
+                let normal = Vector3::from(v.normal);
+
+                assert!((normal.magnitude() - 1.0).abs() < 1e-4, "normal {:?} is not unit length", normal);
+                // Counter-clockwise winding must agree with the vertex normals...
+                assert!(face_normal.dot(normal) > 0.0, "triangle {:?} is wound against its normals", face);
+                // ...and the normals must point away from the inside of the shape
+                assert!(normal.dot(position - inside(position)) > -1e-4, "normal {:?} at {:?} points inwards", normal, position);
+            }
+        }
+    }
+
+    #[test]
+    fn primitive_winding_and_normals() {
+        use mesh_data::{ MeshData, PrimitiveType };
+        use cgmath::{ InnerSpace, Vector3 };
+
+        let origin = |_: Vector3<f32>| Vector3::new(0.0, 0.0, 0.0);
+
+        let plane = MeshData::generate(PrimitiveType::Plane { subdivisions: 3 });
+        assert_eq!(plane.vertices.len(), 25);
+        assert_outward_triangles(&plane, |p| p - Vector3::unit_y());
+
+        let quad = MeshData::generate(PrimitiveType::Quad { width: 2.0, height: 1.0 });
+        assert_outward_triangles(&quad, |p| p - Vector3::unit_z());
+
+        let cube = MeshData::generate(PrimitiveType::Cube);
+        assert_outward_triangles(&cube, origin);
+
+        let icosphere = MeshData::generate(PrimitiveType::Icosphere { radius: 2.0, subdivisions: 2 });
+        assert_outward_triangles(&icosphere, origin);
+        assert!(icosphere.vertices.iter().all(|v| (Vector3::from(v.position).magnitude() - 2.0).abs() < 1e-4));
+
+        let cylinder = MeshData::generate(PrimitiveType::Cylinder { radius: 0.5, height: 2.0, segments: 16 });
+        assert_outward_triangles(&cylinder, origin);
+
+        let cone = MeshData::generate(PrimitiveType::Cone { radius: 1.0, height: 2.0, segments: 16 });
+        assert_outward_triangles(&cone, origin);
+
+        let capsule = MeshData::generate(PrimitiveType::Capsule { radius: 0.5, height: 3.0, segments: 16, rings: 4 });
+        assert_outward_triangles(&capsule, origin);
+        assert!(capsule.vertices.iter().all(|v| v.position[1].abs() <= 1.5 + 1e-4));
+
+        let torus = MeshData::generate(PrimitiveType::Torus { radius: 1.0, tube_radius: 0.25, segments: 24, sides: 12 });
+        assert_outward_triangles(&torus, |p| Vector3::new(p.x, 0.0, p.z).normalize());
+    }
+
+    #[test]
+    fn primitive_uvs() {
+        use mesh_data::{ MeshData, PrimitiveType };
+        use cgmath::{ InnerSpace, Vector3 };
+
+        let primitives = vec![
+            PrimitiveType::Plane { subdivisions: 4 },
+            PrimitiveType::Quad { width: 1.0, height: 1.0 },
+            PrimitiveType::Cylinder { radius: 1.0, height: 1.0, segments: 8 },
+            PrimitiveType::Cone { radius: 1.0, height: 1.0, segments: 8 },
+            PrimitiveType::Torus { radius: 1.0, tube_radius: 0.5, segments: 8, sides: 8 },
+            PrimitiveType::Capsule { radius: 0.5, height: 2.0, segments: 8, rings: 3 },
+            PrimitiveType::Icosphere { radius: 1.0, subdivisions: 0 },
+            PrimitiveType::Icosphere { radius: 1.0, subdivisions: 3 },
+        ];
+
+        for primitive in primitives {
+            let data = MeshData::generate(primitive);
+            assert!(data.vertices.iter().all(|v| v.uv.iter().all(|&c| (0.0..=1.0).contains(&c))));
+        }
+
+        // The icosphere seam is split, so no triangle away from the poles should stretch across the whole texture
+        let icosphere = MeshData::generate(PrimitiveType::Icosphere { radius: 1.0, subdivisions: 3 });
+        for face in icosphere.indices.chunks(3) {
+            if face.iter().any(|&i| (icosphere.vertices[i as usize].uv[1] - 0.5).abs() > 0.4) {
+                continue;
+            }
+
+            let us: Vec<f32> = face.iter().map(|&i| icosphere.vertices[i as usize].uv[0]).collect();
+            let span = us.iter().cloned().fold(f32::MIN, f32::max) - us.iter().cloned().fold(f32::MAX, f32::min);
+            assert!(span <= 0.5, "triangle {:?} wraps around the seam", face);
+        }
+
+        // Cutting the triangles along the seam only adds points on the sphere, so the surface grows towards it
+        let area = |mesh: &MeshData| mesh.indices.chunks(3).map(|face| {
+            let p: Vec<Vector3<f32>> = face.iter().map(|&i| mesh.vertices[i as usize].position.into()).collect();
+            (p[1] - p[0]).cross(p[2] - p[0]).magnitude() / 2.0
+        }).sum::<f32>();
+        let sphere = MeshData::generate(PrimitiveType::Sphere(3));
+        assert!(area(&icosphere) >= area(&sphere) && area(&icosphere) < 4.0 * std::f32::consts::PI);
+    }
+
+    #[test]
+    fn terrain_sampling() {
+        use terrain::{ Terrain, TerrainSettings, HeightSource };
+        use cgmath::{ InnerSpace, Vector3 };
+
+        let source = HeightSource::Noise { seed: 7, resolution: 33, frequency: 4.0, octaves: 3 };
+        let terrain = Terrain::new(TerrainSettings { chunk_cells: 8, ..TerrainSettings::new(source, 16.0, 2.0) });
+        assert_eq!(terrain.chunk_count(), (4, 4));
+
+        let mesh = terrain.mesh(None);
+        assert_eq!(mesh.submeshes.len(), 16);
+
+        // Surface vertices lie exactly on the sampled heights, skirt vertices hang below them
+        let on_surface = |v: &buffer_objects::Vertex| {
+            let h = terrain.height_at(v.position[0], v.position[2]).unwrap();
+            assert!(v.position[1] <= h + 1e-4);
+            (v.position[1] - h).abs() < 1e-4
+        };
+
+        for face in mesh.indices.chunks(3) {
+            let vertices: Vec<_> = face.iter().map(|&i| mesh.vertices[i as usize]).collect();
+
+            if vertices.iter().all(&on_surface) {
+                let p: Vec<Vector3<f32>> = vertices.iter().map(|v| Vector3::from(v.position)).collect();
+                assert!((p[1] - p[0]).cross(p[2] - p[0]).y > 0.0, "terrain triangle {:?} faces down", face);
+                assert!(vertices.iter().all(|v| Vector3::from(v.normal).y > 0.0));
+            }
+        }
+
+        assert!(terrain.height_at(8.5, 0.0).is_none());
+        assert!(terrain.height_at(0.0, -8.5).is_none());
+
+        // A distant viewer gets coarser chunks
+        let far = terrain.mesh(Some(Vector3::new(100.0, 0.0, 100.0)));
+        assert!(far.vertices.len() < mesh.vertices.len());
+        assert!(Vector3::from(far.vertices[0].normal).magnitude() > 0.99);
+
+        let heightmap = Terrain::new(TerrainSettings::new(HeightSource::Heightmap("models/textures/color.png".into()), 10.0, 1.0));
+        let h = heightmap.height_at(0.0, 0.0).unwrap();
+        assert!((0.0..=1.0).contains(&h));
+
+        // 16-bit heightmaps keep their low byte
+        let directory = std::env::temp_dir().join(format!("hephaestus_terrain_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("fine.png");
+        image::ImageBuffer::from_pixel(2, 2, image::Luma([0x0180u16])).save(&path).unwrap();
+
+        let fine = Terrain::new(TerrainSettings::new(HeightSource::Heightmap(path.to_string_lossy().into_owned()), 10.0, 65535.0));
+        assert!((fine.height_at(0.0, 0.0).unwrap() - 384.0).abs() < 1e-2);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Chunk LODs follow the camera, and the terrain is only remeshed when one of them changes
+        let mut terrain = terrain;
+        let transform = entity::Transform::default();
+        let remeshed = terrain.update(&transform, Vector3::new(100.0, 0.0, 100.0)).unwrap();
+        assert_eq!(remeshed.vertices.len(), far.vertices.len());
+        assert!(terrain.update(&transform, Vector3::new(100.0, 0.0, 100.0)).is_none());
+        let near = terrain.mesh(Some(Vector3::new(0.0, 0.0, 0.0)));
+        assert_eq!(terrain.update(&transform, Vector3::new(0.0, 0.0, 0.0)).unwrap().vertices.len(), near.vertices.len());
+    }
+
+    #[test]
+    fn mesh_processing() {
+        use mesh_data::{ MeshData, PrimitiveType };
+        use mesh_processing::NormalMode;
+        use cgmath::{ InnerSpace, Vector3 };
+
+        let origin = |_: Vector3<f32>| Vector3::new(0.0, 0.0, 0.0);
+        let mut sphere = MeshData::generate(PrimitiveType::Icosphere { radius: 1.0, subdivisions: 2 });
+
+        for v in &sphere.vertices {
+            let (normal, tangent) = (Vector3::from(v.normal), Vector3::new(v.tangent[0], v.tangent[1], v.tangent[2]));
+
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-4);
+            assert!(normal.dot(tangent).abs() < 1e-4);
+            assert_eq!(v.tangent[3].abs(), 1.0);
+        }
+
+        // Subdivision duplicates the shared midpoints, which welding merges back together. Without a seam, a twice
+        // subdivided icosahedron is left with 10 * 4^2 + 2 vertices.
+        let mut geodesic = MeshData::generate(PrimitiveType::Sphere(2));
+        assert_eq!(geodesic.vertices.len(), 12 + 20 * 3 + 80 * 3);
+        assert_eq!(geodesic.weld_vertices(1e-5), 312 - 162);
+        assert_eq!(geodesic.vertices.len(), 162);
+        assert_outward_triangles(&geodesic, origin);
+
+        assert!(sphere.weld_vertices(1e-5) > 0);
+        assert_outward_triangles(&sphere, origin);
+
+        sphere.recompute_normals(NormalMode::Smooth);
+        assert_outward_triangles(&sphere, origin);
+
+        sphere.recompute_normals(NormalMode::Flat);
+        assert_eq!(sphere.vertices.len(), sphere.indices.len());
+        assert_outward_triangles(&sphere, origin);
+
+        sphere.flip_winding();
+        let face: Vec<Vector3<f32>> = sphere.indices[0..3].iter().map(|&i| Vector3::from(sphere.vertices[i as usize].position)).collect();
+        assert!((face[1] - face[0]).cross(face[2] - face[0]).dot(Vector3::from(sphere.vertices[sphere.indices[0] as usize].normal)) < 0.0);
+    }
+
+    #[test]
+    fn mesh_simplification() {
+        use mesh_data::{ MeshData, PrimitiveType };
+        use cgmath::Vector3;
+
+        let mut sphere = MeshData::generate(PrimitiveType::Icosphere { radius: 1.0, subdivisions: 3 });
+        sphere.weld_vertices(1e-5);
+
+        let lods = sphere.generate_lods(4, 0.5);
+        assert_eq!(lods.len(), 4);
+        assert_eq!(lods[0].indices, sphere.indices);
+
+        for pair in lods.windows(2) {
+            let (previous, next) = (pair[0].indices.len() / 3, pair[1].indices.len() / 3);
+
+            assert!(next < previous);
+            assert!(next <= previous * 3 / 5, "{} -> {} triangles", previous, next);
+            assert_outward_triangles(&pair[1], |_| Vector3::new(0.0, 0.0, 0.0));
+        }
+
+        // Submeshes survive simplification and still cover the whole index buffer
+        let teapot = MeshData::load("models/teapot.obj");
+        let simplified = teapot.simplify(0.25);
+        assert!(simplified.indices.len() < teapot.indices.len() / 2);
+        assert_eq!(simplified.parts().len(), teapot.parts().len());
+        assert_eq!(simplified.parts().iter().map(|p| p.index_count).sum::<usize>(), simplified.indices.len());
+        assert!((simplified.bounding_radius() - teapot.bounding_radius()).abs() < teapot.bounding_radius() * 0.1);
+
+        // Groups built from the same mesh share their levels, so entities at the same level can still be instanced
+        let source = assets::meshes().add(sphere);
+        let near = lod::LodGroup::generate(&source, lod::LodMetric::Distance, &[0.0, 10.0, 20.0]);
+        let far = lod::LodGroup::generate(&source, lod::LodMetric::Distance, &[0.0, 50.0]);
+        assert_eq!((near.levels.len(), far.levels.len()), (3, 2));
+        assert!(near.levels.iter().zip(&far.levels).all(|(a, b)| a.data.ptr_eq(&b.data)));
+        assert!(near.levels[0].data.ptr_eq(&source) && !near.levels[1].data.ptr_eq(&near.levels[2].data));
+        assert!(near.levels[2].data.read().indices.len() < near.levels[1].data.read().indices.len());
+
+        // Another mesh gets levels of its own
+        let other = lod::LodGroup::generate(&assets::meshes().add(MeshData::generate(PrimitiveType::Sphere(2))), lod::LodMetric::Distance, &[0.0, 10.0]);
+        assert!(!other.levels[1].data.ptr_eq(&near.levels[1].data));
+    }
+
+    #[test]
+    fn wavefront_parsing() {
+        use wavefront::parse_obj;
+        use std::path::Path;
+
+        let square = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n";
+
+        // Every face form resolves to the same positions, with whatever attributes it names
+        let faces = [
+            ("f 1/1/1 2/2/1 3/3/1 4/4/1", true, true),
+            ("f -4/-4/-1 -3/-3/-1 -2/-2/-1 -1/-1/-1", true, true),
+            ("f 1/1 2/2 3/3 4/4", true, false),
+            ("f 1//1 2//1 3//1 4//1", false, true),
+            ("f 1 2 3 4", false, false)
+        ];
+        for &(face, has_uvs, has_normals) in &faces {
+            let mesh = parse_obj(&format!("{}{}", square, face), Path::new("")).unwrap();
+
+            // Quads are split into a fan of two triangles around the first corner
+            assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3], "{}", face);
+            assert_eq!(mesh.vertices[2].position, [1.0, 1.0, 0.0], "{}", face);
+            assert_eq!(mesh.vertices[2].uv, if has_uvs { [1.0, 1.0] } else { [0.0; 2] }, "{}", face);
+            assert_eq!(mesh.vertices[2].normal, if has_normals { [0.0, 0.0, 1.0] } else { [0.0; 3] }, "{}", face);
+        }
+
+        // Negative indices count back from the last element defined so far, not from the end of the file
+        let relative = parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 5 5\nf -4 -3 -1", Path::new("")).unwrap();
+        assert_eq!(relative.vertices.len(), 4);
+        assert_eq!(relative.indices, vec![0, 1, 2, 0, 1, 3]);
+
+        let pentagon = parse_obj("v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5", Path::new("")).unwrap();
+        assert_eq!(pentagon.indices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4]);
+        assert_eq!(pentagon.submeshes.len(), 1);
+        assert_eq!(pentagon.submeshes[0].index_count, 9);
+
+        // Material libraries and their textures are found relative to the file naming them
+        let directory = std::env::temp_dir().join(format!("hephaestus_wavefront_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("materials")).unwrap();
+        std::fs::write(directory.join("materials/red.mtl"), "newmtl red\nKd 1 0 0\nmap_Kd -s 2 2 1 red.png\n").unwrap();
+
+        let textured = parse_obj("mtllib materials/red.mtl\nusemtl red\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3", &directory).unwrap();
+        let material = textured.submeshes[0].material.as_ref().unwrap();
+        assert_eq!(material.diffuse_color, [1.0, 0.0, 0.0]);
+        assert_eq!(material.diffuse_texture, Some(directory.join("materials").join("red.png").to_string_lossy().into_owned()));
+        assert_eq!(textured.vertices[0].color, [1.0, 0.0, 0.0]);
+
+        // A missing library only costs the colors
+        let unlit = parse_obj("mtllib missing.mtl\nusemtl red\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3", &directory).unwrap();
+        assert_eq!(unlit.submeshes[0].material.as_ref().map(|m| m.diffuse_color), Some([1.0; 3]));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Malformed lines are reported with their line number instead of panicking
+        let triangle = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+        for line in &["f 1 2", "f 1 2 4", "f 0 1 2", "f -4 1 2", "f 1/2 2 3", "f 1//2 2 3", "f a b c", "f 1/x 2 3", "v 1 2", "v 1 2 abc", "vt one", "vn 0 1"] {
+            let error = parse_obj(&format!("{}{}", triangle, line), Path::new("")).err().unwrap();
+            assert!(error.starts_with("line 4: "), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn mesh_export_round_trip() {
+        use mesh_data::{ MeshData, MeshMaterial, PrimitiveType };
+        use mesh_export::PlyEncoding;
+        use cgmath::{ Matrix4, Vector3 };
+        use std::path::Path;
+
+        let mut mesh = MeshData::generate(PrimitiveType::Icosphere { radius: 1.0, subdivisions: 1 });
+        let mut cube = MeshData::generate(PrimitiveType::Cube);
+        cube.transform(Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)));
+        mesh.append(&cube);
+        cube.transform(Matrix4::from_translation(Vector3::new(3.0, 0.0, 0.0)));
+        mesh.append(&cube);
+
+        let directory = std::env::temp_dir().join(format!("hephaestus_export_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |file: &str| directory.join(file).to_string_lossy().into_owned();
+
+        let texture = directory.join("textures").join("orange.png").to_string_lossy().into_owned();
+        mesh.submeshes[1].material = Some(MeshMaterial { diffuse_color: [1.0, 0.5, 0.25], diffuse_texture: Some(texture.clone()), ..MeshMaterial::named("orange") });
+
+        mesh.export_obj(&path("mesh.obj")).unwrap();
+        let loaded = MeshData::load(&path("mesh.obj"));
+
+        // The loader may share vertices differently, so compare the triangles corner by corner
+        assert_eq!(loaded.indices.len(), mesh.indices.len());
+        for (&a, &b) in mesh.indices.iter().zip(&loaded.indices) {
+            let (a, b) = (mesh.vertices[a as usize], loaded.vertices[b as usize]);
+            assert_eq!((a.position, a.uv, a.normal), (b.position, b.uv, b.normal));
+        }
+
+        assert_eq!(loaded.submeshes.len(), 3);
+        assert_eq!(loaded.submeshes[1].material.as_ref().map(|m| m.diffuse_color), Some([1.0, 0.5, 0.25]));
+        assert_eq!(loaded.vertices[loaded.indices[loaded.submeshes[1].first_index] as usize].color, [1.0, 0.5, 0.25]);
+
+        // Textures are found next to the library again, and parts after a material don't take it over
+        assert_eq!(loaded.submeshes[1].material.as_ref().and_then(|m| m.diffuse_texture.clone()), Some(texture));
+        assert!(std::fs::read_to_string(path("mesh.mtl")).unwrap().contains(&format!("map_Kd {}\n", Path::new("textures").join("orange.png").display())));
+        assert_eq!(loaded.submeshes[2].material.as_ref().map(|m| m.diffuse_color), Some([1.0; 3]));
+        assert_eq!(loaded.vertices[loaded.indices[loaded.submeshes[2].first_index] as usize].color, [1.0; 3]);
+
+        let (vertices, faces) = (mesh.vertices.len(), mesh.indices.len() / 3);
+
+        mesh.export_stl(&path("mesh.stl")).unwrap();
+        assert_eq!(std::fs::metadata(path("mesh.stl")).unwrap().len() as usize, 84 + faces * 50);
+
+        mesh.export_ply(&path("ascii.ply"), PlyEncoding::Ascii).unwrap();
+        let ascii = std::fs::read_to_string(path("ascii.ply")).unwrap();
+        assert!(ascii.contains(&format!("element vertex {}\n", vertices)));
+        assert_eq!(ascii.lines().skip_while(|l| *l != "end_header").count(), 1 + vertices + faces);
+
+        mesh.export_ply(&path("binary.ply"), PlyEncoding::BinaryLittleEndian).unwrap();
+        let binary = std::fs::read(path("binary.ply")).unwrap();
+        let header = binary.windows(11).position(|w| w == b"end_header\n").unwrap() + 11;
+        assert_eq!(binary.len(), header + vertices * (8 * 4 + 3) + faces * (1 + 3 * 4));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn static_batching() {
+        use world::World;
+        use entity::Mesh;
+        use mesh_data::{ MeshData, MeshType, PrimitiveType };
+        use cgmath::Deg;
+
+        let mut world = World::new();
+
+        for i in 0..10 {
+            let color = if i % 2 == 0 { [1.0; 3] } else { [1.0, 0.0, 0.0] };
+            let cube = world.new_entity()
+                .transform([i as f32, 0.0, 0.0], [0.5; 3], [Deg(0.0), Deg(i as f32 * 10.0), Deg(0.0)])
+                .mesh(MeshType::Primitive(PrimitiveType::Cube))
+                .material(color)
+                .static_mesh();
+            world.add_entity(cube);
+        }
+
+        // Entities without the marker are left alone, even when they share a material
+        let dynamic = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(0.0); 3])
+            .mesh(MeshType::Primitive(PrimitiveType::Cube))
+            .material([1.0; 3]);
+        world.add_entity(dynamic);
+
+        assert_eq!(world.batch_static_meshes(), 2);
+
+        let meshes = world.get_components_of_type::<Mesh>().unwrap();
+        let cube = MeshData::generate(PrimitiveType::Cube);
+        assert_eq!(meshes.len(), 3);
+
+        let batches: Vec<_> = meshes.iter().map(|m| m.data.read().clone()).filter(|m| m.indices.len() == 5 * cube.indices.len()).collect();
+        assert_eq!(batches.len(), 2);
+
+        for batch in batches {
+            assert_eq!(batch.parts().len(), 1);
+
+            // Transforms are baked in, so the merged geometry spreads along x
+            let max_x = batch.vertices.iter().map(|v| v.position[0]).fold(f32::MIN, f32::max);
+            assert!(max_x > 7.5);
+        }
+    }
+
+    #[test]
+    fn instancing() {
+        use world::World;
+        use buffer_objects::InstanceData;
+        use mesh_data::{ MeshType, PrimitiveType };
+        use cgmath::{ Deg, Matrix4, Vector3 };
+
+        let mut world = World::new();
+
+        for i in 0..10 {
+            let cube = world.new_entity()
+                .transform([i as f32, 0.0, 0.0], [0.5; 3], [Deg(0.0), Deg(i as f32 * 10.0), Deg(0.0)])
+                .mesh(MeshType::Primitive(PrimitiveType::Cube))
+                .material([i as f32 / 10.0, 0.0, 0.0]);
+            world.add_entity(cube);
+        }
+
+        let sphere = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(0.0); 3])
+            .mesh(MeshType::Primitive(PrimitiveType::Sphere(16)));
+        world.add_entity(sphere);
+
+        // Colors and transforms vary per instance, so only the mesh splits the groups
+        let mut sizes: Vec<usize> = world.instance_groups().iter().map(|g| g.len()).collect();
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![1, 10]);
+
+        // Shadow flags are shared by the whole draw
+        let unshadowed = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(0.0); 3])
+            .mesh(MeshType::Primitive(PrimitiveType::Cube))
+            .shadows(false, false);
+        world.add_entity(unshadowed);
+        assert_eq!(world.instance_groups().len(), 3);
+
+        // Normals are kept perpendicular to surfaces under non-uniform scales
+        let instance = InstanceData::new(Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0), [1.0; 3]);
+        assert_eq!(instance.model_0, [2.0, 0.0, 0.0, 0.0]);
+        assert_eq!(instance.normals_0, [0.5, 0.0, 0.0]);
+        assert_eq!(instance.normals_1, [0.0, 1.0, 0.0]);
+
+        let translated = InstanceData::new(Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)), [1.0; 3]);
+        assert_eq!(translated.model_3, [1.0, 2.0, 3.0, 1.0]);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan driver, run with --ignored"]
+    fn headless_rendering() {
+        use renderer::Renderer;
+        use engine::render_offscreen;
+        use world::World;
+        use light::Light;
+        use mesh_data::{ MeshType, PrimitiveType };
+        use cgmath::Deg;
+
+        // A software driver like lavapipe will do
+        let mut renderer = Renderer::headless(64, 48).unwrap();
+        assert_eq!(renderer.dimensions(), [64, 48]);
+
+        let mut world = World::new();
+        world.void_color = [0.0, 0.0, 0.0, 1.0];
+
+        let camera = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(0.0); 3])
+            .camera();
+        world.add_entity(camera);
+
+        // One of them is in front of the camera, whichever way it faces
+        for z in &[-3.0, 3.0] {
+            let cube = world.new_entity()
+                .transform([0.0, 0.0, *z], [1.0; 3], [Deg(0.0); 3])
+                .mesh(MeshType::Primitive(PrimitiveType::Cube))
+                .material([1.0, 0.5, 0.2]);
+            world.add_entity(cube);
+        }
+
+        let light = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(30.0), Deg(0.0), Deg(0.0)])
+            .light(Light::directional([1.0; 3], 0.5));
+        world.add_entity(light);
+
+        let pixels = render_offscreen(&mut renderer, &mut world).unwrap();
+        assert_eq!(pixels.len(), 64 * 48 * 4);
+        let pixel = |x: usize, y: usize| &pixels[(y * 64 + x) * 4..(y * 64 + x) * 4 + 3];
+
+        // The cube's front face spans the middle two thirds of the width, leaving the corners to the void
+        for &(x, y) in &[(0, 0), (63, 0), (0, 47), (63, 47), (10, 24), (53, 24)] {
+            assert_eq!(pixel(x, y), &[0, 0, 0], "pixel ({}, {}) isn't the void", x, y);
+        }
+        for &(x, y) in &[(12, 24), (51, 24), (32, 4), (32, 43), (32, 24)] {
+            let color = pixel(x, y);
+            assert!(color[0] > color[2] && color[0] >= color[1] && color[1] >= color[2], "pixel ({}, {}) is {:?}, not orange", x, y, color);
+        }
+
+        // Nothing in the scene tells left from right
+        for y in 0..48 {
+            for x in 0..32 {
+                let (left, right) = (pixel(x, y), pixel(63 - x, y));
+                assert!(left.iter().zip(right).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 2), "row {} isn't symmetric at {}", y, x);
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("hephaestus_headless_{}.png", std::process::id()));
+        renderer.save_png(path.to_str().unwrap(), &pixels).unwrap();
+        assert_eq!(image::image_dimensions(&path).unwrap(), (64, 48));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn light_volumes() {
+        use light::{ PointLight, SpotLight };
+        use cgmath::{ Deg, InnerSpace, Matrix4, Vector3, Vector4 };
+
+        let transformed = |matrix: Matrix4<f32>, p: [f32; 3]| (matrix * Vector4::new(p[0], p[1], p[2], 1.0)).truncate();
+
+        // The volume is a faceted sphere, so its faces have to sit outside the range for it to contain every lit point
+        let point = PointLight::new([1.0, 2.0, 3.0], [1.0; 3], 2.0, 5.0);
+        let mesh = PointLight::volume_mesh();
+        for face in mesh.indices.chunks(3) {
+            let center = face.iter()
+                .map(|&i| transformed(point.volume(), mesh.vertices[i as usize].position))
+                .fold(Vector3::new(0.0, 0.0, 0.0), |sum, p| sum + p / 3.0);
+
+            assert!((center - Vector3::from(point.position)).magnitude() >= point.range);
+        }
+
+        // Spot light volumes hang from the light's position along its direction
+        let spot = SpotLight::new([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [1.0; 3], 1.0, 4.0, Deg(20.0), Deg(30.0));
+        let apex = transformed(spot.volume(), [0.0, 0.5, 0.0]);
+        let base = transformed(spot.volume(), [0.0, -0.5, 0.0]);
+        let rim = transformed(spot.volume(), [1.0, -0.5, 0.0]);
+
+        assert!((apex - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-4);
+        assert!(base.x >= spot.range && (base.y - 1.0).abs() < 1e-4 && base.z.abs() < 1e-4);
+        assert!((rim - base).magnitude() >= spot.range * 30f32.to_radians().tan());
+
+        let (cos_inner, cos_outer) = spot.cone_cosines();
+        assert!(cos_inner > cos_outer);
+    }
+
+    #[test]
+    fn light_components() {
+        use world::World;
+        use entity::{ Component, Transform };
+        use light::{ Light, PlacedLight };
+        use cgmath::Deg;
+
+        let mut world = World::new();
+
+        let lamp = world.new_entity()
+            .transform([1.0, 2.0, 3.0], [1.0; 3], [Deg(0.0); 3])
+            .light(Light::point([1.0, 0.5, 0.0], 2.0, 5.0));
+        world.add_entity(lamp);
+
+        let sun = world.new_entity()
+            .transform([0.0; 3], [1.0; 3], [Deg(45.0), Deg(0.0), Deg(0.0)])
+            .light(Light::directional([1.0; 3], 0.5));
+        world.add_entity(sun);
+
+        let lights = world.get_components_of_type::<Light>().unwrap();
+        assert_eq!(lights.len(), 2);
+
+        for light in lights {
+            let transform = world.get_component_by_id::<Transform>(light.get_id()).unwrap();
+
+            match light.place(transform) {
+                PlacedLight::Point(point) => {
+                    assert_eq!(point.position, [1.0, 2.0, 3.0]);
+                    assert_eq!(point.range, 5.0);
+                },
+                // Shines along the forward vector, so the light is the other way
+                PlacedLight::Directional(directional) => {
+                    let forward = transform.forward_vector();
+                    let towards_light = directional.position;
+
+                    assert!((forward.x + towards_light[0]).abs() < 1e-5);
+                    assert!((forward.y + towards_light[1]).abs() < 1e-5);
+                    assert!((forward.z + towards_light[2]).abs() < 1e-5);
+                    assert_eq!(directional.color, [0.5; 3]);
+                },
+                PlacedLight::Spot(_) => panic!("No spot light was added")
+            }
+        }
+
+        // Moving the entity moves the light
+        let id = world.get_components_of_type::<Light>().unwrap().iter()
+            .find(|l| matches!(l.kind, light::LightKind::Point { .. }))
+            .unwrap()
+            .get_id();
+        world.get_component_by_id_mut::<Transform>(id).unwrap().translate([1.0, 0.0, 0.0]);
+
+        let transform = world.get_component_by_id::<Transform>(id).unwrap();
+        match world.get_component_by_id::<Light>(id).unwrap().place(transform) {
+            PlacedLight::Point(point) => assert_eq!(point.position, [2.0, 2.0, 3.0]),
+            _ => panic!("The light changed type")
+        }
+    }
+
+    #[test]
+    fn environment_lighting() {
+        use environment::Irradiance;
+        use texture_formats::DecodedImage;
+        use vulkano::format::Format;
+        use cgmath::Vector3;
+
+        let image = |width: u32, height: u32, texel: &dyn Fn(u32, u32) -> f32| {
+            let mut bytes = Vec::new();
+            for y in 0..height {
+                for x in 0..width {
+                    let value = texel(x, y);
+                    for channel in [value, value, value, 1.0].iter() {
+                        bytes.extend_from_slice(&channel.to_le_bytes());
+                    }
+                }
+            }
+
+            DecodedImage { width, height, format: Format::R32G32B32A32Sfloat, bytes }
+        };
+
+        let close = |color: [f32; 3], expected: f32| color.iter().all(|c| (c - expected).abs() < 0.02);
+        let directions = [Vector3::unit_x(), -Vector3::unit_y(), Vector3::new(1.0, 1.0, -1.0)];
+
+        // A white environment lights every side of a white surface fully
+        let white = Irradiance::from_equirectangular(&image(64, 32, &|_, _| 1.0)).unwrap();
+        assert!(directions.iter().all(|&d| close(white.evaluate(d), 1.0)));
+
+        let faces: Vec<_> = (0..6).map(|_| image(8, 8, &|_, _| 1.0)).collect();
+        let cubemap = Irradiance::from_cubemap(&faces).unwrap();
+        assert!(directions.iter().all(|&d| close(cubemap.evaluate(d), 1.0)));
+
+        assert!(directions.iter().all(|&d| close(Irradiance::uniform([1.0; 3]).evaluate(d), 1.0)));
+        assert!(Irradiance::from_cubemap(&faces[..5]).is_err());
+
+        // Bright sky over dark ground
+        let sky = Irradiance::from_equirectangular(&image(64, 32, &|_, y| if y < 16 { 1.0 } else { 0.0 })).unwrap();
+        let up = sky.evaluate(Vector3::unit_y())[0];
+        let down = sky.evaluate(-Vector3::unit_y())[0];
+        let side = sky.evaluate(Vector3::unit_z())[0];
+
+        assert!(up > 0.9 && down < 0.1);
+        assert!((side - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn shadow_projections() {
+        use light::{ sphere_in_light_space, ShadowSettings, SpotLight };
+        use cgmath::{ Deg, InnerSpace, Matrix4, Point3, Vector3 };
+
+        let project = |light_space: Matrix4<f32>, point: Vector3<f32>| {
+            let clip = light_space * point.extend(1.0);
+            clip.truncate() / clip.w
+        };
+        let inside = |ndc: Vector3<f32>| ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0 && (0.0..=1.0).contains(&ndc.z);
+
+        // Points along a spot light land in the middle of its map, farther ones deeper
+        let spot = SpotLight::new([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], [1.0; 3], 1.0, 10.0, Deg(20.0), Deg(30.0));
+        let near = project(spot.shadow_projection(), Vector3::new(0.0, 3.0, 0.0));
+        let far = project(spot.shadow_projection(), Vector3::new(0.0, 0.0, 0.0));
+
+        assert!(near.x.abs() < 1e-4 && near.y.abs() < 1e-4);
+        assert!(inside(near) && inside(far) && near.z < far.z);
+        assert!(!inside(project(spot.shadow_projection(), Vector3::new(5.0, 4.0, 0.0))));
+
+        // Each cascade of a directional light covers its slice of the camera's view
+        let settings = ShadowSettings::new(1024, 0.002, &[5.0, 20.0, 50.0, 100.0, 200.0]);
+        assert_eq!(settings.cascade_count(), 4);
+
+        let view = Matrix4::look_at_rh(Point3::new(0.0, 2.0, 0.0), Point3::new(0.0, 2.0, -1.0), Vector3::unit_y());
+        let direction = Vector3::new(0.3, -1.0, 0.2).normalize();
+        let cascades = settings.cascades(view, Deg(60.0), 16.0 / 9.0, 0.1, direction);
+        assert_eq!(cascades.len(), 4);
+
+        for (cascade, &distance) in cascades.iter().zip([3.0f32, 12.0, 35.0, 80.0].iter()) {
+            assert!(inside(project(*cascade, Vector3::new(0.0, 2.0, -distance))));
+        }
+
+        // Casters between the light and a surface are closer to the light
+        let ground = Vector3::new(0.0, 0.0, -3.0);
+        let caster = ground - direction * 2.0;
+        assert!(inside(project(cascades[0], caster)));
+        assert!(project(cascades[0], caster).z < project(cascades[0], ground).z);
+
+        // Casters are skipped once their bounding sphere is entirely outside a map, however little of it reaches in
+        assert!(sphere_in_light_space(spot.shadow_projection(), Vector3::new(0.0, 3.0, 0.0), 0.1));
+        assert!(!sphere_in_light_space(spot.shadow_projection(), Vector3::new(5.0, 4.0, 0.0), 0.5));
+        assert!(sphere_in_light_space(spot.shadow_projection(), Vector3::new(5.0, 4.0, 0.0), 5.0));
+        assert!(!sphere_in_light_space(spot.shadow_projection(), Vector3::new(0.0, -20.0, 0.0), 1.0));
+        assert!(!sphere_in_light_space(spot.shadow_projection(), Vector3::new(0.0, 7.0, 0.0), 1.0));
+
+        assert!(sphere_in_light_space(cascades[0], caster, 0.5));
+        assert!(!sphere_in_light_space(cascades[0], Vector3::new(0.0, 2.0, -150.0), 1.0));
+        assert!(sphere_in_light_space(cascades[3], Vector3::new(0.0, 2.0, -150.0), 1.0));
+    }
+
+    #[test]
+    fn pbr_materials() {
+        use entity::{ MaterialData, MaterialTextures };
+        use buffer_objects::InstanceData;
+        use cgmath::{ Matrix4, Vector3 };
+
+        // Plain colors are rough dielectrics, leaving the textures' channels unscaled
+        let plain = MaterialData::new([0.5, 0.25, 1.0]);
+        assert_eq!((plain.metallic, plain.roughness, plain.emissive), (0.0, 0.5, [0.0; 3]));
+        assert_eq!((plain.normal_scale, plain.occlusion_strength), (1.0, 1.0));
+        assert_eq!(plain.textures, MaterialTextures::default());
+
+        // Specular exponents become a GGX roughness whose highlight matches them, sharper exponents giving smoother surfaces
+        let mut previous = f32::MAX;
+        for &shininess in &[0.0, 1.0, 10.0, 96.078, 1000.0] {
+            let roughness = MaterialData::new([1.0; 3]).with_shininess(shininess).roughness;
+            let alpha = roughness * roughness;
+            assert!((2.0 / (alpha * alpha) - 2.0 - shininess).abs() < shininess.max(1.0) * 1e-4);
+            assert!(roughness > 0.0 && roughness <= 1.0 && roughness < previous);
+            previous = roughness;
+        }
+        assert_eq!(MaterialData::new([1.0; 3]).with_shininess(-5.0).roughness, 1.0);
+
+        // Instances carry the factors, packed the way the instanced shader reads them
+        let gold = MaterialData { metallic: 1.0, roughness: 0.3, emissive: [0.1, 0.2, 0.3], normal_scale: 0.5, ..MaterialData::new([1.0, 0.8, 0.3]) };
+        let instance = InstanceData::with_material(Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)), &gold);
+        assert_eq!(instance.instance_color, [1.0, 0.8, 0.3]);
+        assert_eq!(instance.instance_material, [1.0, 0.3, 0.5, 1.0]);
+        assert_eq!(instance.instance_emissive, [0.1, 0.2, 0.3]);
+        assert_eq!(instance.model_3, [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(InstanceData::new(Matrix4::from_scale(2.0), [0.5; 3]).instance_material, [0.0, 0.5, 1.0, 1.0]);
+
+        // Only the textures a material has get loaded
+        let mut textures = MaterialTextures { normal: Some("normal.png".to_string()), emissive: Some("glow.png".to_string()), ..Default::default() };
+        assert_eq!(textures.iter_mut().count(), 2);
+    }
+
+    #[test]
+    fn normal_matrices() {
+        use buffer_objects::{ normal_matrix, ModelBufferObject };
+        use cgmath::{ InnerSpace, Matrix4, Vector3, Vector4 };
+
+        // Squashing a sphere flattens its surface, so the normal of a slanted point leans towards the squashed axis
+        let model = Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0)) * Matrix4::from_nonuniform_scale(1.0, 0.25, 1.0);
+        let point = Vector3::new(1.0, 1.0, 0.0).normalize();
+        let normal = (normal_matrix(model) * point).normalize();
+
+        // The transformed normal stays perpendicular to the transformed surface
+        let tangent = (model * Vector4::new(-1.0, 1.0, 0.0, 0.0)).truncate();
+        assert!(normal.dot(tangent).abs() < 1e-5);
+        assert!(normal.y > normal.x);
+
+        // Uniform scales and translations leave directions alone
+        let uniform = ModelBufferObject::new(Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)) * Matrix4::from_scale(2.0));
+        let up = (uniform.normals * Vector4::unit_y()).truncate().normalize();
+        assert!((up - Vector3::unit_y()).magnitude() < 1e-5);
+        assert_eq!(uniform.normals.w, Vector4::unit_w());
+
+        assert_eq!(normal_matrix(Matrix4::from_scale(0.0)), cgmath::Matrix3::from_scale(1.0));
+    }
+
+    #[test]
+    fn specular_inputs() {
+        use camera::Camera;
+        use entity::{ MaterialData, Transform };
+        use cgmath::{ EuclideanSpace, InnerSpace, Point3, Rad, Vector3 };
+
+        // The lighting passes get the eye position back from the view they're drawn with
+        let mut camera = Camera::default();
+        let mut transform = Transform::default();
+        transform.translation = Vector3::new(1.0, 2.0, 3.0);
+        transform.rotate([Rad(0.3), Rad(0.8), Rad(0.0)]);
+        camera.calculate_view(&transform);
+
+        assert!((camera.position() - Point3::new(1.0, 2.0, 3.0)).magnitude() < 1e-4);
+        assert_eq!(Camera::default().position(), Point3::origin());
+
+        // Higher exponents give tighter highlights
+        let matte = MaterialData::new([1.0; 3]).with_shininess(10.0);
+        let glossy = MaterialData::new([1.0; 3]).with_shininess(500.0);
+        assert!(glossy.roughness < matte.roughness && matte.roughness < 1.0);
+        assert!((MaterialData::new([1.0; 3]).with_shininess(0.0).roughness - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn asset_handles() {
+        use assets::Assets;
+        use mesh_data::{ MeshData, PrimitiveType };
+
+        let store: Assets<MeshData> = Assets::new();
+        let mut loads = 0;
+
+        let a = store.load("models/teapot.obj", || { loads += 1; MeshData::load("models/teapot.obj") });
+        let b = store.load("models/teapot.obj", || { loads += 1; MeshData::empty() });
+
+        // The second load reuses the live asset instead of reading the file again
+        assert_eq!(loads, 1);
+        assert!(a.ptr_eq(&b));
+        assert_eq!(a.strong_count(), 2);
+        assert_eq!(store.len(), 1);
+
+        b.write().flip_winding();
+        assert_eq!(a.read().indices, b.read().indices);
+
+        let generation = a.generation();
+        b.touch();
+        assert_eq!(a.generation(), generation + 1);
+
+        let generated = store.add(MeshData::generate(PrimitiveType::Cube));
+        assert!(generated.key().is_none());
+        assert_eq!(store.len(), 1);
+
+        // Ids tell assets apart, e.g. as cache keys for their GPU resources
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a.id(), generated.id());
+
+        drop(a);
+        assert_eq!(store.len(), 1);
+        drop(b);
+        assert!(store.is_empty());
+
+        // Once every handle is gone, loading again goes back to the file
+        let c = store.load("models/teapot.obj", || { loads += 1; MeshData::empty() });
+        assert_eq!(loads, 2);
+        assert!(c.read().indices.is_empty());
+    }
+
+    #[test]
+    fn background_loading() {
+        use assets::{ Assets, LoadState };
+        use mesh_data::MeshData;
+
+        let store: Assets<MeshData> = Assets::new();
+
+        let teapot = store.load_async("models/teapot.obj", MeshData::empty(), || MeshData::try_load("models/teapot.obj"));
+        let missing = store.load_async("models/missing.obj", MeshData::empty(), || MeshData::try_load("models/missing.obj"));
+
+        // Requesting a file that is still loading shares the pending handle
+        let again = store.load_async("models/teapot.obj", MeshData::empty(), || unreachable!());
+        assert!(again.ptr_eq(&teapot));
+
+        assets::wait_for_loads(|progress| assert!(progress.finished <= progress.queued));
+
+        assert_eq!(teapot.state(), LoadState::Loaded);
+        assert!(!teapot.read().indices.is_empty());
+
+        // Failed loads keep their placeholder
+        assert!(matches!(missing.state(), LoadState::Failed(_)));
+        assert!(missing.read().indices.is_empty());
+
+        // Other tests load assets in parallel, so only this store's progress is known
+        assert_eq!(store.progress(), assets::LoadProgress { queued: 2, finished: 2, failed: 1 });
+    }
+
+    #[test]
+    fn asset_reload() {
+        use assets::{ Assets, LoadState };
+        use mesh_data::MeshData;
+
+        let store: Assets<MeshData> = Assets::new();
+        let suzanne = store.load("models/suzanne.obj", || MeshData::load("models/suzanne.obj"));
+        let generation = suzanne.generation();
+        let vertex_count = suzanne.read().vertices.len();
+
+        store.reload_async(&suzanne, || MeshData::try_load("models/teapot.obj"));
+        assets::wait_for_loads(|_| {});
+
+        // Every handle sees the reloaded data, and the generation tells users to refresh derived data
+        let shared = store.get("models/suzanne.obj").unwrap();
+        assert!(shared.ptr_eq(&suzanne));
+        assert_eq!(suzanne.generation(), generation + 1);
+        assert_ne!(shared.read().vertices.len(), vertex_count);
+
+        // A failed reload keeps the current data
+        let reloaded_count = suzanne.read().vertices.len();
+        store.reload_async(&suzanne, || Err("syntax error".to_string()));
+        assets::wait_for_loads(|_| {});
+
+        assert!(matches!(suzanne.state(), LoadState::Failed(_)));
+        assert_eq!(suzanne.generation(), generation + 1);
+        assert_eq!(suzanne.read().vertices.len(), reloaded_count);
+    }
+
+    #[test]
+    fn spirv_interfaces() {
+        use spirv::SpirvInterface;
+
+        let mut words = vec![0x0723_0203, 0x0001_0000, 0, 32, 0];
+        let mut op = |opcode: u32, operands: &[u32]| {
+            words.push(((operands.len() as u32 + 1) << 16) | opcode);
+            words.extend_from_slice(operands);
+        };
+
+        // float, vec3, vec4, mat4, uint and the constant 2
+        op(22, &[1, 32]);
+        op(23, &[2, 1, 3]);
+        op(23, &[3, 1, 4]);
+        op(24, &[4, 3, 4]);
+        op(21, &[5, 32, 0]);
+        op(43, &[5, 6, 2]);
+        // A uniform block holding vec4[2], and a push constant block { mat4; vec4 }
+        op(28, &[7, 3, 6]);
+        op(71, &[7, 6, 16]);
+        op(30, &[15, 7]);
+        op(72, &[15, 0, 35, 0]);
+        op(30, &[8, 4, 3]);
+        op(72, &[8, 0, 35, 0]);
+        op(72, &[8, 0, 7, 16]);
+        op(72, &[8, 1, 35, 64]);
+        // Pointers and variables: a vec3 and a mat4 input, a vec4 output, the push constants and the uniform block
+        op(32, &[10, 1, 2]);
+        op(32, &[11, 1, 4]);
+        op(32, &[12, 3, 3]);
+        op(32, &[13, 9, 8]);
+        op(32, &[16, 2, 15]);
+        op(59, &[10, 20, 1]);
+        op(59, &[11, 21, 1]);
+        op(59, &[12, 22, 3]);
+        op(59, &[13, 23, 9]);
+        op(59, &[16, 24, 2]);
+        op(71, &[20, 30, 0]);
+        op(71, &[21, 30, 1]);
+        op(71, &[22, 30, 0]);
+        op(71, &[24, 34, 0]);
+        op(71, &[24, 33, 2]);
+
+        let interface = SpirvInterface::reflect(&words).unwrap();
+        assert_eq!(interface, SpirvInterface {
+            inputs: vec![(0, 1), (1, 5)],
+            outputs: vec![(0, 1)],
+            descriptors: vec![(0, 2)],
+            push_constant_size: Some(80)
+        });
+
+        // Unused vertex inputs and descriptors are fine; anything the shader uses that the pipeline lacks isn't
+        let mut pipeline = interface.clone();
+        pipeline.inputs.push((5, 6));
+        pipeline.descriptors.push((1, 0));
+        assert_eq!(interface.mismatch(&pipeline), None);
+
+        assert!(interface.mismatch(&SpirvInterface { inputs: vec![(0, 1)], ..pipeline.clone() }).is_some());
+        assert!(interface.mismatch(&SpirvInterface { outputs: vec![(0, 1), (1, 2)], ..pipeline.clone() }).is_some());
+        assert!(interface.mismatch(&SpirvInterface { descriptors: vec![(0, 1)], ..pipeline.clone() }).is_some());
+        assert!(interface.mismatch(&SpirvInterface { push_constant_size: Some(64), ..pipeline.clone() }).is_some());
+        assert!(interface.mismatch(&SpirvInterface { push_constant_size: None, ..pipeline }).is_some());
+
+        assert!(SpirvInterface::reflect(&[1, 2, 3]).is_err());
+        assert!(SpirvInterface::reflect(&[0x0723_0203, 0x0001_0000, 0, 32, 0, 0x0005_0047]).is_err());
+    }
+
+    #[test]
+    fn texture_formats() {
+        use image::{ ImageBuffer, Luma, Rgb };
+        use ddsfile::{ AlphaMode, D3D10ResourceDimension, Dds, DxgiFormat, NewDxgiParams };
+        use vulkano::format::Format;
+        use texture_formats::{ decode, ColorSpace };
+
+        let directory = std::env::temp_dir().join(format!("hephaestus_texture_formats_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+
+        // Grayscale PNGs are expanded to RGBA instead of being read as if they were already RGBA
+        ImageBuffer::from_fn(3, 2, |x, _| Luma([x as u8 * 100])).save(path("gray.png")).unwrap();
+        let gray = decode(&path("gray.png"), ColorSpace::Srgb).unwrap();
+        assert_eq!((gray.width, gray.height, gray.format), (3, 2, Format::R8G8B8A8Srgb));
+        assert_eq!(gray.bytes.len(), 3 * 2 * 4);
+        assert_eq!(&gray.bytes[4..8], &[100, 100, 100, 255]);
+
+        // Data textures are never sRGB, and 16-bit data keeps its precision
+        ImageBuffer::from_pixel(2, 2, Rgb([0u16, 32768, 65535])).save(path("normal.png")).unwrap();
+        let normal = decode(&path("normal.png"), ColorSpace::Linear).unwrap();
+        assert_eq!(normal.format, Format::R16G16B16A16Sfloat);
+        assert_eq!(normal.bytes.len(), 2 * 2 * 8);
+        let texel = normal.linear_rgb().unwrap()[0];
+        assert!(texel[0] == 0.0 && (texel[1] - 0.5).abs() < 0.001 && texel[2] == 1.0);
+        assert_eq!(decode(&path("normal.png"), ColorSpace::Srgb).unwrap().format, Format::R8G8B8A8Srgb);
+
+        // Radiance files keep their range as half floats, clamped to the largest one
+        let file = std::fs::File::create(path("sky.hdr")).unwrap();
+        image::codecs::hdr::HdrEncoder::new(file).encode(&[Rgb([4.0f32, 2.0, 1.0]), Rgb([100_000.0; 3])], 2, 1).unwrap();
+        let sky = decode(&path("sky.hdr"), ColorSpace::Linear).unwrap();
+        assert_eq!((sky.width, sky.height, sky.format), (2, 1, Format::R16G16B16A16Sfloat));
+        let texels = sky.linear_rgb().unwrap();
+        assert_eq!(texels, vec![[4.0, 2.0, 1.0], [65504.0; 3]]);
+
+        ImageBuffer::from_pixel(4, 4, Rgb([255u8, 0, 0])).save(path("color.tga")).unwrap();
+        let tga = decode(&path("color.tga"), ColorSpace::Linear).unwrap();
+        assert_eq!(tga.format, Format::R8G8B8A8Unorm);
+        assert_eq!(&tga.bytes[..4], &[255, 0, 0, 255]);
+
+        // Block compressed files are uploaded as is, only the base level is kept
+        let dds = Dds::new_dxgi(NewDxgiParams {
+            height: 8,
+            width: 8,
+            depth: None,
+            format: DxgiFormat::BC1_UNorm_sRGB,
+            mipmap_levels: Some(4),
+            array_layers: None,
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: D3D10ResourceDimension::Texture2D,
+            alpha_mode: AlphaMode::Unknown
+        }).unwrap();
+        dds.write(&mut std::fs::File::create(path("color.dds")).unwrap()).unwrap();
+
+        let compressed = decode(&path("color.dds"), ColorSpace::Srgb).unwrap();
+        assert_eq!(compressed.format, Format::BC1_RGBASrgbBlock);
+        assert_eq!(compressed.bytes.len(), 2 * 2 * 8);
+        assert_eq!(decode(&path("color.dds"), ColorSpace::Linear).unwrap().format, Format::BC1_RGBAUnormBlock);
+
+        assert!(decode(&path("missing.png"), ColorSpace::Srgb).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn cpu_mipmaps() {
+        use vulkano::format::Format;
+        use texture_formats::DecodedImage;
+
+        // A 4x2 checkerboard of black and white
+        let bytes = (0..8).flat_map(|i| {
+            let c = if (i % 4 + i / 4) % 2 == 0 { 0 } else { 255 };
+            vec![c, c, c, 255]
+        }).collect();
+        let image = DecodedImage { width: 4, height: 2, format: Format::R8G8B8A8Srgb, bytes };
+
+        let chain = image.mip_chain();
+        assert_eq!(chain.iter().map(|l| (l.width, l.height)).collect::<Vec<_>>(), vec![(2, 1), (1, 1)]);
+        assert!(chain.iter().all(|l| l.bytes.len() == (l.width * l.height * 4) as usize));
+
+        // Half black, half white is linear 0.5, which is much brighter than 128 in sRGB
+        assert_eq!(&chain[1].bytes, &[188, 188, 188, 255]);
+
+        let linear = DecodedImage { format: Format::R8G8B8A8Unorm, ..image };
+        assert_eq!(&linear.mip_chain()[1].bytes, &[128, 128, 128, 255]);
+
+        // Block compressed data can't be filtered
+        let compressed = DecodedImage { width: 4, height: 4, format: Format::BC1_RGBAUnormBlock, bytes: vec![0; 8] };
+        assert!(compressed.mip_chain().is_empty());
+    }
+
+    #[test]
+    fn atlas_packing() {
+        use vulkano::format::Format;
+        use atlas::{ AtlasBuilder, AtlasDescription };
+        use texture_formats::DecodedImage;
+        use mesh_data::{ MeshData, MeshMaterial, SubMesh };
+
+        let solid = |width, height, value: u8| DecodedImage {
+            width,
+            height,
+            format: Format::R8G8B8A8Srgb,
+            bytes: vec![value; (width * height * 4) as usize]
+        };
+
+        let mut builder = AtlasBuilder::new(2, 256);
+        builder.add("wide", solid(40, 10, 10)).unwrap();
+        builder.add("tall", solid(12, 30, 20)).unwrap();
+        builder.add("square", solid(16, 16, 30)).unwrap();
+        assert_eq!(builder.add_sprite_sheet("walk", &solid(32, 16, 40), 8, 8).unwrap(), 8);
+        assert!(builder.add("data", DecodedImage { format: Format::R8G8B8A8Unorm, ..solid(1, 1, 0) }).is_err());
+
+        let atlas = builder.build("atlas.png").unwrap();
+        let description = &atlas.description;
+        assert_eq!(description.regions.len(), 11);
+        assert!(description.width.is_power_of_two() && description.height.is_power_of_two());
+
+        // Regions stay inside the atlas and keep their padding from each other
+        for (i, a) in description.regions.iter().enumerate() {
+            assert!(a.x >= 2 && a.y >= 2 && a.x + a.width + 2 <= description.width && a.y + a.height + 2 <= description.height);
+
+            for b in &description.regions[i + 1..] {
+                let apart = a.x + a.width + 4 <= b.x || b.x + b.width + 4 <= a.x || a.y + a.height + 4 <= b.y || b.y + b.height + 4 <= a.y;
+                assert!(apart, "{} overlaps {}", a.name, b.name);
+            }
+        }
+
+        // Pixels are copied into their region, and edges are extended into the padding
+        let tall = description.region("tall").unwrap();
+        let pixel = |x: u32, y: u32| atlas.image.bytes[((y * description.width + x) * 4) as usize];
+        assert_eq!(pixel(tall.x, tall.y), 20);
+        assert_eq!(pixel(tall.x - 2, tall.y + tall.height + 1), 20);
+
+        let directory = std::env::temp_dir().join(format!("hephaestus_atlas_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("atlas.txt").to_string_lossy().into_owned();
+        atlas.save(&path).unwrap();
+        assert_eq!(&AtlasDescription::load(&path).unwrap(), description);
+        assert!(directory.join("atlas.png").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let [u_min, v_min, u_max, v_max] = description.uv_rect(tall);
+        assert_eq!(description.map_uv(tall, [0.0, 1.0]), [u_min, v_max]);
+        assert_eq!(description.map_uv(tall, [1.0, 0.0]), [u_max, v_min]);
+
+        // Submeshes textured with packed images sample the atlas instead
+        let mut mesh = MeshData::generate(mesh_data::PrimitiveType::Cube);
+        let half = mesh.indices.len() / 2;
+        let material = |texture: &str| Some(MeshMaterial { diffuse_texture: Some(texture.into()), ..MeshMaterial::named(texture) });
+        mesh.submeshes = vec![
+            SubMesh { name: "a".into(), first_index: 0, index_count: half, material: material("square") },
+            SubMesh { name: "b".into(), first_index: half, index_count: mesh.indices.len() - half, material: material("unpacked.png") }
+        ];
+
+        // A vertex shared with the unpacked part keeps its UVs there, whichever part comes first
+        let shared = mesh.indices[half] as usize;
+        mesh.indices[1] = shared as u32;
+        let shared_uv = mesh.vertices[shared].uv;
+
+        assert_eq!(mesh.remap_to_atlas(description, "atlas.png"), 1);
+        assert_eq!(mesh.vertices[shared].uv, shared_uv);
+        assert_ne!(mesh.indices[1] as usize, shared);
+        let square = description.region("square").unwrap();
+        let [u_min, v_min, u_max, v_max] = description.uv_rect(square);
+        assert!(mesh.indices[..half].iter().all(|&i| {
+            let uv = mesh.vertices[i as usize].uv;
+            uv[0] >= u_min && uv[0] <= u_max && uv[1] >= v_min && uv[1] <= v_max
+        }));
+        assert_eq!(mesh.submeshes[0].material.as_ref().unwrap().diffuse_texture.as_deref(), Some("atlas.png"));
+        assert_eq!(mesh.submeshes[1].material.as_ref().unwrap().diffuse_texture.as_deref(), Some("unpacked.png"));
+
+        // Tiling UVs would sample the neighbouring regions, so those parts keep their own texture
+        let mut tiled = MeshData::generate(mesh_data::PrimitiveType::Cube);
+        tiled.submeshes = vec![SubMesh { name: "tiled".into(), first_index: 0, index_count: tiled.indices.len(), material: material("square") }];
+        for vertex in &mut tiled.vertices {
+            vertex.uv = [vertex.uv[0] * 4.0, vertex.uv[1] * 4.0];
+        }
+        let uvs: Vec<[f32; 2]> = tiled.vertices.iter().map(|v| v.uv).collect();
+
+        assert_eq!(tiled.remap_to_atlas(description, "atlas.png"), 0);
+        assert_eq!(tiled.vertices.iter().map(|v| v.uv).collect::<Vec<_>>(), uvs);
+        assert_eq!(tiled.submeshes[0].material.as_ref().unwrap().diffuse_texture.as_deref(), Some("square"));
+    }
+
+    #[test]
+    fn tonemapping() {
+        use tonemap::{ AutoExposure, Exposure, ToneOperator, Tonemapping, HISTOGRAM_BINS };
+
+        // Only the CPU copies of the curves and the histogram binning are checked here, not the shaders they mirror;
+        // those only run in headless_rendering
+        // Every curve keeps black black, never darkens brighter light and stays displayable
+        for operator in &[ToneOperator::Aces, ToneOperator::Reinhard, ToneOperator::Uncharted2] {
+            assert!(operator.apply([0.0; 3])[0].abs() < 1e-4);
+            assert!(operator.apply([0.18; 3])[0] > 0.0);
+
+            let mut previous = 0.0;
+            for i in 1..200 {
+                let mapped = operator.apply([i as f32 * 0.1; 3])[0];
+                assert!(mapped >= previous && mapped <= 1.0, "{:?} at {}", operator, i as f32 * 0.1);
+                previous = mapped;
+            }
+        }
+        assert!((ToneOperator::Uncharted2.apply([5.6; 3])[0] - 1.0).abs() < 1e-4);
+
+        // Pixels below the range aren't counted, the rest are spread over the other bins
+        let auto = AutoExposure::default();
+        assert_eq!(auto.bin(0.0), 0);
+        assert_eq!(auto.bin(auto.min_luminance), 1);
+        assert_eq!(auto.bin(auto.max_luminance * 100.0), HISTOGRAM_BINS - 1);
+        assert!(auto.bin(0.5) < auto.bin(1.0) && auto.bin(1.0) < auto.bin(2.0));
+
+        let mut histogram = vec![0; HISTOGRAM_BINS];
+        histogram[0] = 1000;
+        assert_eq!(auto.average_luminance(&histogram), None);
+
+        // A frame lit evenly averages to its own luminance, whatever the void around it holds
+        histogram[auto.bin(1.0)] = 500;
+        let average = auto.average_luminance(&histogram).unwrap();
+        assert!(average.log2().abs() < 0.05, "{}", average);
+
+        // The brightest pixels are left out, so a small bright light barely moves the average
+        histogram[HISTOGRAM_BINS - 1] = 20;
+        assert!((auto.average_luminance(&histogram).unwrap() - average).abs() < 1e-4);
+
+        assert!((auto.target_exposure(0.18) - 1.0).abs() < 1e-5);
+        assert_eq!(auto.target_exposure(0.0), auto.max_exposure);
+        assert_eq!(auto.target_exposure(1e6), auto.min_exposure);
+
+        // Adaptation moves towards the target without overshooting, and settles on it given time
+        let step = auto.adapt(1.0, 4.0, 0.1);
+        assert!(step > 1.0 && step < 4.0);
+        assert!((auto.adapt(1.0, 4.0, 100.0) - 4.0).abs() < 1e-3);
+        assert_eq!(auto.adapt(1.0, 4.0, 0.0), 1.0);
+
+        // sRGB targets already encode the default gamma
+        let settings = Tonemapping::default();
+        assert!(matches!(settings.exposure, Exposure::Auto(_)));
+        assert!((settings.gamma_power(true) - 1.0).abs() < 1e-6);
+        assert!((settings.gamma_power(false) - 1.0 / 2.2).abs() < 1e-6);
+    }
+}
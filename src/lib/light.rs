@@ -1,3 +1,211 @@
+use hephaestus_macros::*;
+
+use cgmath::{ Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, Quaternion, Rad, SquareMatrix, Vector3, Vector4, Zero };
+
+use crate::{
+    entity::{ Component, Transform },
+    environment::Irradiance,
+    mesh_data::{ MeshData, PrimitiveType }
+};
+
+// Light volumes are faceted, so they're scaled up a bit to contain the whole lit region
+const VOLUME_MARGIN: f32 = 1.15;
+
+// The lighting shaders hold this many cascades
+pub const MAX_CASCADES: usize = 4;
+
+// How far behind a cascade its shadow map starts, in cascade radii, so casters outside the camera's view still shade it
+const CASTER_DISTANCE: f32 = 2.0;
+
+// Near plane of spot light shadow maps
+const SPOT_SHADOW_NEAR: f32 = 0.05;
+
+// How a light's shadow map is drawn. Directional lights split the camera's view into cascades, each with its own map;
+// spot lights use one map covering their cone. Point lights don't cast shadows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    // Width and height of each map in texels. A directional light's cascades are laid side by side in one image,
+    // so `resolution` times the number of cascades has to fit the device's image size limit.
+    pub resolution: u32,
+    // Depth offset against surfaces shadowing themselves (acne), in shadow map depth
+    pub bias: f32,
+    // Distance from the camera where each cascade ends, increasing. Only the first `MAX_CASCADES` are used.
+    pub cascade_splits: Vec<f32>
+}
+
+impl ShadowSettings {
+    pub fn new(resolution: u32, bias: f32, cascade_splits: &[f32]) -> Self {
+        Self {
+            resolution,
+            bias,
+            cascade_splits: cascade_splits.to_vec()
+        }
+    }
+
+    pub fn cascade_count(&self) -> usize {
+        self.cascade_splits.len().min(MAX_CASCADES)
+    }
+
+    // Light space (view, orthographic projection and depth remapped to Vulkan's 0..1) of each cascade of a directional
+    // light shining along `direction`. Each one covers the bounding sphere of its slice of the camera's frustum, so it
+    // keeps its size as the camera turns, and moves in whole texels so shadow edges don't shimmer.
+    pub fn cascades(&self, view: Matrix4<f32>, fov: Deg<f32>, aspect: f32, near: f32, direction: Vector3<f32>) -> Vec<Matrix4<f32>> {
+        let camera_to_world = view.invert().unwrap_or_else(Matrix4::identity);
+        let tan_half_fov = (Rad::from(fov).0 / 2.0).tan();
+        let direction = direction.normalize();
+        let up = shadow_up(direction);
+
+        // Only turns, so cascade centers can be snapped in light space
+        let rotation = Matrix4::look_at_rh(Point3::origin(), Point3::from_vec(direction), up);
+        let inverse_rotation = rotation.invert().unwrap_or_else(Matrix4::identity);
+
+        let mut start = near;
+
+        self.cascade_splits.iter().take(MAX_CASCADES).map(|&end| {
+            let mut corners = Vec::with_capacity(8);
+
+            for &distance in [start, end].iter() {
+                let (half_width, half_height) = (distance * tan_half_fov * aspect, distance * tan_half_fov);
+
+                for &(x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].iter() {
+                    let corner = camera_to_world * Vector4::new(x * half_width, y * half_height, -distance, 1.0);
+                    corners.push(corner.truncate());
+                }
+            }
+
+            start = end;
+
+            let center = corners.iter().fold(Vector3::zero(), |sum, &c| sum + c) / corners.len() as f32;
+            let radius = corners.iter().map(|&c| (c - center).magnitude()).fold(0.0, f32::max).max(1e-3);
+
+            let texel = 2.0 * radius / self.resolution.max(1) as f32;
+            let light_center = (rotation * center.extend(1.0)).truncate();
+            let snapped = Vector3::new((light_center.x / texel).floor() * texel, (light_center.y / texel).floor() * texel, light_center.z);
+            let center = (inverse_rotation * snapped.extend(1.0)).truncate();
+
+            let eye = center - direction * radius * CASTER_DISTANCE;
+            let light_view = Matrix4::look_at_rh(Point3::from_vec(eye), Point3::from_vec(center), up);
+            let projection = cgmath::ortho(-radius, radius, -radius, radius, 0.0, radius * (CASTER_DISTANCE + 1.0));
+
+            unit_depth() * projection * light_view
+        }).collect()
+    }
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self::new(1024, 0.002, &[8.0, 24.0, 64.0])
+    }
+}
+
+// Light reaching every surface from all around. Flat by default; with an environment it depends on which way surfaces face,
+// tinted by `color`.
+#[derive(Clone, Debug)]
+pub struct AmbientLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub environment: Option<Irradiance>
+}
+
+impl AmbientLight {
+    pub fn new(color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            color,
+            intensity,
+            environment: None
+        }
+    }
+
+    pub fn with_environment(mut self, environment: Irradiance) -> Self {
+        self.environment = Some(environment);
+
+        self
+    }
+}
+
+impl Default for AmbientLight {
+    fn default() -> Self {
+        Self::new([1.0; 3], 0.1)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LightKind {
+    // Parallel rays, e.g. sunlight
+    Directional,
+    Point { range: f32 },
+    Spot { range: f32, inner_cone: Deg<f32>, outer_cone: Deg<f32> }
+}
+
+// A light placed by its entity's `Transform`. Directional and spot lights shine along its forward vector.
+#[derive(Clone, Component)]
+pub struct Light {
+    id: usize,
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // Casts shadows when set
+    pub shadows: Option<ShadowSettings>
+}
+
+// A light component in world space, as the renderer draws it
+pub enum PlacedLight {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight)
+}
+
+impl Light {
+    pub fn directional(color: [f32; 3], intensity: f32) -> Self {
+        Self::new(LightKind::Directional, color, intensity)
+    }
+
+    pub fn point(color: [f32; 3], intensity: f32, range: f32) -> Self {
+        Self::new(LightKind::Point { range }, color, intensity)
+    }
+
+    pub fn spot(color: [f32; 3], intensity: f32, range: f32, inner_cone: Deg<f32>, outer_cone: Deg<f32>) -> Self {
+        Self::new(LightKind::Spot { range, inner_cone, outer_cone }, color, intensity)
+    }
+
+    fn new(kind: LightKind, color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            id: 0,
+            kind,
+            color,
+            intensity,
+            shadows: None
+        }
+    }
+
+    pub fn with_shadows(mut self, settings: ShadowSettings) -> Self {
+        self.shadows = Some(settings);
+
+        self
+    }
+
+    pub fn place(&self, transform: &Transform) -> PlacedLight {
+        let position = transform.translation.into();
+        let direction = transform.forward_vector().normalize();
+
+        match self.kind {
+            LightKind::Directional => {
+                // The directional shader expects the direction towards the light
+                let towards_light = -direction;
+                let color = [self.color[0] * self.intensity, self.color[1] * self.intensity, self.color[2] * self.intensity];
+
+                PlacedLight::Directional(DirectionalLight::new(towards_light.extend(0.0).into(), color))
+            },
+            LightKind::Point { range } => {
+                PlacedLight::Point(PointLight::new(position, self.color, self.intensity, range))
+            },
+            LightKind::Spot { range, inner_cone, outer_cone } => {
+                PlacedLight::Spot(SpotLight::new(position, direction.into(), self.color, self.intensity, range, inner_cone, outer_cone))
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DirectionalLight {
     pub position: [f32; 4],
@@ -12,3 +220,132 @@ impl DirectionalLight {
         }
     }
 }
+
+// Lights everything within `range` of its position, falling off with the square of the distance
+#[derive(Clone)]
+pub struct PointLight {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32
+}
+
+impl PointLight {
+    pub fn new(position: [f32; 3], color: [f32; 3], intensity: f32, range: f32) -> Self {
+        Self {
+            position,
+            color,
+            intensity,
+            range
+        }
+    }
+
+    // Unit sphere rasterized to find the pixels the light reaches
+    pub fn volume_mesh() -> MeshData {
+        MeshData::generate(PrimitiveType::Icosphere { radius: 1.0, subdivisions: 2 })
+    }
+
+    // Places `volume_mesh` around the light's range
+    pub fn volume(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position.into()) * Matrix4::from_scale(self.range * VOLUME_MARGIN)
+    }
+}
+
+// A point light limited to a cone around `direction`. Full intensity inside the inner cone, fading out towards the outer one.
+// The angles are measured from the direction to the edge of each cone.
+#[derive(Clone)]
+pub struct SpotLight {
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_cone: Deg<f32>,
+    pub outer_cone: Deg<f32>
+}
+
+impl SpotLight {
+    pub fn new(position: [f32; 3], direction: [f32; 3], color: [f32; 3], intensity: f32, range: f32, inner_cone: Deg<f32>, outer_cone: Deg<f32>) -> Self {
+        Self {
+            position,
+            direction,
+            color,
+            intensity,
+            range,
+            inner_cone,
+            outer_cone
+        }
+    }
+
+    // Cone one unit high and wide, rasterized to find the pixels the light reaches
+    pub fn volume_mesh() -> MeshData {
+        MeshData::generate(PrimitiveType::Cone { radius: 1.0, height: 1.0, segments: 16 })
+    }
+
+    // Cosines of the inner and outer cone angles, clamped so the cones stay narrower than a hemisphere
+    pub fn cone_cosines(&self) -> (f32, f32) {
+        let outer = self.outer_cone.0.clamp(0.1, 89.0);
+        let inner = self.inner_cone.0.clamp(0.0, outer);
+
+        (inner.to_radians().cos(), outer.to_radians().cos())
+    }
+
+    // Places `volume_mesh` with its apex on the light, opening along `direction` up to the light's range
+    pub fn volume(&self) -> Matrix4<f32> {
+        let direction = Vector3::from(self.direction).normalize();
+        let (_, cos_outer) = self.cone_cosines();
+        let tan_outer = (1.0 - cos_outer * cos_outer).sqrt() / cos_outer;
+
+        let length = self.range * VOLUME_MARGIN;
+        let radius = length * tan_outer;
+
+        // The mesh is centered with its apex up, so it's moved to hang from the origin before pointing it along the light
+        let rotation = Quaternion::from_arc(-Vector3::unit_y(), direction, Some(Vector3::unit_x()));
+
+        Matrix4::from_translation(self.position.into())
+            * Matrix4::from(rotation)
+            * Matrix4::from_nonuniform_scale(radius, length, radius)
+            * Matrix4::from_translation(Vector3::new(0.0, -0.5, 0.0))
+    }
+
+    // Light space of the spot light's shadow map: a perspective view through the outer cone, out to the light's range
+    pub fn shadow_projection(&self) -> Matrix4<f32> {
+        let direction = Vector3::from(self.direction).normalize();
+        let (_, cos_outer) = self.cone_cosines();
+
+        let position = Point3::from(self.position);
+        let view = Matrix4::look_at_rh(position, position + direction, shadow_up(direction));
+        let projection = cgmath::perspective(Rad(2.0 * cos_outer.acos()), 1.0, SPOT_SHADOW_NEAR, self.range.max(SPOT_SHADOW_NEAR * 2.0));
+
+        unit_depth() * projection * view
+    }
+}
+
+// Whether a sphere reaches into the volume a shadow map's light space (from `cascades` or `shadow_projection`) covers,
+// so casters entirely outside of it can be skipped. Tested against the planes of the clip volume, depth being 0..1.
+pub fn sphere_in_light_space(light_space: Matrix4<f32>, center: Vector3<f32>, radius: f32) -> bool {
+    let row = |i: usize| Vector4::new(light_space.x[i], light_space.y[i], light_space.z[i], light_space.w[i]);
+    let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+
+    [w + x, w - x, w + y, w - y, z, w - z].iter().all(|plane| {
+        let normal = plane.truncate();
+        let length = normal.magnitude();
+
+        length == 0.0 || (normal.dot(center) + plane.w) / length >= -radius
+    })
+}
+
+// Any vector not parallel to `direction`, to orient shadow maps around it
+fn shadow_up(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() }
+}
+
+// cgmath's projections map depth to -1..1 like OpenGL, while Vulkan clips it to 0..1
+fn unit_depth() -> Matrix4<f32> {
+    Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.5, 0.0,
+        0.0, 0.0, 0.5, 1.0
+    )
+}
@@ -0,0 +1,386 @@
+use hephaestus_macros::*;
+use std::collections::{ HashMap, HashSet };
+use cgmath::{ Deg, InnerSpace, Vector3 };
+
+use crate::{
+    assets::{ self, Handle },
+    entity::{ Component, Transform },
+    mesh_data::{ MeshData, SubMesh },
+};
+
+// Open edges are weighted heavily so simplification doesn't eat away at a mesh's outline
+const BORDER_WEIGHT: f64 = 10.0;
+// Cosine of the largest rotation a collapse may apply to a neighbouring triangle
+const MAX_TILT_COS: f64 = 0.5;
+
+// Symmetric 4x4 error quadric (Garland & Heckbert), stored as its upper triangle
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: Vector3<f64>, d: f64, weight: f64) -> Self {
+        let (a, b, c) = (normal.x, normal.y, normal.z);
+
+        Quadric([
+            a * a, a * b, a * c, a * d,
+                   b * b, b * c, b * d,
+                          c * c, c * d,
+                                 d * d
+        ].map(|q| q * weight))
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        self.0.iter_mut().zip(other.0.iter()).for_each(|(a, b)| *a += b);
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9]
+    }
+}
+
+impl MeshData {
+    // Largest distance from the mesh's origin to any vertex, used as a bounding sphere
+    pub fn bounding_radius(&self) -> f32 {
+        self.vertices.iter()
+            .map(|v| Vector3::from(v.position).magnitude())
+            .fold(0.0, f32::max)
+    }
+
+    // Quadric-error simplification down to roughly `ratio` of the original triangle count.
+    // Edges are collapsed onto one of their endpoints, so vertices keep their own normals and UVs
+    // and seams between submeshes or UV islands stay intact.
+    pub fn simplify(&self, ratio: f32) -> MeshData {
+        let triangle_count = self.indices.len() / 3;
+        let target = ((triangle_count as f32 * ratio.clamp(0.0, 1.0)) as usize).max(1);
+
+        // Vertices split along UV or normal seams share a position, and are collapsed together
+        let mut position_ids: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut positions: Vec<Vector3<f64>> = Vec::new();
+        let mut vertex_position: Vec<usize> = self.vertices.iter()
+            .map(|v| {
+                let key = [v.position[0].to_bits(), v.position[1].to_bits(), v.position[2].to_bits()];
+
+                *position_ids.entry(key).or_insert_with(|| {
+                    positions.push(Vector3::from(v.position).cast().unwrap());
+                    positions.len() - 1
+                })
+            })
+            .collect();
+
+        let mut position_vertices: Vec<Vec<usize>> = vec![Vec::new(); positions.len()];
+        for (vertex, &position) in vertex_position.iter().enumerate() {
+            position_vertices[position].push(vertex);
+        }
+
+        let triangles: Vec<[usize; 3]> = self.indices.chunks_exact(3)
+            .map(|f| [f[0] as usize, f[1] as usize, f[2] as usize])
+            .collect();
+
+        let mut position_triangles: Vec<Vec<usize>> = vec![Vec::new(); positions.len()];
+        let mut quadrics = vec![Quadric::default(); positions.len()];
+        let mut edge_use: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+
+        for (t, triangle) in triangles.iter().enumerate() {
+            let p = triangle.map(|v| vertex_position[v]);
+            let cross = (positions[p[1]] - positions[p[0]]).cross(positions[p[2]] - positions[p[0]]);
+            let area = cross.magnitude();
+
+            if area <= f64::EPSILON {
+                continue;
+            }
+
+            let normal = cross / area;
+            let plane = Quadric::from_plane(normal, -normal.dot(positions[p[0]]), area);
+
+            for k in 0..3 {
+                quadrics[p[k]].add(&plane);
+                position_triangles[p[k]].push(t);
+
+                let edge = (p[k].min(p[(k + 1) % 3]), p[k].max(p[(k + 1) % 3]));
+                edge_use.entry(edge).or_insert((0, t)).0 += 1;
+            }
+        }
+
+        for (&(a, b), &(uses, t)) in &edge_use {
+            if uses != 1 {
+                continue;
+            }
+
+            let p = triangles[t].map(|v| vertex_position[v]);
+            let face_normal = (positions[p[1]] - positions[p[0]]).cross(positions[p[2]] - positions[p[0]]).normalize();
+            let edge = positions[b] - positions[a];
+            let border_normal = edge.cross(face_normal).normalize();
+            let border = Quadric::from_plane(border_normal, -border_normal.dot(positions[a]), edge.magnitude2() * BORDER_WEIGHT);
+
+            quadrics[a].add(&border);
+            quadrics[b].add(&border);
+        }
+
+        let degenerate = |triangle: &[usize; 3], vertex_position: &[usize]| {
+            let p = triangle.map(|v| vertex_position[v]);
+            p[0] == p[1] || p[1] == p[2] || p[2] == p[0]
+        };
+
+        // Collapsing `from` onto `to` must not turn any of the remaining triangles around `from` inside out,
+        // or tilt them so far that they end up standing on their side
+        let flips = |from: usize, to: usize, vertex_position: &[usize], position_triangles: &[Vec<usize>], positions: &[Vector3<f64>]| {
+            position_triangles[from].iter().any(|&t| {
+                let p = triangles[t].map(|v| vertex_position[v]);
+
+                if p.contains(&to) || degenerate(&triangles[t], vertex_position) {
+                    return false;
+                }
+
+                let before = (positions[p[1]] - positions[p[0]]).cross(positions[p[2]] - positions[p[0]]);
+                let moved = p.map(|q| if q == from { positions[to] } else { positions[q] });
+                let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+
+                before.dot(after) <= before.magnitude() * after.magnitude() * MAX_TILT_COS
+            })
+        };
+
+        // Collapse in passes: each pass sorts the current edges by cost and collapses the cheapest ones
+        // that don't share a position with an edge already collapsed in the same pass
+        loop {
+            let live: Vec<usize> = (0..triangles.len())
+                .filter(|&t| !degenerate(&triangles[t], &vertex_position))
+                .collect();
+
+            if live.len() <= target {
+                break;
+            }
+
+            let mut edges: Vec<(f64, usize, usize)> = Vec::new();
+            let mut seen = HashSet::new();
+
+            for &t in &live {
+                let p = triangles[t].map(|v| vertex_position[v]);
+
+                for k in 0..3 {
+                    let (a, b) = (p[k], p[(k + 1) % 3]);
+                    if !seen.insert((a.min(b), a.max(b))) {
+                        continue;
+                    }
+
+                    let mut combined = quadrics[a];
+                    combined.add(&quadrics[b]);
+
+                    let (cost_ab, cost_ba) = (combined.error(positions[b]), combined.error(positions[a]));
+                    if cost_ab <= cost_ba {
+                        edges.push((cost_ab, a, b));
+                    } else {
+                        edges.push((cost_ba, b, a));
+                    }
+                }
+            }
+
+            edges.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(std::cmp::Ordering::Equal));
+
+            // Every collapse removes about two triangles
+            let wanted = ((live.len() - target) / 2).max(1);
+            let mut locked = vec![false; positions.len()];
+            let mut collapsed = 0;
+
+            for &(_, from, to) in &edges {
+                if collapsed >= wanted {
+                    break;
+                }
+
+                if locked[from] || locked[to] || flips(from, to, &vertex_position, &position_triangles, &positions) {
+                    continue;
+                }
+
+                let moved = std::mem::take(&mut position_vertices[from]);
+                for &v in &moved {
+                    vertex_position[v] = to;
+                }
+                position_vertices[to].extend(moved);
+
+                let moved_triangles = std::mem::take(&mut position_triangles[from]);
+                position_triangles[to].extend(moved_triangles);
+
+                let from_quadric = quadrics[from];
+                quadrics[to].add(&from_quadric);
+
+                locked[from] = true;
+                locked[to] = true;
+                collapsed += 1;
+            }
+
+            if collapsed == 0 {
+                break;
+            }
+        }
+
+        // Rebuild the mesh from the surviving triangles, keeping them grouped by submesh
+        let mut data = MeshData::empty();
+        let mut remap: HashMap<usize, u32> = HashMap::new();
+
+        for part in self.parts() {
+            let first_index = data.indices.len();
+            let first_triangle = part.first_index / 3;
+            let last_triangle = first_triangle + part.index_count / 3;
+
+            for triangle in &triangles[first_triangle..last_triangle] {
+                if degenerate(triangle, &vertex_position) {
+                    continue;
+                }
+
+                for &v in triangle {
+                    let index = *remap.entry(v).or_insert_with(|| {
+                        let mut vertex = self.vertices[v];
+                        let p = positions[vertex_position[v]];
+                        vertex.position = [p.x as f32, p.y as f32, p.z as f32];
+                        data.vertices.push(vertex);
+
+                        (data.vertices.len() - 1) as u32
+                    });
+
+                    data.indices.push(index);
+                }
+            }
+
+            if !self.submeshes.is_empty() {
+                data.submeshes.push(SubMesh {
+                    first_index,
+                    index_count: data.indices.len() - first_index,
+                    ..part
+                });
+            }
+        }
+
+        data
+    }
+
+    // Successively simplified copies of this mesh, each with `ratio` of the previous level's triangles.
+    // The first element is always a copy of the original mesh.
+    pub fn generate_lods(&self, levels: usize, ratio: f32) -> Vec<MeshData> {
+        let mut lods = vec![self.clone()];
+
+        for _ in 1..levels {
+            let next = lods.last().unwrap().simplify(ratio);
+            lods.push(next);
+        }
+
+        lods
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LodMetric {
+    // Level `i` is used once the camera is at least `threshold` units away
+    Distance,
+    // Level `i` is used once the mesh's bounding sphere covers at most `threshold` of the screen height
+    ScreenSize
+}
+
+#[derive(Clone)]
+pub struct LodLevel {
+    pub data: Handle<MeshData>,
+    pub threshold: f32
+}
+
+#[derive(Clone, Component)]
+pub struct LodGroup {
+    id: usize,
+    pub levels: Vec<LodLevel>,
+    pub metric: LodMetric,
+    pub current: usize,
+    source: Handle<MeshData>,
+    // Generation of `source` the levels were built from
+    source_generation: Option<usize>,
+    thresholds: Vec<f32>,
+    bounding_radius: f32
+}
+
+impl LodGroup {
+    // Simplifies `mesh` into one level per threshold, halving the triangle count at each step.
+    // The first level shares the original mesh. Meshes still loading in the background are simplified once they finish.
+    pub fn generate(mesh: &Handle<MeshData>, metric: LodMetric, thresholds: &[f32]) -> Self {
+        let mut group = Self {
+            id: 0,
+            levels: Vec::new(),
+            metric,
+            current: 0,
+            source: mesh.clone(),
+            source_generation: None,
+            thresholds: thresholds.to_vec(),
+            bounding_radius: 0.0
+        };
+        group.build_levels();
+
+        group
+    }
+
+    // (Re)builds the levels once the source mesh has loaded, and again whenever it is reloaded.
+    // Returns whether the levels changed.
+    fn build_levels(&mut self) -> bool {
+        let generation = self.source.generation();
+
+        if self.source_generation == Some(generation) || !self.source.is_loaded() {
+            return false;
+        }
+
+        self.source_generation = Some(generation);
+        self.bounding_radius = self.source.read().bounding_radius();
+
+        // Each level halves the one before it, and is kept in the mesh store under the source and its generation.
+        // Every group built from the same mesh then shares its levels, so entities at the same level still instance
+        // together. The source's id keeps generated meshes apart, and is unique for as long as it's held here.
+        let mut levels = vec![self.source.clone()];
+        for i in 1..self.thresholds.len() {
+            let key = format!("{}@{:x}/{}#lod{}", self.source.key().unwrap_or("generated"), self.source.id(), generation, i);
+            let previous = levels[i - 1].clone();
+
+            levels.push(assets::meshes().load(&key, || previous.read().simplify(0.5)));
+        }
+
+        self.levels = levels.into_iter()
+            .zip(self.thresholds.iter())
+            .map(|(data, &threshold)| LodLevel { data, threshold })
+            .collect();
+
+        true
+    }
+
+    pub fn select(&self, transform: &Transform, camera_position: Vector3<f32>, fov: Deg<f32>) -> usize {
+        let distance = (transform.translation - camera_position).magnitude();
+
+        match self.metric {
+            LodMetric::Distance => {
+                self.levels.iter()
+                    .rposition(|l| distance >= l.threshold)
+                    .unwrap_or(0)
+            },
+            LodMetric::ScreenSize => {
+                let scale = transform.scale.x.abs().max(transform.scale.y.abs()).max(transform.scale.z.abs());
+                let radius = self.bounding_radius * scale;
+                let half_height = distance * (fov.0.to_radians() / 2.0).tan();
+                let screen_size = if half_height > 0.0 { radius / half_height } else { f32::MAX };
+
+                self.levels.iter()
+                    .rposition(|l| screen_size <= l.threshold)
+                    .unwrap_or(0)
+            }
+        }
+    }
+
+    // Switches to the level for the current camera position, returning the new mesh data if it changed
+    pub fn update(&mut self, transform: &Transform, camera_position: Vector3<f32>, fov: Deg<f32>) -> Option<Handle<MeshData>> {
+        let rebuilt = self.build_levels();
+        let level = self.select(transform, camera_position, fov);
+
+        if (rebuilt || level != self.current) && level < self.levels.len() {
+            self.current = level;
+            Some(self.levels[level].data.clone())
+        } else {
+            None
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
-use std::cell::RefCell;
+use std::cell::{ Cell, RefCell };
+use std::sync::Mutex;
 use std::time::SystemTime;
 use chrono::{ DateTime, Local };
 use egui::Color32;
@@ -196,22 +197,62 @@ impl Logger for RefCell<Log> {
 
 thread_local! {
     pub static APP_LOGGER: RefCell<Log> = RefCell::new(Log { messages: None });
+    // Set on worker threads, whose own logs nobody reads
+    static FORWARDING: Cell<bool> = Cell::new(false);
+}
+
+// Messages from worker threads, waiting for the main thread to log them
+static FORWARDED: Mutex<Vec<Message>> = Mutex::new(Vec::new());
+
+// Queues the message for the main thread when called from a forwarding thread, returning whether it was
+fn forwarded(content: &str, level: LogLevel, emitter: &MessageEmitter) -> bool {
+    if !FORWARDING.with(|forwarding| forwarding.get()) {
+        return false;
+    }
+
+    FORWARDED.lock().unwrap().push(Message::new(content.into(), level, emitter.clone()));
+
+    true
 }
 
 pub fn log_debug(content: &str, emitter: MessageEmitter) {
-    APP_LOGGER.with(|logger| logger.log_debug(content, emitter));
+    if !forwarded(content, LogLevel::Debug, &emitter) {
+        APP_LOGGER.with(|logger| logger.log_debug(content, emitter));
+    }
 }
 
 pub fn log_info(content: &str, emitter: MessageEmitter) {
-    APP_LOGGER.with(|logger| logger.log_info(content, emitter));
+    if !forwarded(content, LogLevel::Info, &emitter) {
+        APP_LOGGER.with(|logger| logger.log_info(content, emitter));
+    }
 }
 
 pub fn log_warning(content: &str, emitter: MessageEmitter) {
-    APP_LOGGER.with(|logger| logger.log_warning(content, emitter));
+    if !forwarded(content, LogLevel::Warning, &emitter) {
+        APP_LOGGER.with(|logger| logger.log_warning(content, emitter));
+    }
 }
 
 pub fn log_error(content: &str, emitter: MessageEmitter) {
-    APP_LOGGER.with(|logger| logger.log_error(content, emitter));
+    if !forwarded(content, LogLevel::Error, &emitter) {
+        APP_LOGGER.with(|logger| logger.log_error(content, emitter));
+    }
+}
+
+// Makes everything the calling thread logs from now on show up in the log of whichever thread calls `flush_forwarded`
+pub fn forward_to_main_thread() {
+    FORWARDING.with(|forwarding| forwarding.set(true));
+}
+
+// Logs the messages worker threads forwarded since the last call
+pub fn flush_forwarded() {
+    let messages = std::mem::take(&mut *FORWARDED.lock().unwrap());
+
+    APP_LOGGER.with(|logger| {
+        for message in messages {
+            logger.log(message);
+        }
+    });
 }
 
 pub fn get_messages() -> Vec<Message> {
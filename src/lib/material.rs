@@ -1,6 +1,4 @@
 use std::sync::Arc;
-use std::io::Cursor;
-use std::fs;
 use vulkano::device::{ Device, Queue };
 use vulkano::format::Format;
 use vulkano::image::{ ImageDimensions, ImmutableImage, view::ImageView };
@@ -8,6 +6,11 @@ use vulkano::sampler::Sampler;
 use vulkano::command_buffer::{ PrimaryAutoCommandBuffer, CommandBufferExecFuture };
 use vulkano::sync::NowFuture;
 
+use crate::{
+    texture_formats::{ self, ColorSpace },
+    logger::{ self, MessageEmitter }
+};
+
 pub trait Material: MaterialClone {
     fn get_color(&self) -> [f32; 3];
     fn set_color(&mut self, color: [f32; 3]);
@@ -35,7 +38,7 @@ impl Clone for Box<dyn Material> {
 #[derive(Clone)]
 pub struct Diffuse {
     color: [f32; 3],
-    texture_data: Option<(Vec<u8>, ImageDimensions)>,
+    texture_data: Option<(Vec<u8>, ImageDimensions, Format)>,
 }
 
 impl Diffuse {
@@ -60,31 +63,36 @@ impl Material for Diffuse {
     }
    
     fn add_texture(&mut self, tex_path: &str) {
-        let png_bytes = fs::read(&tex_path).unwrap(); 
-        let cursor = Cursor::new(png_bytes);
-        let decoder = png::Decoder::new(cursor);
-        let mut reader = decoder.read_info().unwrap();
-        let info = reader.info();
+        // Keep the previous texture, or a white pixel if there's none yet, so the material can still be drawn
+        let image = match texture_formats::decode(tex_path, ColorSpace::Srgb) {
+            Ok(image) => image,
+            Err(e) => {
+                logger::log_error(&format!("Unable to load texture '{}': {}", tex_path, e), MessageEmitter::Renderer);
+
+                if self.texture_data.is_none() {
+                    let dimensions = ImageDimensions::Dim2d { width: 1, height: 1, array_layers: 1 };
+                    self.texture_data = Some((vec![255; 4], dimensions, Format::R8G8B8A8Srgb));
+                }
+                return;
+            }
+        };
         let dimensions = ImageDimensions::Dim2d {
-            width: info.width,
-            height: info.height,
+            width: image.width,
+            height: image.height,
             array_layers: 1
         };
-        let mut image_data = Vec::new();
-        image_data.resize((info.width * info.height * 4) as usize, 0);
-        reader.next_frame(&mut image_data).unwrap();
 
-        self.texture_data = Some((image_data, dimensions));
+        self.texture_data = Some((image.bytes, dimensions, image.format));
     }
     
     fn get_texture_buffer(&self, queue: &Arc<Queue>) -> (Arc<ImageView<Arc<ImmutableImage>>>, CommandBufferExecFuture<NowFuture, PrimaryAutoCommandBuffer>) {
-        let (tex_bytes, dimensions) = self.texture_data.as_ref().unwrap();
+        let (tex_bytes, dimensions, format) = self.texture_data.as_ref().unwrap();
         
         let (image, future) = ImmutableImage::from_iter(
             tex_bytes.iter().cloned(),
             *dimensions,
             vulkano::image::MipmapsCount::One,
-            Format::R8G8B8A8Srgb,
+            *format,
             queue.clone()
         ).unwrap();
         
@@ -1,114 +1,204 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashMap;
+use std::f32::consts::{ FRAC_PI_2, PI, TAU };
 use cgmath::{ InnerSpace, Vector3 };
-use obj::{ Obj, TexturedVertex, load_obj };
 
 use crate::{ 
     buffer_objects::Vertex,
+    mesh_processing::NormalMode,
+    terrain::TerrainSettings,
+    wavefront,
     logger::{ self, MessageEmitter }
 };
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum PrimitiveType {
-    Plane,
+    // 1x1 plane on the XZ axis, split into (subdivisions + 1)^2 quads
+    Plane { subdivisions: u32 },
+    // Upright quad on the XY axis, facing +Z
+    Quad { width: f32, height: f32 },
     Cube,
-    Sphere(u8)
+    Sphere(u8),
+    Icosphere { radius: f32, subdivisions: u8 },
+    Cylinder { radius: f32, height: f32, segments: u32 },
+    Cone { radius: f32, height: f32, segments: u32 },
+    // `radius` is measured from the center to the middle of the tube
+    Torus { radius: f32, tube_radius: f32, segments: u32, sides: u32 },
+    // `height` includes both hemispherical caps
+    Capsule { radius: f32, height: f32, segments: u32, rings: u32 }
 }
 
 #[derive(Clone)]
 pub enum MeshType {
     Model(String),
-    Primitive(PrimitiveType)
+    Primitive(PrimitiveType),
+    Terrain(TerrainSettings),
+    // Static geometry merged by `World::batch_static_meshes`, which has no source to be rebuilt from
+    Batched
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshMaterial {
+    pub name: String,
+    pub diffuse_color: [f32; 3],
+    pub diffuse_texture: Option<String>
+}
+
+impl MeshMaterial {
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            diffuse_color: [1.0; 3],
+            diffuse_texture: None
+        }
+    }
+}
+
+// A contiguous range of `MeshData::indices` drawn with its own material
+#[derive(Clone, Debug)]
+pub struct SubMesh {
+    pub name: String,
+    pub first_index: usize,
+    pub index_count: usize,
+    pub material: Option<MeshMaterial>
 }
 
 #[derive(Clone)]
 pub struct MeshData {
     pub vertices: Vec<Vertex>,
-    pub indices: Vec<u16>
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<SubMesh>
 }
 
 impl MeshData {
     pub fn empty() -> Self {
         Self {
             vertices: Vec::new(),
-            indices: Vec::new()
+            indices: Vec::new(),
+            submeshes: Vec::new()
         }
     }
 
     pub fn load(path: &str) -> MeshData {
-        if let Some(file) = File::open(path).ok() {
-            let input = BufReader::new(file);
-
-            if let Some(object) = load_obj(input).ok() as Option<Obj<TexturedVertex, u16>> {
-                let mut data = MeshData::empty();
-                data.indices = object.indices;
-                
-                data.vertices = object.vertices.iter()
-                    .map(|v| Vertex {
-                        position: v.position,
-                        normal: v.normal,
-                        color: [1.0; 3],
-                        uv: [v.texture[0], v.texture[1]]
-                    })
-                    .collect();
+        Self::try_load(path).unwrap_or_else(|e| {
+            logger::log_error(&e, MessageEmitter::World);
+            MeshData::empty()
+        })
+    }
 
-                data
-            } else {
-                logger::log_error(&format!("Unable to load object data from '{}'", path), MessageEmitter::World);
-                MeshData::empty()
-            }
+    pub fn try_load(path: &str) -> Result<MeshData, String> {
+        let mut data = wavefront::load_obj(path)?;
+
+        if !data.has_normals() {
+            logger::log_warning(&format!("'{}' is missing vertex normals, generating smooth normals", path), MessageEmitter::World);
+            data.recompute_normals(NormalMode::Smooth);
+        }
+        data.generate_tangents();
+
+        Ok(data)
+    }
+
+    // Meshes without explicit submeshes (e.g. primitives) are drawn as a single part
+    pub fn parts(&self) -> Vec<SubMesh> {
+        if self.submeshes.is_empty() {
+            vec![SubMesh {
+                name: "default".into(),
+                first_index: 0,
+                index_count: self.indices.len(),
+                material: None
+            }]
         } else {
-            logger::log_error(&format!("Unable to read '{}'", path), MessageEmitter::World);
-            MeshData::empty()
+            self.submeshes.clone()
         }
     }
     
     pub fn generate(mesh_type: PrimitiveType) -> MeshData {
-        match mesh_type {
-            PrimitiveType::Plane => {
-                Self::generate_plane()
+        let mut data = match mesh_type {
+            PrimitiveType::Plane { subdivisions } => {
+                Self::generate_plane(subdivisions)
+            },
+            PrimitiveType::Quad { width, height } => {
+                Self::generate_quad(width, height)
             },
             PrimitiveType::Cube => {
                 Self::generate_cube()
             },
             PrimitiveType::Sphere(resolution) => {
                 Self::generate_sphere(resolution)
+            },
+            PrimitiveType::Icosphere { radius, subdivisions } => {
+                Self::generate_icosphere(radius, subdivisions)
+            },
+            PrimitiveType::Cylinder { radius, height, segments } => {
+                Self::generate_cylinder(radius, height, segments.max(3))
+            },
+            PrimitiveType::Cone { radius, height, segments } => {
+                Self::generate_cone(radius, height, segments.max(3))
+            },
+            PrimitiveType::Torus { radius, tube_radius, segments, sides } => {
+                Self::generate_torus(radius, tube_radius, segments.max(3), sides.max(3))
+            },
+            PrimitiveType::Capsule { radius, height, segments, rings } => {
+                Self::generate_capsule(radius, height, segments.max(3), rings.max(1))
+            }
+        };
+        data.generate_tangents();
+
+        data
+    }
+
+    fn push_vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, uv: [f32; 2]) -> u32 {
+        self.vertices.push(Vertex {
+            position: position.into(),
+            color: [1.0; 3],
+            normal: normal.normalize().into(),
+            uv,
+            tangent: [0.0; 4]
+        });
+
+        (self.vertices.len() - 1) as u32
+    }
+
+    fn generate_plane(subdivisions: u32) -> MeshData {
+        let mut data = MeshData::empty();
+        let cells = subdivisions + 1;
+        let row = cells + 1;
+
+        for j in 0..=cells {
+            for i in 0..=cells {
+                let (u, v) = (i as f32 / cells as f32, j as f32 / cells as f32);
+                data.push_vertex([u - 0.5, 0.0, v - 0.5].into(), Vector3::unit_y(), [u, v]);
             }
         }
+
+        for j in 0..cells {
+            for i in 0..cells {
+                let near_left = j * row + i;
+                let near_right = near_left + 1;
+                let far_left = near_left + row;
+                let far_right = far_left + 1;
+
+                data.indices.extend_from_slice(&[
+                    far_left, far_right, near_right,
+                    near_right, near_left, far_left
+                ]);
+            }
+        }
+
+        data
     }
 
-    fn generate_plane() -> MeshData {
+    fn generate_quad(width: f32, height: f32) -> MeshData {
         let mut data = MeshData::empty();
+        let (w, h) = (width / 2.0, height / 2.0);
+        let normal = Vector3::unit_z();
 
-        data.vertices = vec![
-            Vertex { // top left
-                position: [-0.5, 0.0, 0.5],
-                color: [1.0; 3],
-                normal: [0.0, 1.0, 0.0],
-                uv: [0.0, 0.0]
-            },
-            Vertex { // top right
-                position: [0.5, 0.0, 0.5],
-                color: [1.0; 3],
-                normal: [0.0, 1.0, 0.0],
-                uv: [1.0, 0.0]
-            },
-            Vertex { // bottom right
-                position: [0.5, 0.0, -0.5],
-                color: [1.0; 3],
-                normal: [0.0, 1.0, 0.0],
-                uv: [0.0, 1.0]
-            },
-            Vertex { // bottom left
-                position: [-0.5, 0.0, -0.5],
-                color: [1.0; 3],
-                normal: [0.0, 1.0, 0.0],
-                uv: [1.0, 1.0]
-            },
-        ];
+        data.push_vertex([-w, -h, 0.0].into(), normal, [0.0, 0.0]); // bottom left
+        data.push_vertex([w, -h, 0.0].into(), normal, [1.0, 0.0]); // bottom right
+        data.push_vertex([w, h, 0.0].into(), normal, [1.0, 1.0]); // top right
+        data.push_vertex([-w, h, 0.0].into(), normal, [0.0, 1.0]); // top left
 
         data.indices = vec![
-            0, 1, 2, 
+            0, 1, 2,
             2, 3, 0
         ];
 
@@ -120,40 +210,40 @@ impl MeshData {
 
         data.vertices = vec![
             // Top
-            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [0.0, 0.0] }, // Back-Left
-            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [1.0, 0.0] }, // Back-Right
-            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [1.0, 1.0] }, // Front-Right
-            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [0.0, 1.0] }, // Front-Left
+            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [0.0, 0.0], tangent: [0.0; 4] }, // Back-Left
+            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [1.0, 0.0], tangent: [0.0; 4] }, // Back-Right
+            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [1.0, 1.0], tangent: [0.0; 4] }, // Front-Right
+            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [0.0, 0.0, 1.0],  uv: [0.0, 1.0], tangent: [0.0; 4] }, // Front-Left
             // Bottom
-            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [1.0, 1.0] }, // Back-Left
-            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [0.0, 1.0] }, // Back-Right
-            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [0.0, 0.0] }, // Front-Right
-            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [1.0, 0.0] }, // Front-Left
+            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [1.0, 1.0], tangent: [0.0; 4] }, // Back-Left
+            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [0.0, 1.0], tangent: [0.0; 4] }, // Back-Right
+            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [0.0, 0.0], tangent: [0.0; 4] }, // Front-Right
+            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [0.0, 0.0, -1.0], uv: [1.0, 0.0], tangent: [0.0; 4] }, // Front-Left
             // Front
-            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [1.0, 1.0] }, // Top-Left
-            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [0.0, 1.0] }, // Top-Right
-            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [0.0, 0.0] }, // Bottom-Right
-            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [1.0, 0.0] }, // Bottom-Left
+            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [1.0, 1.0], tangent: [0.0; 4] }, // Top-Left
+            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [0.0, 1.0], tangent: [0.0; 4] }, // Top-Right
+            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [0.0, 0.0], tangent: [0.0; 4] }, // Bottom-Right
+            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [0.0, 1.0, 0.0], uv: [1.0, 0.0], tangent: [0.0; 4] }, // Bottom-Left
             // Back
-            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [0.0, 1.0] }, // Top-Left     
-            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [1.0, 1.0] }, // Top-Right    
-            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [1.0, 0.0] }, // Bottom-Right 
-            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [0.0, 0.0] }, // Bottom-Left  
+            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [0.0, 1.0], tangent: [0.0; 4] }, // Top-Left     
+            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [1.0, 1.0], tangent: [0.0; 4] }, // Top-Right    
+            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [1.0, 0.0], tangent: [0.0; 4] }, // Bottom-Right 
+            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [0.0, -1.0, 0.0],  uv: [0.0, 0.0], tangent: [0.0; 4] }, // Bottom-Left  
             // Left
-            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [0.0, 1.0] }, // Top-Front     
-            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [1.0, 1.0] }, // Top-Back    
-            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [1.0, 0.0] }, // Bottom-Back  
-            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [0.0, 0.0] }, // Bottom-Front 
+            Vertex { position: [-1.0, 1.0, 1.0],   color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [0.0, 1.0], tangent: [0.0; 4] }, // Top-Front     
+            Vertex { position: [-1.0, -1.0, 1.0],  color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [1.0, 1.0], tangent: [0.0; 4] }, // Top-Back    
+            Vertex { position: [-1.0, -1.0, -1.0], color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [1.0, 0.0], tangent: [0.0; 4] }, // Bottom-Back  
+            Vertex { position: [-1.0, 1.0, -1.0],  color: [1.0; 3], normal: [-1.0, 0.0, 0.0], uv: [0.0, 0.0], tangent: [0.0; 4] }, // Bottom-Front 
             // Right
-            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [1.0, 1.0] }, // Top-Front     
-            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [0.0, 1.0] }, // Top-Back    
-            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [0.0, 0.0] }, // Bottom-Back  
-            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [1.0, 0.0] }, // Bottom-Front 
+            Vertex { position: [1.0, 1.0, 1.0],    color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [1.0, 1.0], tangent: [0.0; 4] }, // Top-Front     
+            Vertex { position: [1.0, -1.0, 1.0],   color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [0.0, 1.0], tangent: [0.0; 4] }, // Top-Back    
+            Vertex { position: [1.0, -1.0, -1.0],  color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [0.0, 0.0], tangent: [0.0; 4] }, // Bottom-Back  
+            Vertex { position: [1.0, 1.0, -1.0],   color: [1.0; 3], normal: [1.0, 0.0, 0.0], uv: [1.0, 0.0], tangent: [0.0; 4] }, // Bottom-Front 
         ];
 
         data.indices = vec![
             0,  1,  2,  2,  3,  0,
-            4,  5,  6,  6,  7,  4,
+            4,  7,  6,  6,  5,  4,
             8,  9,  10, 10, 11, 8,
             12, 15, 14, 14, 13, 12,
             16, 19, 18, 18, 17, 16,
@@ -168,7 +258,7 @@ impl MeshData {
 
         let t = (1.0 + (5.0f32).sqrt()) / 2.0;
         let mut v: Vec<Vector3<f32>> = Vec::new();
-        let mut i: Vec<u16> = Vec::new();
+        let mut i: Vec<u32> = Vec::new();
 
         // Initial vertices
         v.append(&mut vec![
@@ -214,7 +304,7 @@ impl MeshData {
 
         let mut last_index = 11;
         for _ in 0..resolution {
-        let mut new_indices: Vec<u16> = Vec::new();
+        let mut new_indices: Vec<u32> = Vec::new();
             for face in i.clone().chunks(3) {
                 let mut new_points: Vec<Vector3<f32>> = vec![
                     (v[face[0] as usize] + v[face[1] as usize]) / 2.0,
@@ -243,12 +333,248 @@ impl MeshData {
                     v.z.atan2(v.x) / std::f32::consts::TAU,
                     (v.y.asin() / std::f32::consts::PI) + 0.5,
                 ], // https://www.alexisgiard.com/icosahedron-sphere/
-                normal: v.normalize().into() // smooth shading
+                normal: v.normalize().into(), // smooth shading
+                tangent: [0.0; 4]
             }
         }).collect();
         data.indices = i;
         
         data
     }
-}
 
+    fn generate_icosphere(radius: f32, subdivisions: u8) -> MeshData {
+        let sphere = Self::generate_sphere(subdivisions);
+        let mut data = MeshData::empty();
+
+        // Regenerate the UVs in the [0, 1] range. Triangles crossing the seam are unwrapped past u = 1 and cut there,
+        // the part beyond the cut starting over from u = 0, so neither half stretches across the whole texture.
+        let uv = |p: Vector3<f32>| [0.5 + p.z.atan2(p.x) / TAU, 0.5 + p.y.asin() / PI];
+
+        for v in &sphere.vertices {
+            let p = Vector3::from(v.position);
+            data.push_vertex(p * radius, p, uv(p));
+        }
+
+        // Vertices added along the seam, keyed on the edge they lie on (a corner being an edge to itself) and their U,
+        // so neighbouring triangles share them
+        let mut seam_vertices: HashMap<(u32, u32, u32), u32> = HashMap::new();
+
+        for face in sphere.indices.chunks(3) {
+            let max_u = face.iter().map(|&i| data.vertices[i as usize].uv[0]).fold(f32::MIN, f32::max);
+            let us: Vec<f32> = face.iter().map(|&i| data.vertices[i as usize].uv[0])
+                .map(|u| if u < max_u - 0.5 { u + 1.0 } else { u })
+                .collect();
+
+            // The polygons on either side of the cut, as points `t` along an edge with the U they get there
+            let mut near = Vec::new();
+            let mut far = Vec::new();
+
+            for k in 0..3 {
+                let (a, b, ua, ub) = (face[k], face[(k + 1) % 3], us[k], us[(k + 1) % 3]);
+
+                if ua <= 1.0 {
+                    near.push((a, a, 0.0, ua));
+                }
+                if ua >= 1.0 {
+                    far.push((a, a, 0.0, ua - 1.0));
+                }
+                if (ua < 1.0 && ub > 1.0) || (ua > 1.0 && ub < 1.0) {
+                    let (from, to, u_from, u_to) = if a < b { (a, b, ua, ub) } else { (b, a, ub, ua) };
+                    let t = (1.0 - u_from) / (u_to - u_from);
+
+                    near.push((from, to, t, 1.0));
+                    far.push((from, to, t, 0.0));
+                }
+            }
+
+            for polygon in [near, far].iter().filter(|p| p.len() >= 3) {
+                let indices: Vec<u32> = polygon.iter().map(|&(from, to, t, u)| {
+                    let (a, b) = (data.vertices[from as usize], data.vertices[to as usize]);
+
+                    if from == to && a.uv[0] == u {
+                        return from;
+                    }
+
+                    // Points on the seam plane stay on it when pushed out onto the sphere
+                    *seam_vertices.entry((from, to, u.to_bits())).or_insert_with(|| {
+                        let p = (Vector3::from(a.position) + (Vector3::from(b.position) - Vector3::from(a.position)) * t).normalize();
+
+                        data.push_vertex(p * radius, p, [u, uv(p)[1]])
+                    })
+                }).collect();
+
+                // The halves of a cut triangle are convex, so a fan covers them
+                for i in 1..indices.len() - 1 {
+                    data.indices.extend_from_slice(&[indices[0], indices[i], indices[i + 1]]);
+                }
+            }
+        }
+
+        data
+    }
+
+    fn generate_cylinder(radius: f32, height: f32, segments: u32) -> MeshData {
+        let mut data = MeshData::empty();
+        let half = height / 2.0;
+
+        // Side
+        for i in 0..=segments {
+            let u = i as f32 / segments as f32;
+            let (sin, cos) = (u * TAU).sin_cos();
+            let normal = Vector3::new(cos, 0.0, sin);
+
+            data.push_vertex(Vector3::new(cos * radius, -half, sin * radius), normal, [u, 0.0]);
+            data.push_vertex(Vector3::new(cos * radius, half, sin * radius), normal, [u, 1.0]);
+        }
+
+        for i in 0..segments {
+            let (bottom, top) = (i * 2, i * 2 + 1);
+            let (next_bottom, next_top) = (bottom + 2, top + 2);
+
+            data.indices.extend_from_slice(&[
+                bottom, top, next_bottom,
+                next_bottom, top, next_top
+            ]);
+        }
+
+        // Caps
+        data.push_cap(radius, half, segments, Vector3::unit_y());
+        data.push_cap(radius, -half, segments, -Vector3::unit_y());
+
+        data
+    }
+
+    fn generate_cone(radius: f32, height: f32, segments: u32) -> MeshData {
+        let mut data = MeshData::empty();
+        let half = height / 2.0;
+
+        // Side: each segment gets its own apex vertex so the normals don't collapse into a single point
+        let side_normal = |u: f32| {
+            let (sin, cos) = (u * TAU).sin_cos();
+            Vector3::new(cos * height, radius, sin * height)
+        };
+
+        for i in 0..=segments {
+            let u = i as f32 / segments as f32;
+            let (sin, cos) = (u * TAU).sin_cos();
+
+            data.push_vertex(Vector3::new(cos * radius, -half, sin * radius), side_normal(u), [u, 0.0]);
+        }
+
+        for i in 0..segments {
+            let u = (i as f32 + 0.5) / segments as f32;
+
+            data.push_vertex(Vector3::new(0.0, half, 0.0), side_normal(u), [u, 1.0]);
+        }
+
+        for i in 0..segments {
+            let apex = segments + 1 + i;
+
+            data.indices.extend_from_slice(&[i, apex, i + 1]);
+        }
+
+        data.push_cap(radius, -half, segments, -Vector3::unit_y());
+
+        data
+    }
+
+    fn generate_torus(radius: f32, tube_radius: f32, segments: u32, sides: u32) -> MeshData {
+        let mut data = MeshData::empty();
+
+        for i in 0..=segments {
+            let u = i as f32 / segments as f32;
+            let (sin_theta, cos_theta) = (u * TAU).sin_cos();
+            let center = Vector3::new(cos_theta * radius, 0.0, sin_theta * radius);
+
+            for j in 0..=sides {
+                let v = j as f32 / sides as f32;
+                let (sin_phi, cos_phi) = (v * TAU).sin_cos();
+                let normal = Vector3::new(cos_phi * cos_theta, sin_phi, cos_phi * sin_theta);
+
+                data.push_vertex(center + normal * tube_radius, normal, [u, v]);
+            }
+        }
+
+        let row = sides + 1;
+        for i in 0..segments {
+            for j in 0..sides {
+                let a = i * row + j;
+                let b = a + row;
+
+                data.indices.extend_from_slice(&[
+                    a, a + 1, b,
+                    b, a + 1, b + 1
+                ]);
+            }
+        }
+
+        data
+    }
+
+    fn generate_capsule(radius: f32, height: f32, segments: u32, rings: u32) -> MeshData {
+        let mut data = MeshData::empty();
+        let height = height.max(radius * 2.0);
+        let half_cylinder = height / 2.0 - radius;
+
+        // Latitude rows from the top pole to the bottom pole; the two equator rows form the cylinder wall
+        let mut rows: Vec<(f32, f32)> = Vec::new();
+        for k in 0..=rings {
+            rows.push((FRAC_PI_2 - FRAC_PI_2 * k as f32 / rings as f32, half_cylinder));
+        }
+        for k in 0..=rings {
+            rows.push((-FRAC_PI_2 * k as f32 / rings as f32, -half_cylinder));
+        }
+
+        for &(latitude, offset) in &rows {
+            let (sin_lat, cos_lat) = latitude.sin_cos();
+
+            for i in 0..=segments {
+                let u = i as f32 / segments as f32;
+                let (sin, cos) = (u * TAU).sin_cos();
+                let normal = Vector3::new(cos_lat * cos, sin_lat, cos_lat * sin);
+                let position = normal * radius + Vector3::new(0.0, offset, 0.0);
+
+                data.push_vertex(position, normal, [u, position.y / height + 0.5]);
+            }
+        }
+
+        let row = segments + 1;
+        let last_row = rows.len() - 1;
+        for r in 0..last_row {
+            for i in 0..segments {
+                let top = r as u32 * row + i;
+                let bottom = top + row;
+
+                // Skip the degenerate triangles touching the poles
+                if r + 1 != last_row {
+                    data.indices.extend_from_slice(&[bottom, top, bottom + 1]);
+                }
+                if r != 0 {
+                    data.indices.extend_from_slice(&[bottom + 1, top, top + 1]);
+                }
+            }
+        }
+
+        data
+    }
+
+    // Flat disc at height `y`, facing along `normal` (either +Y or -Y)
+    fn push_cap(&mut self, radius: f32, y: f32, segments: u32, normal: Vector3<f32>) {
+        let center = self.push_vertex(Vector3::new(0.0, y, 0.0), normal, [0.5, 0.5]);
+
+        for i in 0..=segments {
+            let (sin, cos) = (i as f32 / segments as f32 * TAU).sin_cos();
+            self.push_vertex(Vector3::new(cos * radius, y, sin * radius), normal, [0.5 + cos / 2.0, 0.5 + sin / 2.0]);
+        }
+
+        for i in 0..segments {
+            let (current, next) = (center + 1 + i, center + 2 + i);
+
+            if normal.y > 0.0 {
+                self.indices.extend_from_slice(&[center, next, current]);
+            } else {
+                self.indices.extend_from_slice(&[center, current, next]);
+            }
+        }
+    }
+}
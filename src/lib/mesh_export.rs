@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Component, Path, PathBuf };
+use cgmath::{ InnerSpace, Vector3 };
+
+use crate::mesh_data::{ MeshData, MeshMaterial };
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlyEncoding {
+    Ascii,
+    BinaryLittleEndian
+}
+
+impl MeshData {
+    // Writes a Wavefront object, plus a material library next to it if any submesh has a material
+    pub fn export_obj(&self, path: &str) -> Result<(), String> {
+        let path = Path::new(path);
+        let mut materials: Vec<_> = self.submeshes.iter().filter_map(|s| s.material.clone()).collect();
+
+        // A material stays in use until the next usemtl, so parts without one name a plain white material instead of
+        // inheriting the one before them
+        let mut default_material = MeshMaterial::named("default");
+        while materials.iter().any(|m| m.name == default_material.name) {
+            default_material.name.push('_');
+        }
+        if !materials.is_empty() && self.submeshes.iter().any(|s| s.material.is_none()) {
+            materials.push(default_material.clone());
+        }
+
+        let library = if materials.is_empty() {
+            None
+        } else {
+            let library = path.with_extension("mtl");
+            let library_directory = absolute(library.parent().unwrap_or_else(|| Path::new("")));
+
+            write_file(&library, |w| {
+                let mut written = HashSet::new();
+
+                for material in materials.iter().filter(|m| written.insert(&m.name)) {
+                    writeln!(w, "newmtl {}", material.name)?;
+                    writeln!(w, "Kd {} {} {}", material.diffuse_color[0], material.diffuse_color[1], material.diffuse_color[2])?;
+
+                    // Loaders look for textures next to the library, while loaded paths include the directory of the model
+                    if let Some(texture) = &material.diffuse_texture {
+                        writeln!(w, "map_Kd {}", relative_to(&absolute(Path::new(texture)), &library_directory).display())?;
+                    }
+
+                    writeln!(w)?;
+                }
+
+                Ok(())
+            })?;
+
+            library.file_name().map(|f| f.to_string_lossy().into_owned())
+        };
+
+        write_file(path, |w| {
+            if let Some(library) = &library {
+                writeln!(w, "mtllib {}", library)?;
+            }
+
+            for v in &self.vertices {
+                writeln!(w, "v {} {} {}", v.position[0], v.position[1], v.position[2])?;
+            }
+            for v in &self.vertices {
+                writeln!(w, "vt {} {}", v.uv[0], v.uv[1])?;
+            }
+            for v in &self.vertices {
+                writeln!(w, "vn {} {} {}", v.normal[0], v.normal[1], v.normal[2])?;
+            }
+
+            for part in self.parts() {
+                writeln!(w, "o {}", part.name)?;
+
+                match &part.material {
+                    Some(material) => writeln!(w, "usemtl {}", material.name)?,
+                    None if library.is_some() => writeln!(w, "usemtl {}", default_material.name)?,
+                    None => { }
+                }
+
+                // Positions, UVs and normals are written in the same order, so every attribute shares an index
+                for face in self.indices[part.first_index..part.first_index + part.index_count].chunks_exact(3) {
+                    let (a, b, c) = (face[0] + 1, face[1] + 1, face[2] + 1);
+                    writeln!(w, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, b, c)?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    // Stanford polygon file with positions, normals, UVs and 8-bit vertex colors
+    pub fn export_ply(&self, path: &str, encoding: PlyEncoding) -> Result<(), String> {
+        let format = match encoding {
+            PlyEncoding::Ascii => "ascii",
+            PlyEncoding::BinaryLittleEndian => "binary_little_endian"
+        };
+
+        write_file(Path::new(path), |w| {
+            writeln!(w, "ply")?;
+            writeln!(w, "format {} 1.0", format)?;
+            writeln!(w, "element vertex {}", self.vertices.len())?;
+            for property in &["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+                writeln!(w, "property float {}", property)?;
+            }
+            for property in &["red", "green", "blue"] {
+                writeln!(w, "property uchar {}", property)?;
+            }
+            writeln!(w, "element face {}", self.indices.len() / 3)?;
+            writeln!(w, "property list uchar uint vertex_indices")?;
+            writeln!(w, "end_header")?;
+
+            let color = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+            for v in &self.vertices {
+                let floats = [v.position[0], v.position[1], v.position[2], v.normal[0], v.normal[1], v.normal[2], v.uv[0], v.uv[1]];
+                let colors = [color(v.color[0]), color(v.color[1]), color(v.color[2])];
+
+                match encoding {
+                    PlyEncoding::Ascii => {
+                        let line: Vec<String> = floats.iter().map(|f| f.to_string())
+                            .chain(colors.iter().map(|c| c.to_string()))
+                            .collect();
+                        writeln!(w, "{}", line.join(" "))?;
+                    },
+                    PlyEncoding::BinaryLittleEndian => {
+                        for f in &floats {
+                            w.write_all(&f.to_le_bytes())?;
+                        }
+                        w.write_all(&colors)?;
+                    }
+                }
+            }
+
+            for face in self.indices.chunks_exact(3) {
+                match encoding {
+                    PlyEncoding::Ascii => writeln!(w, "3 {} {} {}", face[0], face[1], face[2])?,
+                    PlyEncoding::BinaryLittleEndian => {
+                        w.write_all(&[3])?;
+                        for i in face {
+                            w.write_all(&i.to_le_bytes())?;
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    // Binary stereolithography file; STL only stores triangles and their facet normals
+    pub fn export_stl(&self, path: &str) -> Result<(), String> {
+        write_file(Path::new(path), |w| {
+            let mut header = [0u8; 80];
+            let title = b"hephaestus mesh export";
+            header[..title.len()].copy_from_slice(title);
+
+            w.write_all(&header)?;
+            w.write_all(&((self.indices.len() / 3) as u32).to_le_bytes())?;
+
+            for face in self.indices.chunks_exact(3) {
+                let p: Vec<Vector3<f32>> = face.iter().map(|&i| Vector3::from(self.vertices[i as usize].position)).collect();
+                let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+                let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { normal };
+
+                for v in std::iter::once(&normal).chain(p.iter()) {
+                    w.write_all(&v.x.to_le_bytes())?;
+                    w.write_all(&v.y.to_le_bytes())?;
+                    w.write_all(&v.z.to_le_bytes())?;
+                }
+
+                // Attribute byte count, unused
+                w.write_all(&[0, 0])?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// Absolute path with `.` and `..` resolved, without touching the file system
+fn absolute(path: &Path) -> PathBuf {
+    let path = std::env::current_dir().map(|d| d.join(path)).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => { },
+            Component::ParentDir => { normalized.pop(); },
+            component => normalized.push(component)
+        }
+    }
+
+    normalized
+}
+
+// `target` as seen from the `base` directory, both from `absolute`
+fn relative_to(target: &Path, base: &Path) -> PathBuf {
+    let target: Vec<_> = target.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let shared = target.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    // Paths on different drives have nothing in common to be relative to
+    if shared == 0 {
+        return target.iter().collect();
+    }
+
+    base[shared..].iter().map(|_| Component::ParentDir)
+        .chain(target[shared..].iter().copied())
+        .collect()
+}
+
+fn write_file(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("Unable to create '{}': {}", path.display(), e))?;
+    let mut writer = BufWriter::new(file);
+
+    write(&mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|e| format!("Unable to write '{}': {}", path.display(), e))
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use cgmath::{ InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector2, Vector3, Zero };
+
+use crate::{
+    buffer_objects::Vertex,
+    mesh_data::{ MeshData, SubMesh }
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormalMode {
+    // Vertices sharing a position average the (area-weighted) normals of every face touching them
+    Smooth,
+    // Every triangle gets its own vertices, all using the face normal
+    Flat
+}
+
+impl MeshData {
+    pub fn has_normals(&self) -> bool {
+        self.vertices.iter().all(|v| Vector3::from(v.normal).magnitude2() > 0.0)
+    }
+
+    fn triangle_positions(&self, face: &[u32]) -> [Vector3<f32>; 3] {
+        [
+            Vector3::from(self.vertices[face[0] as usize].position),
+            Vector3::from(self.vertices[face[1] as usize].position),
+            Vector3::from(self.vertices[face[2] as usize].position)
+        ]
+    }
+
+    pub fn recompute_normals(&mut self, mode: NormalMode) {
+        match mode {
+            NormalMode::Smooth => {
+                // Accumulate per position rather than per vertex, so UV seams don't show up as shading seams
+                let mut accumulated: HashMap<[u32; 3], Vector3<f32>> = HashMap::new();
+                let key = |v: &Vertex| [v.position[0].to_bits(), v.position[1].to_bits(), v.position[2].to_bits()];
+
+                for face in self.indices.chunks_exact(3) {
+                    let [a, b, c] = self.triangle_positions(face);
+                    // The cross product's length is twice the triangle's area, which weights the average
+                    let face_normal = (b - a).cross(c - a);
+
+                    for &i in face {
+                        *accumulated.entry(key(&self.vertices[i as usize])).or_insert_with(Vector3::zero) += face_normal;
+                    }
+                }
+
+                for vertex in self.vertices.iter_mut() {
+                    let normal = accumulated.get(&key(vertex)).cloned().unwrap_or_else(Vector3::zero);
+
+                    if normal.magnitude2() > 0.0 {
+                        vertex.normal = normal.normalize().into();
+                    }
+                }
+            },
+            NormalMode::Flat => {
+                let mut vertices = Vec::with_capacity(self.indices.len());
+
+                for face in self.indices.chunks_exact(3) {
+                    let [a, b, c] = self.triangle_positions(face);
+                    let face_normal = (b - a).cross(c - a);
+                    let normal = if face_normal.magnitude2() > 0.0 { face_normal.normalize().into() } else { [0.0, 1.0, 0.0] };
+
+                    for &i in face {
+                        vertices.push(Vertex { normal, ..self.vertices[i as usize] });
+                    }
+                }
+
+                // Triangles keep their position in the index buffer, so submesh ranges stay valid
+                self.indices = (0..vertices.len() as u32).collect();
+                self.vertices = vertices;
+            }
+        }
+    }
+
+    // Per-vertex tangents from the UV layout, in the spirit of MikkTSpace: tangents and bitangents are
+    // accumulated per face, orthogonalized against the normal, and the bitangent's handedness is kept in `w`.
+    // Unlike full MikkTSpace, faces aren't split by angle, so results can differ slightly on hard edges.
+    pub fn generate_tangents(&mut self) {
+        let mut tangents = vec![Vector3::zero(); self.vertices.len()];
+        let mut bitangents = vec![Vector3::zero(); self.vertices.len()];
+
+        for face in self.indices.chunks_exact(3) {
+            let [p0, p1, p2] = self.triangle_positions(face);
+            let uv = |i: u32| Vector2::from(self.vertices[i as usize].uv);
+            let (uv0, uv1, uv2) = (uv(face[0]), uv(face[1]), uv(face[2]));
+
+            let (edge1, edge2) = (p1 - p0, p2 - p0);
+            let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+
+            let determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+            if determinant.abs() < f32::EPSILON {
+                continue;
+            }
+
+            let r = 1.0 / determinant;
+            let tangent = (edge1 * duv2.y - edge2 * duv1.y) * r;
+            let bitangent = (edge2 * duv1.x - edge1 * duv2.x) * r;
+
+            for &i in face {
+                tangents[i as usize] += tangent;
+                bitangents[i as usize] += bitangent;
+            }
+        }
+
+        for (i, vertex) in self.vertices.iter_mut().enumerate() {
+            let normal = Vector3::from(vertex.normal);
+            let tangent = tangents[i] - normal * normal.dot(tangents[i]);
+
+            vertex.tangent = if tangent.magnitude2() > 0.0 {
+                let tangent = tangent.normalize();
+                let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+
+                [tangent.x, tangent.y, tangent.z, handedness]
+            } else {
+                // No usable UVs: any vector perpendicular to the normal keeps the shader's TBN valid
+                let helper = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+                let tangent = normal.cross(helper).normalize();
+
+                if tangent.x.is_finite() {
+                    [tangent.x, tangent.y, tangent.z, 1.0]
+                } else {
+                    [1.0, 0.0, 0.0, 1.0]
+                }
+            };
+        }
+    }
+
+    // Merges vertices whose position, normal, UV and color all lie within `epsilon` of each other, and returns the
+    // number of removed vertices. Tangents are derived data and not compared, so regenerate them afterwards.
+    pub fn weld_vertices(&mut self, epsilon: f32) -> usize {
+        let epsilon = epsilon.max(f32::EPSILON);
+        let cell = |v: &Vertex| {
+            [
+                (v.position[0] / epsilon).floor() as i64,
+                (v.position[1] / epsilon).floor() as i64,
+                (v.position[2] / epsilon).floor() as i64
+            ]
+        };
+
+        let close = |a: &Vertex, b: &Vertex| {
+            let near = |x: &[f32], y: &[f32]| x.iter().zip(y).all(|(x, y)| (x - y).abs() <= epsilon);
+
+            near(&a.position, &b.position) &&
+                near(&a.normal, &b.normal) &&
+                near(&a.uv, &b.uv) &&
+                near(&a.color, &b.color)
+        };
+
+        let mut grid: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+        let mut welded: Vec<Vertex> = Vec::with_capacity(self.vertices.len());
+        let mut remap = Vec::with_capacity(self.vertices.len());
+
+        for vertex in &self.vertices {
+            let [x, y, z] = cell(vertex);
+
+            // Vertices within epsilon can land in a neighbouring cell, so all 27 surrounding cells are checked
+            let existing = (-1..=1).flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [x + dx, y + dy, z + dz])))
+                .filter_map(|key| grid.get(&key))
+                .flatten()
+                .find(|&&i| close(&welded[i as usize], vertex))
+                .cloned();
+
+            let index = match existing {
+                Some(index) => index,
+                None => {
+                    welded.push(*vertex);
+                    let index = (welded.len() - 1) as u32;
+                    grid.entry([x, y, z]).or_default().push(index);
+
+                    index
+                }
+            };
+
+            remap.push(index);
+        }
+
+        let removed = self.vertices.len() - welded.len();
+
+        self.indices.iter_mut().for_each(|i| *i = remap[*i as usize]);
+        self.vertices = welded;
+
+        removed
+    }
+
+    // Reverses the front face of every triangle; normals are left untouched
+    pub fn flip_winding(&mut self) {
+        for face in self.indices.chunks_exact_mut(3) {
+            face.swap(1, 2);
+        }
+    }
+
+    // Bakes `matrix` into the vertices. Normals use the inverse transpose so non-uniform scales keep them
+    // perpendicular to the surface, and mirroring transforms flip the winding to keep faces pointing outwards.
+    pub fn transform(&mut self, matrix: Matrix4<f32>) {
+        let linear = Matrix3::from_cols(matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate());
+        let normal_matrix = linear.invert().map(|m| m.transpose()).unwrap_or(linear);
+
+        for vertex in self.vertices.iter_mut() {
+            let position = matrix * Vector3::from(vertex.position).extend(1.0);
+            let normal = normal_matrix * Vector3::from(vertex.normal);
+            let tangent = linear * Vector3::new(vertex.tangent[0], vertex.tangent[1], vertex.tangent[2]);
+
+            vertex.position = position.truncate().into();
+
+            if normal.magnitude2() > 0.0 {
+                vertex.normal = normal.normalize().into();
+            }
+
+            if tangent.magnitude2() > 0.0 {
+                let tangent = tangent.normalize();
+                vertex.tangent = [tangent.x, tangent.y, tangent.z, vertex.tangent[3]];
+            }
+        }
+
+        if linear.determinant() < 0.0 {
+            self.flip_winding();
+        }
+    }
+
+    // Appends `other`'s geometry, keeping its submeshes (or the whole of it, if it has none) as separate ranges
+    pub fn append(&mut self, other: &MeshData) {
+        // The existing geometry needs a range of its own before anything is added after it
+        if self.submeshes.is_empty() && !self.indices.is_empty() {
+            self.submeshes = self.parts();
+        }
+
+        let vertex_offset = self.vertices.len() as u32;
+        let index_offset = self.indices.len();
+
+        self.vertices.extend_from_slice(&other.vertices);
+        self.indices.extend(other.indices.iter().map(|i| i + vertex_offset));
+        self.submeshes.extend(other.parts().into_iter().map(|part| SubMesh {
+            first_index: part.first_index + index_offset,
+            ..part
+        }));
+    }
+
+    // Regroups the triangles so that all submeshes sampling the same diffuse texture form one contiguous range.
+    // Diffuse colors are already baked into the vertex colors, so the texture is all that tells submeshes apart.
+    pub fn merge_submeshes(&mut self) {
+        if self.submeshes.len() < 2 {
+            return;
+        }
+
+        let mut groups: Vec<(SubMesh, Vec<u32>)> = Vec::new();
+        let mut lookup: HashMap<Option<String>, usize> = HashMap::new();
+
+        for part in self.parts() {
+            let texture = part.material.as_ref().and_then(|m| m.diffuse_texture.clone());
+            let indices = &self.indices[part.first_index..part.first_index + part.index_count];
+
+            match lookup.get(&texture) {
+                Some(&group) => groups[group].1.extend_from_slice(indices),
+                None => {
+                    lookup.insert(texture, groups.len());
+                    groups.push((part.clone(), indices.to_vec()));
+                }
+            }
+        }
+
+        self.indices.clear();
+        self.submeshes.clear();
+
+        for (part, indices) in groups {
+            self.submeshes.push(SubMesh {
+                first_index: self.indices.len(),
+                index_count: indices.len(),
+                ..part
+            });
+            self.indices.extend(indices);
+        }
+    }
+}
@@ -1,20 +1,27 @@
 use std::sync::Arc;
-use vulkano::buffer::{ BufferUsage, CpuAccessibleBuffer, CpuBufferPool, cpu_pool::CpuBufferPoolSubbuffer };
-use vulkano::command_buffer::{ AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, PrimaryAutoCommandBuffer, SubpassContents };
+use std::collections::HashMap;
+use std::time::Instant;
+use cgmath::{ InnerSpace, Matrix4, SquareMatrix, Vector3 };
+use vulkano::buffer::{ BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, ImmutableBuffer, cpu_pool::CpuBufferPoolSubbuffer };
+use vulkano::command_buffer::{ AutoCommandBufferBuilder, CommandBufferUsage, DynamicState, PrimaryAutoCommandBuffer, PrimaryCommandBuffer, SubpassContents };
 use vulkano::descriptor_set::{ DescriptorSet, PersistentDescriptorSet };
 use vulkano::device::{ Device, Queue, DeviceExtensions };
 use vulkano::device::physical::{ PhysicalDevice, PhysicalDeviceType };
 use vulkano::format::Format;
-use vulkano::image::{ ImageAccess, ImageUsage };
+use vulkano::image::{ ImageAccess, ImageUsage, ImmutableImage };
 use vulkano::image::attachment::AttachmentImage;
 use vulkano::image::swapchain::SwapchainImage;
 use vulkano::image::view::ImageView;
-use vulkano::instance::Instance;
+use vulkano::instance::{ Instance, InstanceExtensions };
 use vulkano::memory::pool::StdMemoryPool;
 use vulkano::pipeline::blend::{ AttachmentBlend, BlendFactor, BlendOp };
-use vulkano::pipeline::{ GraphicsPipeline, GraphicsPipelineAbstract };
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::{ ComputePipeline, GraphicsPipeline, GraphicsPipelineAbstract };
+use vulkano::pipeline::vertex::OneVertexOneInstanceDefinition;
+use vulkano::pipeline::shader::GraphicsEntryPoint;
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::render_pass::{ Framebuffer, FramebufferAbstract, RenderPass, Subpass };
+use vulkano::sampler::{ Filter, MipmapMode, Sampler, SamplerAddressMode };
 use vulkano::swapchain::{ FullscreenExclusive, PresentMode, Surface, SurfaceTransform, Swapchain, SwapchainAcquireFuture, SwapchainCreationError };
 use vulkano::sync::{ FlushError, GpuFuture };
 use vulkano::Version;
@@ -26,47 +33,188 @@ use egui_winit_vulkano::Gui;
 use crate::{
     buffer_objects::*,
     camera::Camera,
-    shaders::{ deferred, directional, ambient },
-    light::DirectionalLight,
+    shaders::{ deferred, deferred_instanced, directional, ambient, point, spot, shadow, tonemap, histogram },
+    light::{ sphere_in_light_space, AmbientLight, DirectionalLight, Light, PlacedLight, PointLight, ShadowSettings, SpotLight, MAX_CASCADES },
+    environment::Irradiance,
+    tonemap::{ AutoExposure, Exposure, Tonemapping, HISTOGRAM_BINS },
+    world::World,
     logger::{ self, MessageEmitter },
+    assets::Handle,
+    mesh_data::MeshData,
     entity::*
 };
 
+// Device-local copies of a mesh's geometry. The handle keeps the mesh id used as key from being reused.
+struct MeshBuffers {
+    mesh: Handle<MeshData>,
+    generation: usize,
+    vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    indices: Arc<ImmutableBuffer<[u32]>>,
+    // Of the mesh data the buffers hold, to cull shadow casters without going over the vertices every frame
+    bounding_radius: f32
+}
+
+// Lighting reconstructs world positions from depth, which 16 bits are too coarse for
+const DEPTH_FORMAT: Format = Format::D32Sfloat;
+
+// Mesh rasterized to shade only the pixels a light can reach
+struct LightVolume {
+    vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    indices: Arc<ImmutableBuffer<[u32]>>
+}
+
+// Depth seen from a shadow casting light, with one square tile per cascade side by side
+struct ShadowMap {
+    image: Arc<AttachmentImage>,
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    resolution: u32,
+    // Light space of each tile and the settings they were drawn with, updated every frame
+    projections: Vec<Matrix4<f32>>,
+    settings: ShadowSettings
+}
+
+// A mesh drawn into the shadow maps, with the transform, bounding sphere center and radius of each entity casting it
+struct ShadowCasters {
+    vertices: Arc<ImmutableBuffer<[Vertex]>>,
+    indices: Arc<ImmutableBuffer<[u32]>>,
+    instances: Vec<(Matrix4<f32>, Vector3<f32>, f32)>
+}
+
+// Textures bound by the deferred pass, in binding order: base color, normal map, metallic-roughness, occlusion and emissive
+const TEXTURE_SLOTS: usize = 5;
+
+// Asset ids of the textures in each slot, each with the index of its sampler in `Renderer::samplers`
+type TextureSetKey = [(Option<usize>, usize); TEXTURE_SLOTS];
+
+// A descriptor set binding a material's textures. The handles keep the ids in its key from being reused,
+// and the generations tell when a texture has been reloaded since the set was built.
+struct TextureSet {
+    textures: Vec<Handle<TextureData>>,
+    generations: [Option<usize>; TEXTURE_SLOTS],
+    set: Arc<dyn DescriptorSet + Send + Sync>
+}
+
+// The camera as set 1 of each lighting pipeline, built once per frame and shared by every light drawn with it
+struct CameraSets {
+    directional: Arc<dyn DescriptorSet + Send + Sync>,
+    point: Arc<dyn DescriptorSet + Send + Sync>,
+    spot: Arc<dyn DescriptorSet + Send + Sync>
+}
+
+// Attachments the deferred subpass writes and the lighting subpass reads, and the HDR image lighting adds up in
+struct GBuffer {
+    color: Arc<AttachmentImage>,
+    normals: Arc<AttachmentImage>,
+    depth: Arc<AttachmentImage>,
+    // Metallic, roughness and ambient occlusion
+    material: Arc<AttachmentImage>,
+    emissive: Arc<AttachmentImage>,
+    // Read by the tonemapping subpass, then by the luminance histogram after the render pass
+    hdr: Arc<AttachmentImage>
+}
+
+const MATERIAL_FORMAT: Format = Format::R8G8B8A8Unorm;
+const EMISSIVE_FORMAT: Format = Format::R16G16B16A16Sfloat;
+// Pixels nothing is drawn to are rough dielectrics without occlusion, so the ambient light still reaches the void
+const MATERIAL_CLEAR: [f32; 4] = [0.0, 1.0, 1.0, 0.0];
+const HDR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+
+// Where frames end up: presented to a window, or drawn into an offscreen image and copied to `pixels` for reading back
+enum Target {
+    Window {
+        surface: Arc<Surface<Window>>,
+        swapchain: Arc<Swapchain<Window>>,
+        final_images: Vec<Arc<ImageView<Arc<SwapchainImage<Window>>>>>
+    },
+    Offscreen {
+        image: Arc<AttachmentImage>,
+        pixels: Arc<CpuAccessibleBuffer<[u8]>>
+    }
+}
+
+impl Target {
+    fn dimensions(&self) -> [u32; 2] {
+        match self {
+            Target::Window { surface, .. } => surface.window().inner_size().into(),
+            Target::Offscreen { image, .. } => ImageAccess::dimensions(&**image).width_height()
+        }
+    }
+}
+
 enum RenderStage {
     Stopped,
+    Shadows,
     Deferred,
     Ambient,
-    Directional,
+    Lighting,
+    Tonemapped,
     NeedsRedraw
 }
 
 #[allow(dead_code)]
 pub struct Renderer {
     instance: Arc<Instance>,
-    pub surface: Arc<Surface<Window>>,
+    target: Target,
     pub device: Arc<Device>,
     pub queue: Arc<Queue>,
-    pub final_images: Vec<Arc<ImageView<Arc<SwapchainImage<Window>>>>>,
     camera: Camera,
-    swapchain: Arc<Swapchain<Window>>,
     vp_buffer: Arc<CpuAccessibleBuffer<VPBufferObject>>,
     model_buffer: CpuBufferPool<ModelBufferObject>,
-    ambient_buffer: Arc<CpuAccessibleBuffer<AmbientBufferObject>>,
+    instance_buffer: CpuBufferPool<InstanceData>,
+    ambient_buffer: CpuBufferPool<AmbientBufferObject>,
     directional_buffer: CpuBufferPool<DirectionalBufferObject>,
+    point_buffer: CpuBufferPool<PointBufferObject>,
+    spot_buffer: CpuBufferPool<SpotBufferObject>,
+    camera_buffer: CpuBufferPool<CameraBufferObject>,
+    // Written once per frame as the lighting passes begin
+    camera_sets: Option<CameraSets>,
     render_pass: Arc<RenderPass>,
     deferred_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    instanced_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     directional_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
     ambient_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    point_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    spot_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    tonemap_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    histogram_pipeline: Arc<ComputePipeline>,
+    // Counted after each frame that uses auto exposure, and read back by the next one
+    histogram: Arc<CpuAccessibleBuffer<[u32]>>,
+    histogram_sampler: Arc<Sampler>,
+    // The range the histogram was counted in, when it was
+    measured_exposure: Option<AutoExposure>,
+    exposure: f32,
+    exposure_time: Instant,
+    // Whether the final image encodes sRGB itself
+    srgb_target: bool,
+    shadow_pass: Arc<RenderPass>,
+    shadow_pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    shadow_sampler: Arc<Sampler>,
+    // Keyed by the light's entity id
+    shadow_maps: HashMap<usize, ShadowMap>,
+    // Bound for lights without shadows, cleared to the far plane so it never shadows anything
+    null_shadow_map: ShadowMap,
+    point_volume: LightVolume,
+    spot_volume: LightVolume,
     dummy_verts: Arc<CpuAccessibleBuffer<[DummyVertex]>>,
     dynamic_state: DynamicState,
     framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
-    color_buffer: Arc<AttachmentImage>,
-    normal_buffer: Arc<AttachmentImage>,
+    g_buffer: GBuffer,
+    // Lighting reconstructs world positions from depth with its inverse
+    view_projection: Matrix4<f32>,
     vp_set: Arc<dyn DescriptorSet + Send + Sync>,
     render_stage: RenderStage,
+    // The frame's render pass begins after the shadow maps are drawn
+    clear_color: [f32; 4],
     commands: Option<AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>>,
     img_index: usize,
     acquire_future: Option<SwapchainAcquireFuture<Window>>,
+    null_texture: Arc<ImageView<Arc<ImmutableImage>>>,
+    null_normal_map: Arc<ImageView<Arc<ImmutableImage>>>,
+    samplers: Vec<(SamplerDesc, Arc<Sampler>)>,
+    texture_sets: HashMap<TextureSetKey, TextureSet>,
+    mesh_buffers: HashMap<usize, MeshBuffers>,
+    // Geometry uploads waiting to be submitted with the next frame
+    uploads: Option<Box<dyn GpuFuture>>,
 }
 
 impl Renderer {
@@ -91,15 +239,7 @@ impl Renderer {
                     })
                     .map(|_| p)
             })
-            .min_by_key(|p| {
-                match p.properties().device_type {
-                    PhysicalDeviceType::DiscreteGpu => 0,
-                    PhysicalDeviceType::IntegratedGpu => 1,
-                    PhysicalDeviceType::VirtualGpu => 2,
-                    PhysicalDeviceType::Cpu => 3,
-                    PhysicalDeviceType::Other => 4,
-                }
-            }).unwrap();
+            .min_by_key(Renderer::device_rank).unwrap();
 
         logger::log_debug(&format!("Using device: {} (type: {:?})", physical.properties().device_name, physical.properties().device_type), MessageEmitter::Renderer);
 
@@ -144,25 +284,77 @@ impl Renderer {
                 .unwrap()
         };
 
+        let final_images = images.iter().map(|image| ImageView::new(image.clone()).unwrap()).collect::<Vec<_>>();
+        let target = Target::Window { surface, swapchain, final_images };
+
+        Renderer::with_target(instance, device, queue, camera, target, &images)
+    }
+
+    // Renders into an offscreen image that is read back after every frame, e.g. for automated tests on machines without
+    // a display or GPU (with a software driver such as lavapipe). Fails when there is no Vulkan device to render with.
+    pub fn headless(width: u32, height: u32) -> Result<Self, String> {
+        let instance = Instance::new(None, Version::V1_2, &InstanceExtensions::none(), None)
+            .map_err(|e| format!("Unable to create a Vulkan instance: {}", e))?;
+
+        let physical = PhysicalDevice::enumerate(&instance)
+            .filter(|p| p.queue_families().any(|q| q.supports_graphics()))
+            .min_by_key(Renderer::device_rank)
+            .ok_or_else(|| "No Vulkan device supports graphics".to_string())?;
+
+        logger::log_debug(&format!("Using device: {} (type: {:?}) without a window", physical.properties().device_name, physical.properties().device_type), MessageEmitter::Renderer);
+
+        let queue_family = physical.queue_families().find(|q| q.supports_graphics()).unwrap();
+
+        let (device, mut queues) = Device::new(
+            physical,
+            physical.supported_features(),
+            &DeviceExtensions::none(),
+            [(queue_family, 0.5)].iter().cloned()
+        ).map_err(|e| format!("Unable to create a Vulkan device: {}", e))?;
+
+        let queue = queues.next().unwrap();
+
+        let usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        };
+        let image = AttachmentImage::with_usage(device.clone(), [width, height], Format::R8G8B8A8Srgb, usage)
+            .map_err(|e| format!("Unable to create a {}x{} offscreen image: {}", width, height, e))?;
+
+        let pixels = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..width * height * 4).map(|_| 0u8)
+        ).map_err(|e| format!("Unable to create the readback buffer: {:?}", e))?;
+
+        Ok(Renderer::with_target(instance, device, queue, Camera::default(), Target::Offscreen { image: image.clone(), pixels }, &[image]))
+    }
+
+    // Sets up everything windowed and offscreen rendering share. `images` are the images of `target` to draw into.
+    fn with_target<I>(instance: Arc<Instance>, device: Arc<Device>, queue: Arc<Queue>, camera: Camera, target: Target, images: &[Arc<I>]) -> Self
+        where I: ImageAccess + Send + Sync + 'static {
+        let final_format = match &target {
+            Target::Window { swapchain, .. } => swapchain.format(),
+            Target::Offscreen { image, .. } => image.format()
+        };
+
+        let vp = camera.get_vp_buffer(target.dimensions());
         let vp_buffer = CpuAccessibleBuffer::from_data(
             device.clone(), 
             BufferUsage::all(), 
             false, 
-            camera.get_vp_buffer(surface.window().inner_size().into())
+            vp
         ).unwrap();
 
-        let ambient_buffer = CpuAccessibleBuffer::from_data(
-            device.clone(),
-            BufferUsage::all(),
-            false,
-            AmbientBufferObject {
-                color: [1.0; 3].into(),
-                intensity: 0.1
-            }
-        ).unwrap();
-        
         let model_buffer = CpuBufferPool::<ModelBufferObject>::uniform_buffer(device.clone());
+        let instance_buffer = CpuBufferPool::<InstanceData>::vertex_buffer(device.clone());
+        let ambient_buffer = CpuBufferPool::<AmbientBufferObject>::uniform_buffer(device.clone());
         let directional_buffer = CpuBufferPool::<DirectionalBufferObject>::uniform_buffer(device.clone());
+        let point_buffer = CpuBufferPool::<PointBufferObject>::uniform_buffer(device.clone());
+        let spot_buffer = CpuBufferPool::<SpotBufferObject>::uniform_buffer(device.clone());
+        let camera_buffer = CpuBufferPool::<CameraBufferObject>::uniform_buffer(device.clone());
 
         let render_pass = Arc::new(vulkano::ordered_passes_renderpass!(
             device.clone(),
@@ -170,7 +362,7 @@ impl Renderer {
                 final_color: {
                     load: Clear,
                     store: Store,
-                    format: swapchain.format(),
+                    format: final_format,
                     samples: 1,
                 },
                 color: {
@@ -188,29 +380,69 @@ impl Renderer {
                 depth: {
                     load: Clear,
                     store: DontCare,
-                    format: Format::D16Unorm,
+                    format: DEPTH_FORMAT,
+                    samples: 1,
+                },
+                material: {
+                    load: Clear,
+                    store: DontCare,
+                    format: MATERIAL_FORMAT,
+                    samples: 1,
+                },
+                emissive: {
+                    load: Clear,
+                    store: DontCare,
+                    format: EMISSIVE_FORMAT,
+                    samples: 1,
+                },
+                hdr: {
+                    load: Clear,
+                    store: Store,
+                    format: HDR_FORMAT,
                     samples: 1,
                 }
             },
             passes: [
                 {
-                    color: [color, normals],
+                    color: [color, normals, material, emissive],
                     depth_stencil: {depth},
                     input: []
                 },
+                {
+                    color: [hdr],
+                    depth_stencil: {},
+                    input: [color, normals, depth, material, emissive]
+                },
                 {
                     color: [final_color],
                     depth_stencil: {},
-                    input: [color, normals]
+                    input: [hdr]
                 }
             ]
         ).unwrap());
 
+        let shadow_pass = Arc::new(vulkano::single_pass_renderpass!(
+            device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: DEPTH_FORMAT,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        ).unwrap());
+
         let deferred_pass = Subpass::from(render_pass.clone(), 0).unwrap();
         let lighting_pass = Subpass::from(render_pass.clone(), 1).unwrap();
 
         let deferred_vs = deferred::vs::Shader::load(device.clone()).unwrap();
         let deferred_fs = deferred::fs::Shader::load(device.clone()).unwrap();
+        let instanced_vs = deferred_instanced::vs::Shader::load(device.clone()).unwrap();
         
         let directional_vs = directional::vs::Shader::load(device.clone()).unwrap();
         let directional_fs = directional::fs::Shader::load(device.clone()).unwrap();
@@ -218,71 +450,70 @@ impl Renderer {
         let ambient_vs = ambient::vs::Shader::load(device.clone()).unwrap();
         let ambient_fs = ambient::fs::Shader::load(device.clone()).unwrap();
 
-        let deferred_pipeline = Arc::new(GraphicsPipeline::start()
-            .vertex_input_single_buffer::<Vertex>()
-            .vertex_shader(deferred_vs.main_entry_point(), ())
-            .triangle_list()
-            .viewports_dynamic_scissors_irrelevant(1)
-            .fragment_shader(deferred_fs.main_entry_point(), ())
-            .depth_stencil_simple_depth()
-            .front_face_counter_clockwise()
-            .cull_mode_back()
-            .render_pass(deferred_pass.clone())
-            .build(device.clone())
-            .unwrap()
-        );
-        
-        let directional_pipeline = Arc::new(GraphicsPipeline::start()
-            .vertex_input_single_buffer::<DummyVertex>()
-            .vertex_shader(directional_vs.main_entry_point(), ())
-            .triangle_list()
-            .viewports_dynamic_scissors_irrelevant(1)
-            .fragment_shader(directional_fs.main_entry_point(), ())
-            .blend_collective(AttachmentBlend {
-                enabled: true,
-                color_op: BlendOp::Add,
-                color_source: BlendFactor::One,
-                color_destination: BlendFactor::One,
-                alpha_op: BlendOp::Max,
-                alpha_source: BlendFactor::One,
-                alpha_destination: BlendFactor::One,
-                mask_red: true,
-                mask_green: true,
-                mask_blue: true,
-                mask_alpha: true
-            })
-            .front_face_counter_clockwise()
-            .cull_mode_back()
-            .render_pass(lighting_pass.clone())
-            .build(device.clone())
-            .unwrap()
-        );
-        
-        let ambient_pipeline = Arc::new(GraphicsPipeline::start()
-            .vertex_input_single_buffer::<DummyVertex>()
-            .vertex_shader(ambient_vs.main_entry_point(), ())
-            .triangle_list()
-            .viewports_dynamic_scissors_irrelevant(1)
-            .fragment_shader(ambient_fs.main_entry_point(), ())
-            .blend_collective(AttachmentBlend {
-                enabled: true,
-                color_op: BlendOp::Add,
-                color_source: BlendFactor::One,
-                color_destination: BlendFactor::One,
-                alpha_op: BlendOp::Max,
-                alpha_source: BlendFactor::One,
-                alpha_destination: BlendFactor::One,
-                mask_red: true,
-                mask_green: true,
-                mask_blue: true,
-                mask_alpha: true
-            })
-            .front_face_counter_clockwise()
-            .cull_mode_back()
-            .render_pass(lighting_pass.clone())
-            .build(device.clone())
-            .unwrap()
-        );
+        let point_vs = point::vs::Shader::load(device.clone()).unwrap();
+        let point_fs = point::fs::Shader::load(device.clone()).unwrap();
+
+        let spot_vs = spot::vs::Shader::load(device.clone()).unwrap();
+        let spot_fs = spot::fs::Shader::load(device.clone()).unwrap();
+
+        let shadow_vs = shadow::vs::Shader::load(device.clone()).unwrap();
+        let shadow_fs = shadow::fs::Shader::load(device.clone()).unwrap();
+
+        let tonemap_vs = tonemap::vs::Shader::load(device.clone()).unwrap();
+        let tonemap_fs = tonemap::fs::Shader::load(device.clone()).unwrap();
+        let histogram_cs = histogram::cs::Shader::load(device.clone()).unwrap();
+
+        let deferred_pipeline = Renderer::build_deferred_pipeline(&device, deferred_pass.clone(), deferred_vs.main_entry_point(), deferred_fs.main_entry_point()).unwrap();
+        let instanced_pipeline = Renderer::build_instanced_pipeline(&device, deferred_pass.clone(), instanced_vs.main_entry_point(), deferred_fs.main_entry_point()).unwrap();
+        let directional_pipeline = Renderer::build_lighting_pipeline(&device, lighting_pass.clone(), directional_vs.main_entry_point(), directional_fs.main_entry_point()).unwrap();
+        let ambient_pipeline = Renderer::build_lighting_pipeline(&device, lighting_pass.clone(), ambient_vs.main_entry_point(), ambient_fs.main_entry_point()).unwrap();
+        let point_pipeline = Renderer::build_light_volume_pipeline(&device, lighting_pass.clone(), point_vs.main_entry_point(), point_fs.main_entry_point()).unwrap();
+        let spot_pipeline = Renderer::build_light_volume_pipeline(&device, lighting_pass.clone(), spot_vs.main_entry_point(), spot_fs.main_entry_point()).unwrap();
+        let shadow_pipeline = Renderer::build_shadow_pipeline(&device, Subpass::from(shadow_pass.clone(), 0).unwrap(), shadow_vs.main_entry_point(), shadow_fs.main_entry_point()).unwrap();
+        let tonemap_pipeline = Renderer::build_tonemap_pipeline(&device, Subpass::from(render_pass.clone(), 2).unwrap(), tonemap_vs.main_entry_point(), tonemap_fs.main_entry_point()).unwrap();
+        let histogram_pipeline = Arc::new(ComputePipeline::new(device.clone(), &histogram_cs.main_entry_point(), &(), None).unwrap());
+
+        let histogram = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage { storage_buffer: true, transfer_destination: true, ..BufferUsage::none() },
+            false,
+            (0..HISTOGRAM_BINS).map(|_| 0u32)
+        ).unwrap();
+        // The histogram reads whole texels with texelFetch, so nothing is filtered or wrapped
+        let histogram_sampler = Sampler::new(
+            device.clone(),
+            Filter::Nearest,
+            Filter::Nearest,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0, 1.0, 0.0, 0.0
+        ).unwrap();
+        let srgb_target = matches!(final_format, Format::B8G8R8A8Srgb | Format::R8G8B8A8Srgb | Format::A8B8G8R8SrgbPack32);
+
+        // Compared against the depth in the maps, with linear filtering blending the results of neighbouring texels
+        let shadow_sampler = Sampler::compare(
+            device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0, 1.0, 0.0, 0.0,
+            Compare::LessOrEqual
+        ).unwrap();
+
+        let null_shadow_map = Renderer::create_shadow_map(&device, &shadow_pass, &ShadowSettings::new(1, 0.0, &[]), 1).unwrap();
+        let mut clear_commands = AutoCommandBufferBuilder::primary(device.clone(), queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
+        clear_commands
+            .begin_render_pass(null_shadow_map.framebuffer.clone(), SubpassContents::Inline, vec![1f32.into()]).unwrap()
+            .end_render_pass().unwrap();
+        let null_shadow_map_future = clear_commands.build().unwrap().execute(queue.clone()).unwrap();
+
+        let (point_volume, point_volume_future) = Renderer::upload_light_volume(&queue, PointLight::volume_mesh());
+        let (spot_volume, spot_volume_future) = Renderer::upload_light_volume(&queue, SpotLight::volume_mesh());
 
         let dummy_verts = CpuAccessibleBuffer::from_iter(
             device.clone(),
@@ -300,7 +531,7 @@ impl Renderer {
             reference: None
         };
 
-        let (framebuffers, color_buffer, normal_buffer) = Renderer::window_size_dependent_setup(device.clone(), &images, render_pass.clone(), &mut dynamic_state);
+        let (framebuffers, g_buffer) = Renderer::window_size_dependent_setup(device.clone(), images, render_pass.clone(), &mut dynamic_state);
 
         let vp_layout = deferred_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
         let vp_set = Arc::new(PersistentDescriptorSet::start(vp_layout.clone())
@@ -313,43 +544,89 @@ impl Renderer {
         let commands = None;
         let img_index = 0;
         let acquire_future = None;
-        
-        let final_images = images.into_iter().map(|image| ImageView::new(image).unwrap()).collect::<Vec<_>>();
+
+        let (null_texture, null_texture_future) = Texture::get_null_buffer(&queue);
+        let (null_normal_map, null_normal_map_future) = Texture::get_null_normal_buffer(&queue);
+        null_texture_future.join(null_normal_map_future)
+            .join(point_volume_future)
+            .join(spot_volume_future)
+            .join(null_shadow_map_future)
+            .then_signal_fence_and_flush().unwrap().wait(None).unwrap();
 
         Self {
             instance,
-            surface,
+            target,
             device,
             queue,
             camera,
-            swapchain,
             vp_buffer,
             model_buffer,
+            instance_buffer,
             ambient_buffer,
             directional_buffer,
+            point_buffer,
+            spot_buffer,
+            camera_buffer,
+            camera_sets: None,
             render_pass,
             deferred_pipeline,
+            instanced_pipeline,
             directional_pipeline,
             ambient_pipeline,
+            point_pipeline,
+            spot_pipeline,
+            tonemap_pipeline,
+            histogram_pipeline,
+            histogram,
+            histogram_sampler,
+            measured_exposure: None,
+            exposure: 1.0,
+            exposure_time: Instant::now(),
+            srgb_target,
+            shadow_pass,
+            shadow_pipeline,
+            shadow_sampler,
+            shadow_maps: HashMap::new(),
+            null_shadow_map,
+            point_volume,
+            spot_volume,
             dummy_verts,
             dynamic_state,
             framebuffers,
-            color_buffer,
-            normal_buffer,
+            g_buffer,
+            view_projection: vp.proj * vp.view,
             vp_set,
             render_stage,
+            clear_color: [0.0; 4],
             commands,
             img_index,
             acquire_future,
-            final_images,
+            null_texture,
+            null_normal_map,
+            samplers: Vec::new(),
+            texture_sets: HashMap::new(),
+            mesh_buffers: HashMap::new(),
+            uploads: None,
         }
     }
     //}}}
 
+    // The window frames are presented to. Offscreen renderers have none.
+    pub fn surface(&self) -> &Arc<Surface<Window>> {
+        match &self.target {
+            Target::Window { surface, .. } => surface,
+            Target::Offscreen { .. } => panic!("An offscreen renderer has no window surface")
+        }
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.target.dimensions()
+    }
+
     pub fn start(&mut self, clear_color: [f32; 4]) {
         match self.render_stage {
             RenderStage::Stopped => {
-                self.render_stage = RenderStage::Deferred;
+                self.render_stage = RenderStage::Shadows;
             },
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
@@ -364,36 +641,213 @@ impl Renderer {
             }
         }
         
-        let (img_index, suboptimal, acquire_future) = match vulkano::swapchain::acquire_next_image(self.swapchain.clone(), None) {
-            Ok(r) => r,
-            Err(vulkano::swapchain::AcquireError::OutOfDate) => {
+        let swapchain = match &self.target {
+            Target::Window { swapchain, .. } => Some(swapchain.clone()),
+            Target::Offscreen { .. } => None
+        };
+
+        // Offscreen frames always draw into the same image
+        let (img_index, acquire_future) = match swapchain {
+            Some(swapchain) => {
+                let (img_index, suboptimal, acquire_future) = match vulkano::swapchain::acquire_next_image(swapchain, None) {
+                    Ok(r) => r,
+                    Err(vulkano::swapchain::AcquireError::OutOfDate) => {
+                        self.recreate_swapchain();
+                        return;
+                    },
+                    Err(err) => {
+                        panic!("{:?}", err)
+                    }
+                };
+
+                if suboptimal {
+                    self.recreate_swapchain();
+                    return;
+                }
+
+                (img_index, Some(acquire_future))
+            },
+            None => (0, None)
+        };
+
+        // Cached GPU resources of assets nothing else uses anymore would otherwise keep them alive
+        self.texture_sets.retain(|_, t| t.textures.iter().all(|h| h.strong_count() > 1));
+        self.mesh_buffers.retain(|_, m| m.mesh.strong_count() > 1);
+
+        let commands = AutoCommandBufferBuilder::primary(self.device.clone(), self.queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
+
+        self.commands = Some(commands);
+        self.clear_color = clear_color;
+        self.img_index = img_index;
+        self.acquire_future = acquire_future;
+    }
+
+    // Draws the shadow map of every light with shadow settings, from the meshes that cast shadows. Has to come before
+    // the frame's geometry, once the camera has been updated. Point lights don't cast shadows.
+    pub fn shadows(&mut self, world: &World) {
+        match self.render_stage {
+            RenderStage::Shadows => { },
+            RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
                 return;
             },
-            Err(err) => {
-                panic!("{:?}", err)
+            _ => {
+                self.render_stage = RenderStage::Stopped;
+                self.commands = None;
+                return;
             }
-        };
+        }
+
+        let lights = world.get_components_of_type::<Light>().unwrap_or_default();
 
-        if suboptimal {
-            self.recreate_swapchain();
+        // Maps of lights that were removed or stopped casting shadows
+        self.shadow_maps.retain(|id, _| lights.iter().any(|l| l.get_id() == *id && l.shadows.is_some()));
+
+        if lights.iter().all(|l| l.shadows.is_none()) {
             return;
         }
 
-        let clear_values = vec![[0.0; 4].into(), clear_color.into(), clear_color.into(), 1f32.into()];
+        // Casters are drawn instanced, grouped like the geometry, and left out of the tiles their bounding sphere misses
+        let mut casters = Vec::new();
 
-        let mut commands = AutoCommandBufferBuilder::primary(self.device.clone(), self.queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
-        commands
-            .begin_render_pass(self.framebuffers[img_index].clone(), SubpassContents::Inline, clear_values)
+        for group in world.instance_groups() {
+            let mesh = match world.get_component_by_id::<Mesh>(group[0]) {
+                Some(mesh) if mesh.cast_shadows => mesh,
+                _ => continue
+            };
+            let data = mesh.data.read();
+
+            if data.indices.is_empty() {
+                continue;
+            }
+
+            let (vertex_buffer, index_buffer) = self.mesh_buffers(&mesh.data, &data);
+            let radius = self.mesh_buffers[&mesh.data.id()].bounding_radius;
+
+            let instances = group.iter()
+                .filter_map(|&id| world.get_component_by_id::<Transform>(id))
+                .map(|transform| {
+                    let model = transform.model_matrix();
+                    let scale = model.x.truncate().magnitude().max(model.y.truncate().magnitude()).max(model.z.truncate().magnitude());
+
+                    (model, model.w.truncate(), radius * scale)
+                })
+                .collect();
+
+            casters.push(ShadowCasters { vertices: vertex_buffer, indices: index_buffer, instances });
+        }
+
+        let [width, height] = self.dimensions();
+        let view = self.camera.get_vp_buffer([width, height]).view;
+
+        for light in lights {
+            let settings = match &light.shadows {
+                Some(settings) => settings,
+                None => continue
+            };
+
+            let id = light.get_id();
+            let transform = world.get_component_by_id::<Transform>(id).cloned().unwrap_or_else(Transform::default);
+
+            let projections = match light.place(&transform) {
+                PlacedLight::Directional(_) => settings.cascades(view, self.camera.fov(), width as f32 / height as f32, self.camera.near(), transform.forward_vector()),
+                PlacedLight::Spot(spot) => vec![spot.shadow_projection()],
+                PlacedLight::Point(_) => continue
+            };
+
+            if projections.is_empty() {
+                continue;
+            }
+
+            let outdated = self.shadow_maps.get(&id)
+                .map_or(true, |m| m.settings.resolution != settings.resolution || m.projections.len() != projections.len());
+
+            if outdated {
+                match Renderer::create_shadow_map(&self.device, &self.shadow_pass, settings, projections.len() as u32) {
+                    Ok(map) => { self.shadow_maps.insert(id, map); },
+                    Err(e) => {
+                        logger::log_error(&e, MessageEmitter::Renderer);
+                        self.shadow_maps.remove(&id);
+                        continue;
+                    }
+                }
+            }
+
+            let map = self.shadow_maps.get_mut(&id).unwrap();
+            map.projections = projections.clone();
+            map.settings = settings.clone();
+            let (framebuffer, resolution) = (map.framebuffer.clone(), map.resolution as f32);
+
+            let mut commands = self.commands.take().unwrap();
+            commands
+                .begin_render_pass(framebuffer, SubpassContents::Inline, vec![1f32.into()])
+                .unwrap();
+
+            for (tile, projection) in projections.iter().enumerate() {
+                let dynamic_state = DynamicState {
+                    viewports: Some(vec![Viewport {
+                        origin: [tile as f32 * resolution, 0.0],
+                        dimensions: [resolution, resolution],
+                        depth_range: 0.0..1.0
+                    }]),
+                    ..DynamicState::none()
+                };
+
+                let shadow_data = shadow::vs::ty::ShadowData {
+                    light_space: (*projection).into()
+                };
+
+                for caster in casters.iter() {
+                    let visible: Vec<InstanceData> = caster.instances.iter()
+                        .filter(|(_, center, radius)| sphere_in_light_space(*projection, *center, *radius))
+                        .map(|(model, _, _)| InstanceData::new(*model, [1.0; 3]))
+                        .collect();
+
+                    if visible.is_empty() {
+                        continue;
+                    }
+
+                    let instance_buffer = Arc::new(self.instance_buffer.chunk(visible).unwrap());
+                    let vertex_buffers = vec![
+                        caster.vertices.clone() as Arc<dyn BufferAccess + Send + Sync>,
+                        instance_buffer as Arc<dyn BufferAccess + Send + Sync>
+                    ];
+
+                    commands
+                        .draw_indexed(
+                            self.shadow_pipeline.clone(),
+                            &dynamic_state,
+                            vertex_buffers,
+                            caster.indices.clone(),
+                            (),
+                            shadow_data
+                        ).unwrap();
+                }
+            }
+
+            commands
+                .end_render_pass()
+                .unwrap();
+            self.commands = Some(commands);
+        }
+    }
+
+    // Begins the frame's render pass after the shadow maps
+    fn begin_deferred(&mut self) {
+        let clear_values = vec![[0.0; 4].into(), self.clear_color.into(), self.clear_color.into(), 1f32.into(), MATERIAL_CLEAR.into(), [0.0; 4].into(), [0.0; 4].into()];
+
+        self.commands.as_mut().unwrap()
+            .begin_render_pass(self.framebuffers[self.img_index].clone(), SubpassContents::Inline, clear_values)
             .unwrap();
 
-        self.commands = Some(commands);
-        self.img_index = img_index;
-        self.acquire_future = Some(acquire_future);
+        self.render_stage = RenderStage::Deferred;
     }
 
-    pub fn geometry(&mut self, mesh: &Mesh, transform: &Transform, material: Option<&Material>, texture: Option<&mut Texture>) {
+    pub fn geometry(&mut self, mesh: &Mesh, transform: &Transform, material: Option<&Material>, texture: Option<&Texture>, normal_map: Option<&NormalMap>) {
         match self.render_stage {
+            RenderStage::Shadows => self.begin_deferred(),
             RenderStage::Deferred => { },
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
@@ -408,16 +862,14 @@ impl Renderer {
             }
         }
 
-        let model_buffer = {
-            let model_matrix = transform.model_matrix();
+        let data = mesh.data.read();
 
-            let uniform_data = ModelBufferObject {
-                model: model_matrix.clone(),
-                normals: model_matrix.clone()
-            };
+        // Nothing to draw, e.g. a model that failed to parse
+        if data.indices.is_empty() {
+            return;
+        }
 
-            self.model_buffer.next(uniform_data).unwrap()
-        };
+        let model_buffer = self.model_buffer.next(ModelBufferObject::new(transform.model_matrix())).unwrap();
 
         let deferred_layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
         let model_set = Arc::new(
@@ -426,115 +878,178 @@ impl Renderer {
                 .build().unwrap()
         );
         
-        // Make sure vertex color is up-to-date (allows dynamically changing color)
-        let model_color = if let Some(material) = material { 
-            material.color
-        } else {
-            [1.0; 3]
+        // The material color tints the vertex color, which carries the submesh's diffuse color
+        let factors = material.map_or_else(|| MaterialData::new([1.0; 3]), |m| m.data.read().clone());
+        let material_data = deferred::vs::ty::MaterialData {
+            color: factors.color,
+            receive_shadows: if mesh.receive_shadows { 1.0 } else { 0.0 },
+            emissive: factors.emissive,
+            metallic: factors.metallic,
+            roughness: factors.roughness,
+            normal_scale: factors.normal_scale,
+            occlusion_strength: factors.occlusion_strength
         };
 
-        let vertices: Vec<Vertex> = mesh.data.vertices
-            .iter()
-            .map(|&v| Vertex { color: model_color, ..v })
-            .collect();
-
-        let vertex_buffer = unsafe {
-            let buffer = CpuAccessibleBuffer::uninitialized_array(
-                self.device.clone(),
-                vertices.len() as u64,
-                BufferUsage::vertex_buffer(),
-                false
-            ).unwrap();
-
-            {
-                let mut mapping = buffer.write().unwrap();
-                mapping.clone_from_slice(vertices.as_slice());
-            }
-
-            buffer
-        };
+        let (vertex_buffer, index_buffer) = self.mesh_buffers(&mesh.data, &data);
 
-        let index_buffer = unsafe {
-            let buffer = CpuAccessibleBuffer::uninitialized_array(
-                self.device.clone(),
-                mesh.data.indices.len() as u64,
-                BufferUsage::index_buffer(),
-                false,
-            ).unwrap();
-
-            {
-                let mut mapping = buffer.write().unwrap();
-                mapping.clone_from_slice(mesh.data.indices.as_slice());
+        for part in data.parts() {
+            if part.index_count == 0 {
+                continue;
             }
 
-            buffer
-        };
-        
-        let layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(2).unwrap();
-        let (image, mut texture_future) = if let Some(texture) = texture {
-            unsafe { texture.get_buffer(&self.queue) }
-        } else {
-            Texture::get_null_buffer(&self.queue)
-        };
-        
-        texture_future.cleanup_finished();
-        
-        let tex_set = Arc::new(
-            PersistentDescriptorSet::start(layout.clone())
-                .add_sampled_image(image.clone(), Texture::get_sampler(&self.device.clone()))
-                .unwrap()
-                .build()
-                .unwrap()
-            );
-
-        let mut commands = self.commands.take().unwrap();
-        commands
-            .draw_indexed(
-                self.deferred_pipeline.clone(),
-                &self.dynamic_state,
-                vec![vertex_buffer.clone()],
-                index_buffer.clone(),
-                vec![self.vp_set.clone(), model_set.clone(), tex_set.clone()],
-                (),
-            ).unwrap();
-        self.commands = Some(commands);
+            let indices = index_buffer.clone().into_buffer_slice()
+                .slice(part.first_index as u64..(part.first_index + part.index_count) as u64)
+                .unwrap();
+
+            let part_texture = part.material.as_ref()
+                .and_then(|m| m.diffuse_texture.as_ref())
+                .and_then(|path| mesh.textures.get(path));
+
+            let tex_set = self.texture_set(Renderer::texture_slots(part_texture, material, texture, normal_map));
+
+            let mut commands = self.commands.take().unwrap();
+            commands
+                .draw_indexed(
+                    self.deferred_pipeline.clone(),
+                    &self.dynamic_state,
+                    vec![vertex_buffer.clone()],
+                    indices,
+                    vec![self.vp_set.clone(), model_set.clone(), tex_set.clone()],
+                    material_data,
+                ).unwrap();
+            self.commands = Some(commands);
+        }
     }
 
-    pub fn ambient(&mut self) {
+    // Draws `mesh` once per instance in a single draw call. Every instance shares the mesh's geometry and textures,
+    // including those of `material`; its factors come from the instances.
+    pub fn geometry_instanced(&mut self, mesh: &Mesh, instances: &[InstanceData], material: Option<&Material>, texture: Option<&Texture>, normal_map: Option<&NormalMap>) {
         match self.render_stage {
-            RenderStage::Deferred => {
-                self.render_stage = RenderStage::Ambient;
-            },
-            RenderStage::Ambient => {
-                return;
-            },
+            RenderStage::Shadows => self.begin_deferred(),
+            RenderStage::Deferred => { },
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
-                self.commands = None;
                 self.render_stage = RenderStage::Stopped;
+                self.commands = None;
                 return;
             },
             _ => {
-                self.commands = None;
                 self.render_stage = RenderStage::Stopped;
+                self.commands = None;
                 return;
             }
         }
 
-        let ambient_layout = self.ambient_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
-        let ambient_set = Arc::new(PersistentDescriptorSet::start(ambient_layout.clone())
-            .add_image(ImageView::new(self.color_buffer.clone()).unwrap()).unwrap()
-            .add_image(ImageView::new(self.normal_buffer.clone()).unwrap()).unwrap()
-            .add_buffer(self.ambient_buffer.clone()).unwrap()
-            .build().unwrap()
-        );
+        let data = mesh.data.read();
 
-        let mut commands = self.commands.take().unwrap();
-        commands
-            .next_subpass(SubpassContents::Inline)
-            .unwrap()
-            .draw(
-                self.ambient_pipeline.clone(),
+        if data.indices.is_empty() || instances.is_empty() {
+            return;
+        }
+
+        // The instances carry their own transforms, so the shared one is left as the identity
+        let model_buffer = self.model_buffer.next(ModelBufferObject {
+            model: Matrix4::identity(),
+            normals: Matrix4::identity()
+        }).unwrap();
+
+        let instanced_layout = self.instanced_pipeline.layout().descriptor_set_layouts().get(1).unwrap();
+        let model_set = Arc::new(
+            PersistentDescriptorSet::start(instanced_layout.clone())
+                .add_buffer(model_buffer).unwrap()
+                .build().unwrap()
+        );
+
+        let mesh_data = deferred_instanced::vs::ty::MeshData {
+            receive_shadows: if mesh.receive_shadows { 1.0 } else { 0.0 }
+        };
+
+        let instance_buffer = Arc::new(self.instance_buffer.chunk(instances.iter().cloned()).unwrap());
+        let (vertex_buffer, index_buffer) = self.mesh_buffers(&mesh.data, &data);
+
+        for part in data.parts() {
+            if part.index_count == 0 {
+                continue;
+            }
+
+            let indices = index_buffer.clone().into_buffer_slice()
+                .slice(part.first_index as u64..(part.first_index + part.index_count) as u64)
+                .unwrap();
+
+            let part_texture = part.material.as_ref()
+                .and_then(|m| m.diffuse_texture.as_ref())
+                .and_then(|path| mesh.textures.get(path));
+
+            let tex_set = self.texture_set(Renderer::texture_slots(part_texture, material, texture, normal_map));
+
+            let vertex_buffers = vec![
+                vertex_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>,
+                instance_buffer.clone() as Arc<dyn BufferAccess + Send + Sync>
+            ];
+
+            let mut commands = self.commands.take().unwrap();
+            commands
+                .draw_indexed(
+                    self.instanced_pipeline.clone(),
+                    &self.dynamic_state,
+                    vertex_buffers,
+                    indices,
+                    vec![self.vp_set.clone(), model_set.clone(), tex_set.clone()],
+                    mesh_data,
+                ).unwrap();
+            self.commands = Some(commands);
+        }
+    }
+
+    pub fn ambient(&mut self, light: &AmbientLight) {
+        match self.render_stage {
+            // Nothing was drawn
+            RenderStage::Shadows => {
+                self.begin_deferred();
+                self.render_stage = RenderStage::Ambient;
+            },
+            RenderStage::Deferred => {
+                self.render_stage = RenderStage::Ambient;
+            },
+            RenderStage::Ambient => {
+                return;
+            },
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            },
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return;
+            }
+        }
+
+        // Without an environment the light is the same from every direction
+        let irradiance = light.environment.clone().unwrap_or_else(|| Irradiance::uniform([1.0; 3]));
+        let ambient_buffer = self.ambient_buffer.next(AmbientBufferObject {
+            color: light.color.into(),
+            intensity: light.intensity,
+            irradiance: irradiance.coefficients.map(|c| [c[0], c[1], c[2], 0.0])
+        }).unwrap();
+
+        let ambient_layout = self.ambient_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let ambient_set = Arc::new(PersistentDescriptorSet::start(ambient_layout.clone())
+            .add_image(ImageView::new(self.g_buffer.color.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.normals.clone()).unwrap()).unwrap()
+            .add_buffer(ambient_buffer).unwrap()
+            .add_image(ImageView::new(self.g_buffer.material.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.emissive.clone()).unwrap()).unwrap()
+            .build().unwrap()
+        );
+
+        let mut commands = self.commands.take().unwrap();
+        commands
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
+            .draw(
+                self.ambient_pipeline.clone(),
                 &self.dynamic_state,
                 vec![self.dummy_verts.clone()],
                 ambient_set.clone(),
@@ -544,12 +1059,203 @@ impl Renderer {
         self.commands = Some(commands);
     }
 
+    // Draws every light component in `world`, placed by its entity's transform. Lights without one sit at the origin.
+    // Lights with shadow settings use the maps drawn by `shadows` this frame.
+    pub fn lights(&mut self, world: &World) {
+        for light in world.get_components_of_type::<Light>().unwrap_or_default() {
+            let id = light.get_id();
+            let transform = world.get_component_by_id::<Transform>(id).cloned().unwrap_or_else(Transform::default);
+            let shadow_map = if light.shadows.is_some() && self.shadow_maps.contains_key(&id) { Some(id) } else { None };
+
+            match light.place(&transform) {
+                PlacedLight::Directional(light) => self.draw_directional(&light, shadow_map),
+                PlacedLight::Point(light) => self.point(&light),
+                PlacedLight::Spot(light) => self.draw_spot(&light, shadow_map)
+            }
+        }
+    }
+
     pub fn directional(&mut self, directional_light: &DirectionalLight) {
+        self.draw_directional(directional_light, None);
+    }
+
+    // `shadow_map` is the key of the light's map in `shadow_maps`
+    fn draw_directional(&mut self, directional_light: &DirectionalLight, shadow_map: Option<usize>) {
+        if !self.begin_lighting() {
+            return;
+        }
+
+        let shadow_map = shadow_map.and_then(|id| self.shadow_maps.get(&id)).unwrap_or(&self.null_shadow_map);
+        let directional_buffer = self.generate_directional_buffer(&self.directional_buffer, &directional_light, shadow_map);
+        let shadow_image = ImageView::new(shadow_map.image.clone()).unwrap();
+
+        let directional_layout = self.directional_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let directional_set = Arc::new(PersistentDescriptorSet::start(directional_layout.clone())
+            .add_image(ImageView::new(self.g_buffer.color.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.normals.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.depth.clone()).unwrap()).unwrap()
+            .add_buffer(directional_buffer.clone()).unwrap()
+            .add_sampled_image(shadow_image, self.shadow_sampler.clone()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.material.clone()).unwrap()).unwrap()
+            .build().unwrap()
+        );
+
+        let mut commands = self.commands.take().unwrap();
+        commands
+            .draw(
+                self.directional_pipeline.clone(),
+                &self.dynamic_state,
+                vec![self.dummy_verts.clone()],
+                (directional_set, self.camera_sets.as_ref().unwrap().directional.clone()),
+                ()
+            )
+            .unwrap();
+        self.commands = Some(commands);
+    }
+
+    pub fn point(&mut self, light: &PointLight) {
+        if !self.begin_lighting() {
+            return;
+        }
+
+        let uniform_data = PointBufferObject {
+            volume: self.view_projection * light.volume(),
+            position: [light.position[0], light.position[1], light.position[2], light.range],
+            color: [light.color[0], light.color[1], light.color[2], light.intensity]
+        };
+        let point_buffer = self.point_buffer.next(uniform_data).unwrap();
+
+        let point_layout = self.point_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let point_set = Arc::new(PersistentDescriptorSet::start(point_layout.clone())
+            .add_image(ImageView::new(self.g_buffer.color.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.normals.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.depth.clone()).unwrap()).unwrap()
+            .add_buffer(point_buffer).unwrap()
+            .add_image(ImageView::new(self.g_buffer.material.clone()).unwrap()).unwrap()
+            .build().unwrap()
+        );
+
+        let mut commands = self.commands.take().unwrap();
+        commands
+            .draw_indexed(
+                self.point_pipeline.clone(),
+                &self.dynamic_state,
+                vec![self.point_volume.vertices.clone()],
+                self.point_volume.indices.clone(),
+                (point_set, self.camera_sets.as_ref().unwrap().point.clone()),
+                ()
+            )
+            .unwrap();
+        self.commands = Some(commands);
+    }
+
+    pub fn spot(&mut self, light: &SpotLight) {
+        self.draw_spot(light, None);
+    }
+
+    fn draw_spot(&mut self, light: &SpotLight, shadow_map: Option<usize>) {
+        if !self.begin_lighting() {
+            return;
+        }
+
+        let (cos_inner, cos_outer) = light.cone_cosines();
+        let direction = Vector3::from(light.direction).normalize();
+
+        let map = shadow_map.and_then(|id| self.shadow_maps.get(&id));
+        let (shadow_projection, shadow) = match map {
+            Some(map) => (map.projections[0], [1.0, map.settings.bias, 1.0 / map.resolution as f32, 0.0]),
+            None => (Matrix4::identity(), [0.0; 4])
+        };
+        let shadow_image = ImageView::new(map.unwrap_or(&self.null_shadow_map).image.clone()).unwrap();
+
+        let uniform_data = SpotBufferObject {
+            volume: self.view_projection * light.volume(),
+            position: [light.position[0], light.position[1], light.position[2], light.range],
+            direction: direction.extend(0.0).into(),
+            color: [light.color[0], light.color[1], light.color[2], light.intensity],
+            cone: [cos_inner, cos_outer, 0.0, 0.0],
+            shadow_projection,
+            shadow
+        };
+        let spot_buffer = self.spot_buffer.next(uniform_data).unwrap();
+
+        let spot_layout = self.spot_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let spot_set = Arc::new(PersistentDescriptorSet::start(spot_layout.clone())
+            .add_image(ImageView::new(self.g_buffer.color.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.normals.clone()).unwrap()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.depth.clone()).unwrap()).unwrap()
+            .add_buffer(spot_buffer).unwrap()
+            .add_sampled_image(shadow_image, self.shadow_sampler.clone()).unwrap()
+            .add_image(ImageView::new(self.g_buffer.material.clone()).unwrap()).unwrap()
+            .build().unwrap()
+        );
+
+        let mut commands = self.commands.take().unwrap();
+        commands
+            .draw_indexed(
+                self.spot_pipeline.clone(),
+                &self.dynamic_state,
+                vec![self.spot_volume.vertices.clone()],
+                self.spot_volume.indices.clone(),
+                (spot_set, self.camera_sets.as_ref().unwrap().spot.clone()),
+                ()
+            )
+            .unwrap();
+        self.commands = Some(commands);
+    }
+
+    // Moves on to the lights after the ambient pass. Returns whether the frame is still being drawn.
+    fn begin_lighting(&mut self) -> bool {
         match self.render_stage {
             RenderStage::Ambient => {
-                self.render_stage = RenderStage::Directional;
+                let camera_uniform = self.camera_buffer.next(self.camera_data()).unwrap();
+                self.camera_sets = Some(CameraSets {
+                    directional: Self::camera_set(&self.directional_pipeline, &camera_uniform),
+                    point: Self::camera_set(&self.point_pipeline, &camera_uniform),
+                    spot: Self::camera_set(&self.spot_pipeline, &camera_uniform)
+                });
+                self.render_stage = RenderStage::Lighting;
+            },
+            RenderStage::Lighting => { },
+            RenderStage::NeedsRedraw => {
+                self.recreate_swapchain();
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return false;
             },
-            RenderStage::Directional => { },
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn camera_data(&self) -> CameraBufferObject {
+        CameraBufferObject {
+            inverse_view_projection: self.view_projection.invert().unwrap_or_else(Matrix4::identity),
+            view: self.camera.get_vp_buffer(self.dimensions()).view,
+            position: self.camera.position().to_homogeneous().into()
+        }
+    }
+
+    // The lighting pipelines read the camera from set 1
+    fn camera_set(pipeline: &Arc<dyn GraphicsPipelineAbstract + Send + Sync>, camera_uniform: &CpuBufferPoolSubbuffer<CameraBufferObject, Arc<StdMemoryPool>>)
+        -> Arc<dyn DescriptorSet + Send + Sync> {
+        let layout = pipeline.layout().descriptor_set_layouts().get(1).unwrap();
+
+        Arc::new(PersistentDescriptorSet::start(layout.clone())
+            .add_buffer(camera_uniform.clone()).unwrap()
+            .build().unwrap()
+        )
+    }
+
+    // Maps the light added up in the HDR image to the final image. Frames finished without it use the default settings.
+    pub fn tonemap(&mut self, settings: &Tonemapping) {
+        match self.render_stage {
+            RenderStage::Ambient | RenderStage::Lighting => { },
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
                 self.commands = None;
@@ -563,32 +1269,107 @@ impl Renderer {
             }
         }
 
-        let directional_buffer = self.generate_directional_buffer(&self.directional_buffer, &directional_light);
+        let exposure = match &settings.exposure {
+            Exposure::Manual(exposure) => {
+                self.measured_exposure = None;
+                *exposure
+            },
+            Exposure::Auto(auto) => {
+                let exposure = self.adapt_exposure(auto);
+                self.measured_exposure = Some(auto.clone());
+                exposure
+            }
+        };
 
-        let directional_layout = self.directional_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
-        let directional_set = Arc::new(PersistentDescriptorSet::start(directional_layout.clone())
-            .add_image(ImageView::new(self.color_buffer.clone()).unwrap()).unwrap()
-            .add_image(ImageView::new(self.normal_buffer.clone()).unwrap()).unwrap()
-            .add_buffer(directional_buffer.clone()).unwrap()
+        let tone_data = tonemap::fs::ty::ToneData {
+            exposure,
+            gamma_power: settings.gamma_power(self.srgb_target),
+            tonemapper: settings.operator.index()
+        };
+
+        let tonemap_layout = self.tonemap_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let tonemap_set = Arc::new(PersistentDescriptorSet::start(tonemap_layout.clone())
+            .add_image(ImageView::new(self.g_buffer.hdr.clone()).unwrap()).unwrap()
             .build().unwrap()
         );
 
         let mut commands = self.commands.take().unwrap();
         commands
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
             .draw(
-                self.directional_pipeline.clone(),
+                self.tonemap_pipeline.clone(),
                 &self.dynamic_state,
                 vec![self.dummy_verts.clone()],
-                directional_set.clone(),
-                ()
+                tonemap_set,
+                tone_data
             )
             .unwrap();
         self.commands = Some(commands);
+        self.render_stage = RenderStage::Tonemapped;
+    }
+
+    // Scenes without lights are done after the ambient pass, and frames that weren't tonemapped use the default settings
+    fn end_lighting(&mut self) {
+        if let RenderStage::Ambient | RenderStage::Lighting = self.render_stage {
+            self.tonemap(&Tonemapping::default());
+        }
+    }
+
+    // Moves the exposure towards what the histogram counted after the previous frame asks for
+    fn adapt_exposure(&mut self, auto: &AutoExposure) -> f32 {
+        let now = Instant::now();
+        let delta_time = now.duration_since(self.exposure_time).as_secs_f32();
+        self.exposure_time = now;
+
+        // The previous frame has finished by now, so the histogram isn't in use. Its bins cover the range it was
+        // counted in, even if the settings changed since.
+        let average = match (&self.measured_exposure, self.histogram.read()) {
+            (Some(measured), Ok(bins)) => measured.average_luminance(&bins),
+            _ => None
+        };
+
+        if let Some(average) = average {
+            self.exposure = auto.adapt(self.exposure, auto.target_exposure(average), delta_time);
+        }
+
+        self.exposure.clamp(auto.min_exposure, auto.max_exposure)
+    }
+
+    // Counts the HDR image's pixels into the histogram, for frames using auto exposure
+    fn count_luminance(&self, commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        let auto = match &self.measured_exposure {
+            Some(auto) => auto,
+            None => return
+        };
+
+        let (min_log_luminance, log_luminance_range) = auto.log_luminance_range();
+        let [width, height] = self.dimensions();
+
+        let histogram_layout = self.histogram_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+        let histogram_set = Arc::new(PersistentDescriptorSet::start(histogram_layout.clone())
+            .add_sampled_image(ImageView::new(self.g_buffer.hdr.clone()).unwrap(), self.histogram_sampler.clone()).unwrap()
+            .add_buffer(self.histogram.clone()).unwrap()
+            .build().unwrap()
+        );
+
+        commands
+            .fill_buffer(self.histogram.clone(), 0)
+            .unwrap()
+            .dispatch(
+                [(width + 15) / 16, (height + 15) / 16, 1],
+                self.histogram_pipeline.clone(),
+                histogram_set,
+                histogram::cs::ty::Range { min_log_luminance, log_luminance_range }
+            )
+            .unwrap();
     }
 
     pub fn finish(&mut self, previous_frame_end: &mut Option<Box<dyn GpuFuture>>, gui: &mut Gui) {
+        self.end_lighting();
+
         match self.render_stage {
-            RenderStage::Directional => { },
+            RenderStage::Tonemapped => { },
             RenderStage::NeedsRedraw => {
                 self.recreate_swapchain();
                 self.commands = None;
@@ -602,17 +1383,31 @@ impl Renderer {
             }
         }
 
+        let (swapchain, final_image) = match &self.target {
+            Target::Window { swapchain, final_images, .. } => (swapchain.clone(), final_images[self.img_index].clone()),
+            Target::Offscreen { .. } => {
+                // There's nothing to present offscreen, so the frame is only rendered
+                self.finish_headless();
+                return;
+            }
+        };
+
         let mut commands = self.commands.take().unwrap();
         commands
             .end_render_pass()
             .unwrap();
+        self.count_luminance(&mut commands);
         let command_buffer = commands.build().unwrap();
 
         let af = self.acquire_future.take().unwrap();
-        let command_future = af.then_execute(self.queue.clone(), command_buffer).unwrap()
+        let before_commands: Box<dyn GpuFuture> = match self.uploads.take() {
+            Some(uploads) => Box::new(af.join(uploads)),
+            None => Box::new(af)
+        };
+        let command_future = before_commands.then_execute(self.queue.clone(), command_buffer).unwrap()
             .then_signal_fence_and_flush().unwrap();
         
-        let after_future = gui.draw_on_image(command_future, self.final_images.get(self.img_index).unwrap().clone()).then_signal_fence_and_flush().unwrap();
+        let after_future = gui.draw_on_image(command_future, final_image).then_signal_fence_and_flush().unwrap();
 
         match after_future.wait(None) {
             Ok(x) => x,
@@ -620,7 +1415,7 @@ impl Renderer {
         }
 
         let future = previous_frame_end.take().unwrap().join(after_future)
-            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), self.img_index)
+            .then_swapchain_present(self.queue.clone(), swapchain, self.img_index)
             .then_signal_fence_and_flush();
         
         match future {
@@ -642,12 +1437,80 @@ impl Renderer {
         self.render_stage = RenderStage::Stopped;
     }
 
+    // Ends an offscreen frame, waiting for it to finish rendering. Returns the final image as tightly packed RGBA rows,
+    // top to bottom, or `None` when the frame was dropped.
+    pub fn finish_headless(&mut self) -> Option<Vec<u8>> {
+        self.end_lighting();
+
+        match self.render_stage {
+            RenderStage::Tonemapped => { },
+            _ => {
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return None;
+            }
+        }
+
+        let (image, pixels) = match &self.target {
+            Target::Offscreen { image, pixels } => (image.clone(), pixels.clone()),
+            Target::Window { .. } => {
+                logger::log_error("Only offscreen renderers can be read back.", MessageEmitter::Renderer);
+                self.commands = None;
+                self.render_stage = RenderStage::Stopped;
+                return None;
+            }
+        };
+
+        let mut commands = self.commands.take().unwrap();
+        commands
+            .end_render_pass()
+            .unwrap()
+            .copy_image_to_buffer(image, pixels.clone())
+            .unwrap();
+        self.count_luminance(&mut commands);
+        let command_buffer = commands.build().unwrap();
+
+        let before_commands: Box<dyn GpuFuture> = match self.uploads.take() {
+            Some(uploads) => uploads,
+            None => Box::new(vulkano::sync::now(self.device.clone()))
+        };
+
+        let rendered = before_commands.then_execute(self.queue.clone(), command_buffer).unwrap()
+            .then_signal_fence_and_flush()
+            .and_then(|future| future.wait(None));
+
+        self.commands = None;
+        self.render_stage = RenderStage::Stopped;
+
+        if let Err(e) = rendered {
+            logger::log_error(&format!("{:?}", e), MessageEmitter::Renderer);
+            return None;
+        }
+
+        let pixels = pixels.read().unwrap().to_vec();
+        Some(pixels)
+    }
+
+    // Saves pixels read back by `finish_headless` as a PNG
+    pub fn save_png(&self, path: &str, pixels: &[u8]) -> Result<(), String> {
+        let [width, height] = self.dimensions();
+
+        image::save_buffer(path, pixels, width, height, image::ColorType::Rgba8)
+            .map_err(|e| format!("Unable to save '{}': {}", path, e))
+    }
+
     pub fn update_camera(&mut self, camera: &Camera) {
+        // Kept for fitting shadow cascades to the view
+        self.camera = camera.clone();
+
+        let vp = camera.get_vp_buffer(self.dimensions());
+        self.view_projection = vp.proj * vp.view;
+
         self.vp_buffer = CpuAccessibleBuffer::from_data(
             self.device.clone(), 
             BufferUsage::uniform_buffer(), 
             false, 
-            camera.get_vp_buffer(self.surface.window().inner_size().into())
+            vp
         ).unwrap();
         
         let vp_layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
@@ -660,23 +1523,33 @@ impl Renderer {
     }
 
     pub fn recreate_swapchain(&mut self) {
+        // Offscreen images keep their size
+        let swapchain = match &self.target {
+            Target::Window { swapchain, .. } => swapchain.clone(),
+            Target::Offscreen { .. } => return
+        };
+
         self.render_stage = RenderStage::NeedsRedraw;
         self.commands = None;
 
-        let dimensions: [u32; 2] = self.surface.window().inner_size().into();
-        let (new_swapchain, new_images) = match self.swapchain.recreate().dimensions(dimensions).build() {
+        let dimensions = self.dimensions();
+        let (new_swapchain, new_images) = match swapchain.recreate().dimensions(dimensions).build() {
             Ok(r) => r,
             Err(SwapchainCreationError::UnsupportedDimensions) => return,
             Err(e) => panic!("{:?}", e)
         };
-        self.swapchain = new_swapchain;
-        let (new_framebuffers, new_color_buffer, new_normal_buffer) = Self::window_size_dependent_setup(self.device.clone(), &new_images, self.render_pass.clone(), &mut self.dynamic_state);
+        let (new_framebuffers, new_g_buffer) = Self::window_size_dependent_setup(self.device.clone(), &new_images, self.render_pass.clone(), &mut self.dynamic_state);
         self.framebuffers = new_framebuffers;
-        self.color_buffer = new_color_buffer;
-        self.normal_buffer = new_normal_buffer;
-        self.final_images = new_images.into_iter().map(|i| ImageView::new(i).unwrap()).collect();
+        self.g_buffer = new_g_buffer;
+
+        if let Target::Window { swapchain, final_images, .. } = &mut self.target {
+            *swapchain = new_swapchain;
+            *final_images = new_images.iter().map(|i| ImageView::new(i.clone()).unwrap()).collect();
+        }
 
-        self.vp_buffer = CpuAccessibleBuffer::from_data(self.device.clone(), BufferUsage::all(), false, self.camera.get_vp_buffer(dimensions)).unwrap();
+        let vp = self.camera.get_vp_buffer(dimensions);
+        self.view_projection = vp.proj * vp.view;
+        self.vp_buffer = CpuAccessibleBuffer::from_data(self.device.clone(), BufferUsage::all(), false, vp).unwrap();
 
         let vp_layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
         self.vp_set = Arc::new(PersistentDescriptorSet::start(vp_layout.clone())
@@ -687,22 +1560,486 @@ impl Renderer {
         self.render_stage = RenderStage::Stopped;
     }
 
-    fn generate_directional_buffer(&self, pool: &CpuBufferPool<DirectionalBufferObject>, light: &DirectionalLight)
+    // Recompiles the pipeline `changed` belongs to (e.g. `deferred_frag.glsl` -> the deferred pipeline) from the GLSL on disk.
+    // Compile errors, and shaders whose interface no longer fits the pipeline, are logged and the current pipeline is kept.
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_shader(&mut self, changed: &std::path::Path) {
+        let stem = match changed.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem,
+            None => return
+        };
+        let name = match stem.rsplit_once('_') {
+            // The instanced pipeline shares the deferred fragment shader, so both are rebuilt together
+            Some(("deferred_instanced", _)) => "deferred",
+            Some((name, _)) => name,
+            // Included files have no stage suffix
+            None => stem
+        };
+
+        let directory = changed.parent().unwrap_or_else(|| std::path::Path::new("."));
+
+        // Included files have no pipeline of their own, so every pipeline including them is rebuilt
+        let pipelines = match name {
+            "brdf" => vec!["directional", "point", "spot"],
+            name => vec![name]
+        };
+
+        for name in pipelines {
+            match self.rebuild_pipeline(name, directory) {
+                Ok(true) => logger::log_info(&format!("Reloaded the {} pipeline", name), MessageEmitter::Renderer),
+                Ok(false) => logger::log_warning(&format!("'{}' doesn't belong to a known pipeline", changed.display()), MessageEmitter::Renderer),
+                Err(e) => logger::log_error(&format!("Keeping the previous {} pipeline. {}", name, e), MessageEmitter::Renderer)
+            }
+        }
+    }
+
+    // Recompiles the shaders of the pipeline called `name` from `directory`. Returns whether there is such a pipeline.
+    #[cfg(feature = "hot-reload")]
+    fn rebuild_pipeline(&mut self, name: &str, directory: &std::path::Path) -> Result<bool, String> {
+        use crate::shaders::runtime::{ compile_module, compute_entry_point, entry_point };
+        use shaderc::ShaderKind;
+
+        // The histogram is the only compute shader, and has no vertex or fragment stage to compile alongside it
+        if name == "histogram" {
+            let cs_module = compile_module(&self.device, &directory.join("histogram_comp.glsl"), ShaderKind::Compute)?;
+            let cs = histogram::cs::Shader::load(self.device.clone()).unwrap();
+            let pipeline = ComputePipeline::new(self.device.clone(), &compute_entry_point(&cs_module, &cs.main_entry_point())?, &(), None)
+                .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+            self.histogram_pipeline = Arc::new(pipeline);
+            return Ok(true);
+        }
+
+        if !["deferred", "directional", "ambient", "point", "spot", "tonemap", "shadow"].contains(&name) {
+            return Ok(false);
+        }
+
+        let vs_module = compile_module(&self.device, &directory.join(format!("{}_vert.glsl", name)), ShaderKind::Vertex)?;
+        let fs_module = compile_module(&self.device, &directory.join(format!("{}_frag.glsl", name)), ShaderKind::Fragment)?;
+
+        let deferred_pass = Subpass::from(self.render_pass.clone(), 0).unwrap();
+        let lighting_pass = Subpass::from(self.render_pass.clone(), 1).unwrap();
+
+        // The compiled-in shaders provide the interface (inputs, outputs, descriptors) the reloaded ones must match
+        match name {
+            "deferred" => {
+                let instanced_vs_module = compile_module(&self.device, &directory.join("deferred_instanced_vert.glsl"), ShaderKind::Vertex)?;
+                let (vs, fs) = (deferred::vs::Shader::load(self.device.clone()).unwrap(), deferred::fs::Shader::load(self.device.clone()).unwrap());
+                let instanced_vs = deferred_instanced::vs::Shader::load(self.device.clone()).unwrap();
+
+                // Both are built before either is replaced, so they can't go out of step
+                let deferred_pipeline = Renderer::build_deferred_pipeline(
+                    &self.device,
+                    deferred_pass.clone(),
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+                let instanced_pipeline = Renderer::build_instanced_pipeline(
+                    &self.device,
+                    deferred_pass,
+                    entry_point(&instanced_vs_module, &instanced_vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+
+                self.deferred_pipeline = deferred_pipeline;
+                self.instanced_pipeline = instanced_pipeline;
+                self.texture_sets.clear();
+
+                let vp_layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(0).unwrap();
+                self.vp_set = Arc::new(PersistentDescriptorSet::start(vp_layout.clone())
+                    .add_buffer(self.vp_buffer.clone()).unwrap()
+                    .build().unwrap()
+                );
+            },
+            "directional" => {
+                let (vs, fs) = (directional::vs::Shader::load(self.device.clone()).unwrap(), directional::fs::Shader::load(self.device.clone()).unwrap());
+                self.directional_pipeline = Renderer::build_lighting_pipeline(
+                    &self.device,
+                    lighting_pass,
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            },
+            "ambient" => {
+                let (vs, fs) = (ambient::vs::Shader::load(self.device.clone()).unwrap(), ambient::fs::Shader::load(self.device.clone()).unwrap());
+                self.ambient_pipeline = Renderer::build_lighting_pipeline(
+                    &self.device,
+                    lighting_pass,
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            },
+            "point" => {
+                let (vs, fs) = (point::vs::Shader::load(self.device.clone()).unwrap(), point::fs::Shader::load(self.device.clone()).unwrap());
+                self.point_pipeline = Renderer::build_light_volume_pipeline(
+                    &self.device,
+                    lighting_pass,
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            },
+            "spot" => {
+                let (vs, fs) = (spot::vs::Shader::load(self.device.clone()).unwrap(), spot::fs::Shader::load(self.device.clone()).unwrap());
+                self.spot_pipeline = Renderer::build_light_volume_pipeline(
+                    &self.device,
+                    lighting_pass,
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            },
+            "tonemap" => {
+                let (vs, fs) = (tonemap::vs::Shader::load(self.device.clone()).unwrap(), tonemap::fs::Shader::load(self.device.clone()).unwrap());
+                self.tonemap_pipeline = Renderer::build_tonemap_pipeline(
+                    &self.device,
+                    Subpass::from(self.render_pass.clone(), 2).unwrap(),
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            },
+            _ => {
+                let (vs, fs) = (shadow::vs::Shader::load(self.device.clone()).unwrap(), shadow::fs::Shader::load(self.device.clone()).unwrap());
+                self.shadow_pipeline = Renderer::build_shadow_pipeline(
+                    &self.device,
+                    Subpass::from(self.shadow_pass.clone(), 0).unwrap(),
+                    entry_point(&vs_module, &vs.main_entry_point())?,
+                    entry_point(&fs_module, &fs.main_entry_point())?
+                )?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Geometry is uploaded to device-local memory once per generation of the mesh data. The upload is submitted
+    // along with the frame that first draws it.
+    fn mesh_buffers(&mut self, mesh: &Handle<MeshData>, data: &MeshData) -> (Arc<ImmutableBuffer<[Vertex]>>, Arc<ImmutableBuffer<[u32]>>) {
+        let generation = mesh.generation();
+
+        if let Some(cached) = self.mesh_buffers.get(&mesh.id()) {
+            if cached.generation == generation {
+                return (cached.vertices.clone(), cached.indices.clone());
+            }
+        }
+
+        let (vertices, vertex_future) = ImmutableBuffer::from_iter(
+            data.vertices.iter().cloned(),
+            BufferUsage::vertex_buffer(),
+            self.queue.clone()
+        ).unwrap();
+
+        let (indices, index_future) = ImmutableBuffer::from_iter(
+            data.indices.iter().cloned(),
+            BufferUsage::index_buffer(),
+            self.queue.clone()
+        ).unwrap();
+
+        self.queue_upload(vertex_future.join(index_future));
+
+        self.mesh_buffers.insert(mesh.id(), MeshBuffers {
+            mesh: mesh.clone(),
+            generation,
+            vertices: vertices.clone(),
+            indices: indices.clone(),
+            bounding_radius: data.bounding_radius()
+        });
+
+        (vertices, indices)
+    }
+
+    // Uploads are submitted before the next frame's commands, which is what the frame waits on
+    fn queue_upload<F: GpuFuture + 'static>(&mut self, upload: F) {
+        self.uploads = Some(match self.uploads.take() {
+            Some(uploads) => Box::new(uploads.join(upload)),
+            None => Box::new(upload)
+        });
+    }
+
+    // The textures bound for a part of a mesh. A submesh's own diffuse map takes priority over the material's base color
+    // texture, and the material's textures over the entity's texture and normal map.
+    fn texture_slots<'a>(part_texture: Option<&'a Texture>, material: Option<&'a Material>, texture: Option<&'a Texture>, normal_map: Option<&'a NormalMap>)
+        -> [Option<&'a Texture>; TEXTURE_SLOTS] {
+        let textures = material.map(|m| &m.textures);
+
+        [
+            part_texture.or_else(|| textures.and_then(|t| t.base_color.as_ref())).or(texture),
+            textures.and_then(|t| t.normal.as_ref()).or_else(|| normal_map.map(|n| &n.texture)),
+            textures.and_then(|t| t.metallic_roughness.as_ref()),
+            textures.and_then(|t| t.occlusion.as_ref()),
+            textures.and_then(|t| t.emissive.as_ref())
+        ]
+    }
+
+    // Descriptor sets are built once per combination of textures and samplers, and rebuilt when a texture is reloaded.
+    // Empty slots get white, or a flat normal for the normal map, so the material's factors apply unchanged.
+    fn texture_set(&mut self, textures: [Option<&Texture>; TEXTURE_SLOTS]) -> Arc<dyn DescriptorSet + Send + Sync> {
+        let mut key = [(None, 0); TEXTURE_SLOTS];
+        let mut generations = [None; TEXTURE_SLOTS];
+
+        for (slot, texture) in textures.iter().enumerate() {
+            key[slot] = (texture.map(|t| t.data.id()), self.sampler_index(texture.map_or_else(SamplerDesc::default, |t| t.sampler)));
+            generations[slot] = texture.map(|t| t.data.generation());
+        }
+
+        if let Some(cached) = self.texture_sets.get(&key) {
+            if cached.generations == generations {
+                return cached.set.clone();
+            }
+        }
+
+        let mut images = Vec::with_capacity(TEXTURE_SLOTS);
+
+        for (slot, texture) in textures.iter().enumerate() {
+            images.push(match texture {
+                Some(texture) => {
+                    let (image, upload) = texture.get_image(&self.queue);
+                    if let Some(upload) = upload {
+                        self.queue_upload(upload);
+                    }
+
+                    image
+                },
+                None if slot == 1 => self.null_normal_map.clone(),
+                None => self.null_texture.clone()
+            });
+        }
+        let samplers: Vec<_> = key.iter().map(|&(_, sampler)| self.samplers[sampler].1.clone()).collect();
+
+        let layout = self.deferred_pipeline.layout().descriptor_set_layouts().get(2).unwrap();
+        let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_sampled_image(images[0].clone(), samplers[0].clone()).unwrap()
+                .add_sampled_image(images[1].clone(), samplers[1].clone()).unwrap()
+                .add_sampled_image(images[2].clone(), samplers[2].clone()).unwrap()
+                .add_sampled_image(images[3].clone(), samplers[3].clone()).unwrap()
+                .add_sampled_image(images[4].clone(), samplers[4].clone()).unwrap()
+                .build()
+                .unwrap()
+        );
+
+        self.texture_sets.insert(key, TextureSet {
+            textures: textures.iter().flatten().map(|t| t.data.clone()).collect(),
+            generations,
+            set: set.clone()
+        });
+
+        set
+    }
+
+    // Dedicated GPUs are preferred over integrated, virtual and software ones
+    fn device_rank(device: &PhysicalDevice) -> u8 {
+        match device.properties().device_type {
+            PhysicalDeviceType::DiscreteGpu => 0,
+            PhysicalDeviceType::IntegratedGpu => 1,
+            PhysicalDeviceType::VirtualGpu => 2,
+            PhysicalDeviceType::Cpu => 3,
+            PhysicalDeviceType::Other => 4,
+        }
+    }
+
+    fn sampler_index(&mut self, desc: SamplerDesc) -> usize {
+        if let Some(index) = self.samplers.iter().position(|(d, _)| *d == desc) {
+            return index;
+        }
+
+        self.samplers.push((desc, desc.create(&self.device)));
+
+        self.samplers.len() - 1
+    }
+
+    fn build_deferred_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<Vertex>()
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .depth_stencil_simple_depth()
+            .front_face_counter_clockwise()
+            .cull_mode_back()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // Same as the deferred pipeline, with a second vertex buffer stepping once per instance
+    fn build_instanced_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .depth_stencil_simple_depth()
+            .front_face_counter_clockwise()
+            .cull_mode_back()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // Lighting passes draw a full-screen triangle pair and add their contribution to the final image
+    fn build_lighting_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<DummyVertex>()
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .blend_collective(AttachmentBlend {
+                enabled: true,
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::One,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Max,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::One,
+                mask_red: true,
+                mask_green: true,
+                mask_blue: true,
+                mask_alpha: true
+            })
+            .front_face_counter_clockwise()
+            .cull_mode_back()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // Light volumes only shade the pixels they cover. Their back faces are drawn, so they still cover the screen with the camera inside.
+    fn build_light_volume_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<Vertex>()
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .blend_collective(AttachmentBlend {
+                enabled: true,
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::One,
+                color_destination: BlendFactor::One,
+                alpha_op: BlendOp::Max,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::One,
+                mask_red: true,
+                mask_green: true,
+                mask_blue: true,
+                mask_alpha: true
+            })
+            .front_face_counter_clockwise()
+            .cull_mode_front()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // Draws a full-screen triangle pair over the final image, replacing what's there
+    fn build_tonemap_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_single_buffer::<DummyVertex>()
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .front_face_counter_clockwise()
+            .cull_mode_back()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // Depth only, drawn with a viewport per cascade and instanced like the geometry. No faces are culled, so thin or
+    // open meshes still cast shadows.
+    fn build_shadow_pipeline(device: &Arc<Device>, subpass: Subpass, vs: GraphicsEntryPoint, fs: GraphicsEntryPoint)
+        -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, String> {
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input(OneVertexOneInstanceDefinition::<Vertex, InstanceData>::new())
+            .vertex_shader(vs, ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs, ())
+            .depth_stencil_simple_depth()
+            .cull_mode_disabled()
+            .render_pass(subpass)
+            .build(device.clone())
+            .map_err(|e| format!("Unable to build pipeline: {}", e))?;
+
+        Ok(Arc::new(pipeline))
+    }
+
+    // The tiles are clamped to fit the device's largest image
+    fn create_shadow_map(device: &Arc<Device>, render_pass: &Arc<RenderPass>, settings: &ShadowSettings, tiles: u32) -> Result<ShadowMap, String> {
+        let max_width = device.physical_device().properties().max_image_dimension2_d;
+        let resolution = settings.resolution.clamp(1, (max_width / tiles.max(1)).max(1));
+
+        let image = AttachmentImage::sampled(device.clone(), [resolution * tiles, resolution], DEPTH_FORMAT)
+            .map_err(|e| format!("Unable to create a {}x{} shadow map: {}", resolution * tiles, resolution, e))?;
+
+        let framebuffer = Arc::new(Framebuffer::start(render_pass.clone())
+            .add(ImageView::new(image.clone()).unwrap()).unwrap()
+            .build().unwrap()
+        ) as Arc<dyn FramebufferAbstract + Send + Sync>;
+
+        Ok(ShadowMap {
+            image,
+            framebuffer,
+            resolution,
+            projections: Vec::new(),
+            settings: settings.clone()
+        })
+    }
+
+    fn upload_light_volume(queue: &Arc<Queue>, mesh: MeshData) -> (LightVolume, Box<dyn GpuFuture>) {
+        let (vertices, vertex_future) = ImmutableBuffer::from_iter(mesh.vertices.into_iter(), BufferUsage::vertex_buffer(), queue.clone()).unwrap();
+        let (indices, index_future) = ImmutableBuffer::from_iter(mesh.indices.into_iter(), BufferUsage::index_buffer(), queue.clone()).unwrap();
+
+        (LightVolume { vertices, indices }, Box::new(vertex_future.join(index_future)))
+    }
+
+    fn generate_directional_buffer(&self, pool: &CpuBufferPool<DirectionalBufferObject>, light: &DirectionalLight, shadow_map: &ShadowMap)
         -> CpuBufferPoolSubbuffer<DirectionalBufferObject, Arc<StdMemoryPool>> {
+        let mut cascades = [Matrix4::identity(); MAX_CASCADES];
+        let mut splits = [0.0; MAX_CASCADES];
+
+        for (i, (projection, split)) in shadow_map.projections.iter().zip(shadow_map.settings.cascade_splits.iter()).enumerate() {
+            cascades[i] = *projection;
+            splits[i] = *split;
+        }
+
         let uniform_data = DirectionalBufferObject {
             position: light.position.into(),
-            color: light.color.into()
+            color: light.color.into(),
+            cascade_count: shadow_map.projections.len() as i32,
+            cascades,
+            splits,
+            shadow: [shadow_map.settings.bias, 1.0 / shadow_map.resolution as f32, 0.0, 0.0]
         };
 
         pool.next(uniform_data).unwrap()
     }
     
-    fn window_size_dependent_setup(
+    fn window_size_dependent_setup<I>(
         device: Arc<Device>,
-        images: &[Arc<SwapchainImage<Window>>],
+        images: &[Arc<I>],
         render_pass: Arc<RenderPass>,
         dynamic_state: &mut DynamicState,
-    ) -> (Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, Arc<AttachmentImage>, Arc<AttachmentImage>) {
+    ) -> (Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, GBuffer)
+        where I: ImageAccess + Send + Sync + 'static {
         let dimensions = images[0].dimensions();
     
         let viewport = Viewport {
@@ -712,20 +2049,33 @@ impl Renderer {
         };
         dynamic_state.viewports = Some(vec![viewport]);
 
-        let color_buffer = AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), Format::A2B10G10R10UnormPack32).unwrap();
-        let normal_buffer = AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), Format::R16G16B16A16Sfloat).unwrap();
-        let depth_buffer = AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), Format::D16Unorm).unwrap();
+        let g_buffer = GBuffer {
+            color: AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), Format::A2B10G10R10UnormPack32).unwrap(),
+            normals: AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), Format::R16G16B16A16Sfloat).unwrap(),
+            depth: AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), DEPTH_FORMAT).unwrap(),
+            material: AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), MATERIAL_FORMAT).unwrap(),
+            emissive: AttachmentImage::transient_input_attachment(device.clone(), dimensions.width_height(), EMISSIVE_FORMAT).unwrap(),
+            hdr: AttachmentImage::with_usage(
+                device.clone(),
+                dimensions.width_height(),
+                HDR_FORMAT,
+                ImageUsage { color_attachment: true, input_attachment: true, sampled: true, ..ImageUsage::none() }
+            ).unwrap()
+        };
 
         (images.iter().map(|image| {
             Arc::new(
                 Framebuffer::start(render_pass.clone())
                     // This can't be best-practice...
                     .add(ImageView::new(image.clone()).unwrap()).unwrap()
-                    .add(ImageView::new(color_buffer.clone()).unwrap()).unwrap()
-                    .add(ImageView::new(normal_buffer.clone()).unwrap()).unwrap()
-                    .add(ImageView::new(depth_buffer.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.color.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.normals.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.depth.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.material.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.emissive.clone()).unwrap()).unwrap()
+                    .add(ImageView::new(g_buffer.hdr.clone()).unwrap()).unwrap()
                     .build().unwrap()
             ) as Arc<dyn FramebufferAbstract + Send + Sync>
-        }).collect::<Vec<_>>(), color_buffer.clone(), normal_buffer.clone())
+        }).collect::<Vec<_>>(), g_buffer)
     }
 }
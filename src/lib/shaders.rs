@@ -13,6 +13,15 @@ pub mod deferred {
         }
     }
 }
+// Shares the deferred fragment shader
+pub mod deferred_instanced {
+    pub mod vs {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            path: "src/shaders/deferred_instanced_vert.glsl"
+        }
+    }
+}
 pub mod directional {
     pub mod vs {
         vulkano_shaders::shader! {
@@ -24,7 +33,8 @@ pub mod directional {
     pub mod fs {
         vulkano_shaders::shader! {
             ty: "fragment",
-            path: "src/shaders/directional_frag.glsl"
+            path: "src/shaders/directional_frag.glsl",
+            include: ["src/shaders"]
         }
     }
 }
@@ -43,3 +53,194 @@ pub mod ambient {
         }
     }
 }
+pub mod point {
+    pub mod vs {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            path: "src/shaders/point_vert.glsl"
+        }
+    }
+    
+    pub mod fs {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            path: "src/shaders/point_frag.glsl",
+            include: ["src/shaders"]
+        }
+    }
+}
+pub mod spot {
+    pub mod vs {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            path: "src/shaders/spot_vert.glsl"
+        }
+    }
+    
+    pub mod fs {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            path: "src/shaders/spot_frag.glsl",
+            include: ["src/shaders"]
+        }
+    }
+}
+// Depth-only pass drawing shadow casters from a light
+pub mod shadow {
+    pub mod vs {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            path: "src/shaders/shadow_vert.glsl"
+        }
+    }
+    
+    pub mod fs {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            path: "src/shaders/shadow_frag.glsl"
+        }
+    }
+}
+// Maps the lit HDR image to the final one
+pub mod tonemap {
+    pub mod vs {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            path: "src/shaders/tonemap_vert.glsl"
+        }
+    }
+    
+    pub mod fs {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            path: "src/shaders/tonemap_frag.glsl"
+        }
+    }
+}
+// Counts the HDR image's pixels by luminance for auto exposure
+pub mod histogram {
+    pub mod cs {
+        vulkano_shaders::shader! {
+            ty: "compute",
+            path: "src/shaders/histogram_comp.glsl"
+        }
+    }
+}
+
+// Runtime compilation for hot reloading
+#[cfg(feature = "hot-reload")]
+pub mod runtime {
+    use std::ffi::CStr;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use shaderc::{ CompileOptions, Compiler, IncludeType, ResolvedInclude, ShaderKind };
+    use vulkano::device::Device;
+    use vulkano::pipeline::shader::{ ComputeEntryPoint, EntryPointAbstract, GraphicsEntryPoint, ShaderInterface, ShaderModule };
+
+    use crate::spirv::SpirvInterface;
+
+    // A recompiled shader, along with the interface read from its SPIR-V
+    pub struct RuntimeModule {
+        pub module: Arc<ShaderModule>,
+        pub interface: SpirvInterface,
+        path: String
+    }
+
+    pub fn compile_module(device: &Arc<Device>, path: &Path, kind: ShaderKind) -> Result<RuntimeModule, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("Unable to read shader '{}': {}", path.display(), e))?;
+        let mut compiler = Compiler::new().ok_or_else(|| "Unable to start the shader compiler".to_string())?;
+
+        let mut options = CompileOptions::new().ok_or_else(|| "Unable to create the shader compiler options".to_string())?;
+        let directory = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+        // #include "file" is looked up next to the shader, like the compiled-in shaders do
+        options.set_include_callback(move |name, _: IncludeType, _, _| {
+            let include = directory.join(name);
+
+            fs::read_to_string(&include)
+                .map(|content| ResolvedInclude { resolved_name: include.display().to_string(), content })
+                .map_err(|e| format!("Unable to read '{}': {}", include.display(), e))
+        });
+
+        let spirv = compiler.compile_into_spirv(&source, kind, &path.to_string_lossy(), "main", Some(&options))
+            .map_err(|e| format!("Unable to compile shader '{}': {}", path.display(), e))?;
+
+        let interface = SpirvInterface::reflect(spirv.as_binary())
+            .map_err(|e| format!("Unable to read the interface of '{}': {}", path.display(), e))?;
+
+        let module = unsafe { ShaderModule::new(device.clone(), spirv.as_binary_u8()) }
+            .map_err(|e| format!("Unable to create shader module for '{}': {}", path.display(), e))?;
+
+        Ok(RuntimeModule { module, interface, path: path.display().to_string() })
+    }
+
+    // The `main` entry point of a recompiled module, described by the entry point of the shader compiled into the engine.
+    // Shaders can be edited freely as long as their inputs, outputs, descriptors and push constants still fit that
+    // description; ones that don't are rejected, since Vulkan would trust it regardless.
+    pub fn entry_point<'a>(runtime: &'a RuntimeModule, template: &GraphicsEntryPoint) -> Result<GraphicsEntryPoint<'a>, String> {
+        let expected = expected_interface(template, Some(template.input()), Some(template.output()));
+        check(runtime, &expected)?;
+
+        let name = CStr::from_bytes_with_nul(b"main\0").unwrap();
+
+        Ok(unsafe {
+            runtime.module.graphics_entry_point(
+                name,
+                template.descriptor_set_layout_descs().iter().cloned(),
+                *template.push_constant_range(),
+                &[],
+                template.input().clone(),
+                template.output().clone(),
+                template.ty()
+            )
+        })
+    }
+
+    // Like `entry_point`, for compute shaders
+    pub fn compute_entry_point<'a>(runtime: &'a RuntimeModule, template: &ComputeEntryPoint) -> Result<ComputeEntryPoint<'a>, String> {
+        check(runtime, &expected_interface(template, None, None))?;
+
+        let name = CStr::from_bytes_with_nul(b"main\0").unwrap();
+
+        Ok(unsafe {
+            runtime.module.compute_entry_point(
+                name,
+                template.descriptor_set_layout_descs().iter().cloned(),
+                *template.push_constant_range(),
+                &[]
+            )
+        })
+    }
+
+    fn check(runtime: &RuntimeModule, expected: &SpirvInterface) -> Result<(), String> {
+        match runtime.interface.mismatch(expected) {
+            Some(mismatch) => Err(format!("'{}' no longer fits its pipeline: {}", runtime.path, mismatch)),
+            None => Ok(())
+        }
+    }
+
+    fn expected_interface(template: &impl EntryPointAbstract, input: Option<&ShaderInterface>, output: Option<&ShaderInterface>) -> SpirvInterface {
+        let locations = |interface: Option<&ShaderInterface>| {
+            let mut locations: Vec<(u32, u32)> = interface.map_or(&[][..], |i| i.elements()).iter()
+                .map(|element| (element.location.start, element.location.end))
+                .collect();
+            locations.sort_unstable();
+            locations
+        };
+
+        let mut descriptors: Vec<(u32, u32)> = template.descriptor_set_layout_descs().iter().enumerate()
+            .flat_map(|(set, desc)| desc.bindings().iter().enumerate()
+                .filter(|(_, binding)| binding.is_some())
+                .map(move |(binding, _)| (set as u32, binding as u32)))
+            .collect();
+        descriptors.sort_unstable();
+
+        SpirvInterface {
+            inputs: locations(input),
+            outputs: locations(output),
+            descriptors,
+            push_constant_size: template.push_constant_range().as_ref().map(|range| (range.offset + range.size) as u32)
+        }
+    }
+}
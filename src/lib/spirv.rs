@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+// What a pipeline needs to agree on with a shader module, read from its SPIR-V. Hot reloading compares it against the
+// shader compiled into the engine, since the reloaded module is described to Vulkan by that shader's interface.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpirvInterface {
+    // Location ranges of the stage's user-defined inputs and outputs, sorted
+    pub inputs: Vec<(u32, u32)>,
+    pub outputs: Vec<(u32, u32)>,
+    // (set, binding) of every descriptor, sorted
+    pub descriptors: Vec<(u32, u32)>,
+    // Bytes the push constant block spans, if there is one
+    pub push_constant_size: Option<u32>
+}
+
+const MAGIC: u32 = 0x0723_0203;
+
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_OUTPUT: u32 = 3;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+enum Type {
+    Scalar(u32),
+    Vector(u32, u32),
+    Matrix(u32, u32),
+    Array(u32, u32),
+    Struct(Vec<u32>),
+    Pointer(u32)
+}
+
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    // Variable id to its pointer type and storage class
+    variables: Vec<(u32, u32, u32)>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>
+}
+
+impl SpirvInterface {
+    // Reads the interface of a single-entry-point module, as shaderc compiles them
+    pub fn reflect(words: &[u32]) -> Result<Self, String> {
+        if words.len() < 5 || words[0] != MAGIC {
+            return Err("Not a SPIR-V module".to_string());
+        }
+
+        let mut module = Module::default();
+        let mut i = 5;
+
+        while i < words.len() {
+            let count = (words[i] >> 16) as usize;
+            let opcode = words[i] & 0xffff;
+
+            if count == 0 || i + count > words.len() {
+                return Err(format!("Truncated SPIR-V instruction at word {}", i));
+            }
+            let operands = &words[i + 1..i + count];
+            i += count;
+
+            match (opcode, operands) {
+                (OP_TYPE_BOOL, [id, ..]) => { module.types.insert(*id, Type::Scalar(4)); },
+                (OP_TYPE_INT, [id, width, ..]) | (OP_TYPE_FLOAT, [id, width, ..]) => { module.types.insert(*id, Type::Scalar(width / 8)); },
+                (OP_TYPE_VECTOR, [id, component, count]) => { module.types.insert(*id, Type::Vector(*component, *count)); },
+                (OP_TYPE_MATRIX, [id, column, count]) => { module.types.insert(*id, Type::Matrix(*column, *count)); },
+                (OP_TYPE_ARRAY, [id, element, length]) => { module.types.insert(*id, Type::Array(*element, *length)); },
+                (OP_TYPE_STRUCT, [id, members @ ..]) => { module.types.insert(*id, Type::Struct(members.to_vec())); },
+                (OP_TYPE_POINTER, [id, _, pointee]) => { module.types.insert(*id, Type::Pointer(*pointee)); },
+                (OP_CONSTANT, [_, id, value, ..]) => { module.constants.insert(*id, *value); },
+                (OP_VARIABLE, [ty, id, storage, ..]) => module.variables.push((*id, *ty, *storage)),
+                (OP_DECORATE, [target, decoration, value, ..]) => { module.decorations.insert((*target, *decoration), *value); },
+                (OP_MEMBER_DECORATE, [target, member, decoration, value, ..]) => { module.member_decorations.insert((*target, *member, *decoration), *value); },
+                _ => { }
+            }
+        }
+
+        let mut interface = SpirvInterface::default();
+
+        for &(id, ty, storage) in &module.variables {
+            let pointee = match module.types.get(&ty) {
+                Some(Type::Pointer(pointee)) => *pointee,
+                _ => return Err(format!("Variable %{} isn't a pointer", id))
+            };
+
+            match storage {
+                STORAGE_INPUT | STORAGE_OUTPUT => {
+                    // Built-ins like gl_Position have no location
+                    if let Some(&location) = module.decorations.get(&(id, DECORATION_LOCATION)) {
+                        let range = (location, location + module.locations(pointee)?);
+
+                        if storage == STORAGE_INPUT { interface.inputs.push(range) } else { interface.outputs.push(range) }
+                    }
+                },
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let set = module.decorations.get(&(id, DECORATION_DESCRIPTOR_SET));
+                    let binding = module.decorations.get(&(id, DECORATION_BINDING));
+
+                    if let (Some(&set), Some(&binding)) = (set, binding) {
+                        interface.descriptors.push((set, binding));
+                    }
+                },
+                STORAGE_PUSH_CONSTANT => interface.push_constant_size = Some(module.size(pointee, None)?),
+                _ => { }
+            }
+        }
+
+        interface.inputs.sort_unstable();
+        interface.outputs.sort_unstable();
+        interface.descriptors.sort_unstable();
+
+        Ok(interface)
+    }
+
+    // Why a module with this interface can't stand in for one with `expected`'s. Vertex inputs and descriptors may
+    // go unused, but everything the module does use has to exist in the pipeline it's built into.
+    pub fn mismatch(&self, expected: &SpirvInterface) -> Option<String> {
+        if let Some(input) = self.inputs.iter().find(|input| !expected.inputs.contains(input)) {
+            return Some(format!("input locations {}..{} aren't provided", input.0, input.1));
+        }
+        if self.outputs != expected.outputs {
+            return Some(format!("outputs at locations {:?} don't match {:?}", self.outputs, expected.outputs));
+        }
+        if let Some((set, binding)) = self.descriptors.iter().find(|descriptor| !expected.descriptors.contains(descriptor)) {
+            return Some(format!("descriptor set {} binding {} isn't in the pipeline layout", set, binding));
+        }
+
+        match (self.push_constant_size, expected.push_constant_size) {
+            (Some(size), Some(expected)) if size > expected => Some(format!("push constants take {} bytes, {} are pushed", size, expected)),
+            (Some(_), None) => Some("push constants aren't pushed to this shader".to_string()),
+            _ => None
+        }
+    }
+}
+
+impl Module {
+    fn locations(&self, ty: u32) -> Result<u32, String> {
+        Ok(match self.types.get(&ty) {
+            Some(Type::Scalar(_)) | Some(Type::Vector(..)) => 1,
+            Some(Type::Matrix(_, columns)) => *columns,
+            Some(Type::Array(element, length)) => self.locations(*element)? * self.constant(*length)?,
+            Some(Type::Struct(members)) => members.iter().map(|&m| self.locations(m)).sum::<Result<u32, String>>()?,
+            _ => return Err(format!("Type %{} can't be an input or output", ty))
+        })
+    }
+
+    // `matrix_stride` is the decoration of the struct member holding `ty`
+    fn size(&self, ty: u32, matrix_stride: Option<u32>) -> Result<u32, String> {
+        Ok(match self.types.get(&ty) {
+            Some(Type::Scalar(bytes)) => *bytes,
+            Some(Type::Vector(component, count)) => self.size(*component, None)? * count,
+            Some(Type::Matrix(column, count)) => match matrix_stride {
+                Some(stride) => stride * count,
+                None => self.size(*column, None)? * count
+            },
+            Some(Type::Array(element, length)) => {
+                let stride = match self.decorations.get(&(ty, DECORATION_ARRAY_STRIDE)) {
+                    Some(&stride) => stride,
+                    None => self.size(*element, matrix_stride)?
+                };
+                stride * self.constant(*length)?
+            },
+            Some(Type::Struct(members)) => {
+                let mut end = 0;
+                for (index, &member) in members.iter().enumerate() {
+                    let index = index as u32;
+                    let offset = self.member_decorations.get(&(ty, index, DECORATION_OFFSET)).copied().unwrap_or(end);
+                    let stride = self.member_decorations.get(&(ty, index, DECORATION_MATRIX_STRIDE)).copied();
+                    end = end.max(offset + self.size(member, stride)?);
+                }
+                end
+            },
+            _ => return Err(format!("Type %{} has no size", ty))
+        })
+    }
+
+    fn constant(&self, id: u32) -> Result<u32, String> {
+        self.constants.get(&id).copied().ok_or_else(|| format!("Array length %{} isn't a constant", id))
+    }
+}
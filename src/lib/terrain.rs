@@ -0,0 +1,410 @@
+use hephaestus_macros::*;
+use std::fs;
+use std::io::Cursor;
+use cgmath::{ InnerSpace, Vector3 };
+
+use crate::{
+    buffer_objects::Vertex,
+    entity::{ Component, Transform },
+    mesh_data::{ MeshData, SubMesh },
+    logger::{ self, MessageEmitter }
+};
+
+#[derive(Clone)]
+pub enum HeightSource {
+    // Grayscale (or RGB, in which case the red channel is used) PNG; black is the lowest point
+    Heightmap(String),
+    // Fractal value noise sampled on a `resolution` x `resolution` grid
+    Noise { seed: u32, resolution: u32, frequency: f32, octaves: u32 }
+}
+
+#[derive(Clone)]
+pub struct TerrainSettings {
+    pub source: HeightSource,
+    // Width and depth of the whole terrain, centered on the origin
+    pub size: f32,
+    // Elevation of a full-intensity heightmap sample
+    pub height: f32,
+    // Number of grid cells along each side of a chunk
+    pub chunk_cells: u32,
+    // How many times the UVs repeat across the whole terrain
+    pub uv_tiling: f32,
+    // Chunks further than `lod_distance * 2^n` from the viewer are meshed at LOD n + 1
+    pub lod_distance: f32,
+    pub max_lod: u32
+}
+
+impl TerrainSettings {
+    pub fn new(source: HeightSource, size: f32, height: f32) -> Self {
+        Self {
+            source,
+            size,
+            height,
+            chunk_cells: 32,
+            uv_tiling: 1.0,
+            lod_distance: size / 4.0,
+            max_lod: 3
+        }
+    }
+}
+
+#[derive(Clone, Component)]
+pub struct Terrain {
+    id: usize,
+    pub settings: TerrainSettings,
+    samples_x: u32,
+    samples_z: u32,
+    heights: Vec<f32>,
+    // LOD of every chunk in the current mesh, row by row; empty until `update` first meshes the terrain
+    chunk_lods: Vec<u32>
+}
+
+impl Terrain {
+    pub fn new(settings: TerrainSettings) -> Self {
+        let (samples_x, samples_z, heights) = match &settings.source {
+            HeightSource::Heightmap(path) => {
+                Self::load_heightmap(path).unwrap_or_else(|e| {
+                    logger::log_error(&format!("Unable to load heightmap '{}': {}", path, e), MessageEmitter::World);
+                    (2, 2, vec![0.0; 4])
+                })
+            },
+            HeightSource::Noise { seed, resolution, frequency, octaves } => {
+                Self::generate_noise(*seed, (*resolution).max(2), *frequency, (*octaves).max(1))
+            }
+        };
+
+        Self {
+            id: 0,
+            settings,
+            samples_x,
+            samples_z,
+            heights,
+            chunk_lods: Vec::new()
+        }
+    }
+
+    fn load_heightmap(path: &str) -> Result<(u32, u32, Vec<f32>), String> {
+        let png_bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let mut decoder = png::Decoder::new(Cursor::new(png_bytes));
+        decoder.set_transformations(png::Transformations::EXPAND);
+
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut bytes = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut bytes).map_err(|e| e.to_string())?;
+
+        if info.width < 2 || info.height < 2 {
+            return Err("heightmap must be at least 2x2 pixels".into());
+        }
+
+        // 16-bit heightmaps keep their full precision, stored big-endian
+        let (sample_bytes, max) = match info.bit_depth {
+            png::BitDepth::Sixteen => (2, u16::MAX as f32),
+            _ => (1, u8::MAX as f32)
+        };
+        let pixel_bytes = info.color_type.samples() * sample_bytes;
+        let heights = bytes
+            .chunks(info.line_size)
+            .take(info.height as usize)
+            .flat_map(|line| line.chunks(pixel_bytes).take(info.width as usize))
+            .map(|pixel| match sample_bytes {
+                2 => u16::from_be_bytes([pixel[0], pixel[1]]) as f32 / max,
+                _ => pixel[0] as f32 / max
+            })
+            .collect();
+
+        Ok((info.width, info.height, heights))
+    }
+
+    fn generate_noise(seed: u32, resolution: u32, frequency: f32, octaves: u32) -> (u32, u32, Vec<f32>) {
+        let mut heights = Vec::with_capacity((resolution * resolution) as usize);
+
+        for z in 0..resolution {
+            for x in 0..resolution {
+                let (nx, nz) = (x as f32 / resolution as f32, z as f32 / resolution as f32);
+                heights.push(fractal_noise(seed, nx * frequency, nz * frequency, octaves));
+            }
+        }
+
+        (resolution, resolution, heights)
+    }
+
+    fn cells(&self) -> (u32, u32) {
+        (self.samples_x - 1, self.samples_z - 1)
+    }
+
+    fn spacing(&self) -> (f32, f32) {
+        let (cells_x, cells_z) = self.cells();
+        (self.settings.size / cells_x as f32, self.settings.size / cells_z as f32)
+    }
+
+    pub fn chunk_count(&self) -> (u32, u32) {
+        let (cells_x, cells_z) = self.cells();
+        let chunk = self.settings.chunk_cells.max(1);
+
+        (cells_x.div_ceil(chunk), cells_z.div_ceil(chunk))
+    }
+
+    fn sample(&self, x: u32, z: u32) -> f32 {
+        let x = x.min(self.samples_x - 1);
+        let z = z.min(self.samples_z - 1);
+
+        self.heights[(z * self.samples_x + x) as usize] * self.settings.height
+    }
+
+    fn sample_position(&self, x: u32, z: u32) -> Vector3<f32> {
+        let (dx, dz) = self.spacing();
+        let half = self.settings.size / 2.0;
+
+        Vector3::new(x as f32 * dx - half, self.sample(x, z), z as f32 * dz - half)
+    }
+
+    // Central differences over the full-resolution grid, so normals match across chunks and LODs
+    fn sample_normal(&self, x: u32, z: u32) -> Vector3<f32> {
+        let (dx, dz) = self.spacing();
+        let (left, right) = (x.saturating_sub(1), (x + 1).min(self.samples_x - 1));
+        let (back, front) = (z.saturating_sub(1), (z + 1).min(self.samples_z - 1));
+
+        let slope_x = (self.sample(right, z) - self.sample(left, z)) / ((right - left) as f32 * dx);
+        let slope_z = (self.sample(x, front) - self.sample(x, back)) / ((front - back) as f32 * dz);
+
+        Vector3::new(-slope_x, 1.0, -slope_z).normalize()
+    }
+
+    // Height of the terrain surface at a point in the terrain's local space, or `None` outside its bounds
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (dx, dz) = self.spacing();
+        let (cells_x, cells_z) = self.cells();
+        let half = self.settings.size / 2.0;
+
+        let fx = (x + half) / dx;
+        let fz = (z + half) / dz;
+
+        if !(0.0..=cells_x as f32).contains(&fx) || !(0.0..=cells_z as f32).contains(&fz) {
+            return None;
+        }
+
+        let ix = (fx.floor() as u32).min(cells_x - 1);
+        let iz = (fz.floor() as u32).min(cells_z - 1);
+        let (tx, tz) = (fx - ix as f32, fz - iz as f32);
+
+        let back = self.sample(ix, iz) * (1.0 - tx) + self.sample(ix + 1, iz) * tx;
+        let front = self.sample(ix, iz + 1) * (1.0 - tx) + self.sample(ix + 1, iz + 1) * tx;
+
+        Some(back * (1.0 - tz) + front * tz)
+    }
+
+    // Same as `height_at`, but in world space for a terrain placed with `transform` (rotation is ignored)
+    pub fn height_at_world(&self, transform: &Transform, x: f32, z: f32) -> Option<f32> {
+        let local_x = (x - transform.translation.x) / transform.scale.x;
+        let local_z = (z - transform.translation.z) / transform.scale.z;
+
+        self.height_at(local_x, local_z)
+            .map(|h| h * transform.scale.y + transform.translation.y)
+    }
+
+    pub fn lod_for_distance(&self, distance: f32) -> u32 {
+        let mut lod = 0;
+        let mut threshold = self.settings.lod_distance;
+
+        while distance > threshold && lod < self.settings.max_lod {
+            lod += 1;
+            threshold *= 2.0;
+        }
+
+        lod
+    }
+
+    // The LOD of every chunk, row by row. With a `viewer` (in the terrain's local space), each chunk's LOD is chosen
+    // from its distance to the viewer, otherwise full detail is used.
+    pub fn chunk_lods(&self, viewer: Option<Vector3<f32>>) -> Vec<u32> {
+        let (chunks_x, chunks_z) = self.chunk_count();
+
+        (0..chunks_z)
+            .flat_map(|cz| (0..chunks_x).map(move |cx| (cx, cz)))
+            .map(|(cx, cz)| viewer
+                .map(|v| self.lod_for_distance((self.chunk_center(cx, cz) - v).magnitude()))
+                .unwrap_or(0))
+            .collect()
+    }
+
+    // Re-selects chunk LODs for the camera, returning the remeshed terrain if any chunk changed level.
+    // Like `height_at_world`, the terrain's rotation is ignored.
+    pub fn update(&mut self, transform: &Transform, camera_position: Vector3<f32>) -> Option<MeshData> {
+        let offset = camera_position - transform.translation;
+        let viewer = Vector3::new(offset.x / transform.scale.x, offset.y / transform.scale.y, offset.z / transform.scale.z);
+        let lods = self.chunk_lods(Some(viewer));
+
+        if lods == self.chunk_lods {
+            return None;
+        }
+
+        let data = self.mesh_with_lods(&lods);
+        self.chunk_lods = lods;
+
+        Some(data)
+    }
+
+    // Builds every chunk as its own submesh, at the LODs `chunk_lods` would pick for `viewer`
+    pub fn mesh(&self, viewer: Option<Vector3<f32>>) -> MeshData {
+        self.mesh_with_lods(&self.chunk_lods(viewer))
+    }
+
+    fn mesh_with_lods(&self, lods: &[u32]) -> MeshData {
+        let mut data = MeshData::empty();
+        let (chunks_x, chunks_z) = self.chunk_count();
+
+        for cz in 0..chunks_z {
+            for cx in 0..chunks_x {
+                let lod = lods[(cz * chunks_x + cx) as usize];
+
+                let chunk = self.chunk_mesh(cx, cz, lod);
+                let base_vertex = data.vertices.len() as u32;
+                let first_index = data.indices.len();
+
+                data.vertices.extend(chunk.vertices);
+                data.indices.extend(chunk.indices.iter().map(|i| i + base_vertex));
+                data.submeshes.push(SubMesh {
+                    name: format!("chunk_{}_{}", cx, cz),
+                    first_index,
+                    index_count: data.indices.len() - first_index,
+                    material: None
+                });
+            }
+        }
+        data.generate_tangents();
+
+        data
+    }
+
+    fn chunk_center(&self, cx: u32, cz: u32) -> Vector3<f32> {
+        let (cells_x, cells_z) = self.cells();
+        let chunk = self.settings.chunk_cells.max(1);
+        let x = (cx * chunk + (chunk / 2)).min(cells_x);
+        let z = (cz * chunk + (chunk / 2)).min(cells_z);
+
+        self.sample_position(x, z)
+    }
+
+    pub fn chunk_mesh(&self, cx: u32, cz: u32, lod: u32) -> MeshData {
+        let mut data = MeshData::empty();
+        let (cells_x, cells_z) = self.cells();
+        let chunk = self.settings.chunk_cells.max(1);
+        let step = (1 << lod.min(31)).min(chunk) as usize;
+
+        // Sample indices along each axis, always including the chunk's far edge so neighbouring chunks line up
+        let axis = |start: u32, end: u32| {
+            let mut samples: Vec<u32> = (start..end).step_by(step).collect();
+            samples.push(end);
+            samples
+        };
+        let xs = axis((cx * chunk).min(cells_x), ((cx + 1) * chunk).min(cells_x));
+        let zs = axis((cz * chunk).min(cells_z), ((cz + 1) * chunk).min(cells_z));
+
+        for &z in &zs {
+            for &x in &xs {
+                data.vertices.push(Vertex {
+                    position: self.sample_position(x, z).into(),
+                    color: [1.0; 3],
+                    normal: self.sample_normal(x, z).into(),
+                    uv: [
+                        x as f32 / cells_x as f32 * self.settings.uv_tiling,
+                        z as f32 / cells_z as f32 * self.settings.uv_tiling
+                    ],
+                    tangent: [0.0; 4]
+                });
+            }
+        }
+
+        let row = xs.len() as u32;
+        for j in 0..zs.len() as u32 - 1 {
+            for i in 0..row - 1 {
+                let near_left = j * row + i;
+                let near_right = near_left + 1;
+                let far_left = near_left + row;
+                let far_right = far_left + 1;
+
+                data.indices.extend_from_slice(&[
+                    far_left, far_right, near_right,
+                    near_right, near_left, far_left
+                ]);
+            }
+        }
+
+        // Skirts hanging down from each edge hide the cracks between chunks of different LODs
+        let last_row = (zs.len() as u32 - 1) * row;
+        let edges: [(Vec<u32>, Vector3<f32>); 4] = [
+            ((0..row).collect(), -Vector3::unit_z()),
+            ((0..row).map(|i| last_row + i).collect(), Vector3::unit_z()),
+            ((0..zs.len() as u32).map(|j| j * row).collect(), -Vector3::unit_x()),
+            ((0..zs.len() as u32).map(|j| j * row + row - 1).collect(), Vector3::unit_x()),
+        ];
+
+        let depth = self.settings.height.abs().max(self.settings.size / 100.0) * 0.1;
+        for (edge, outward) in edges.iter() {
+            push_skirt(&mut data, edge, *outward, depth);
+        }
+
+        data
+    }
+}
+
+fn push_skirt(data: &mut MeshData, edge: &[u32], outward: Vector3<f32>, depth: f32) {
+    let lowered: Vec<u32> = edge.iter()
+        .map(|&i| {
+            let mut vertex = data.vertices[i as usize];
+            vertex.position[1] -= depth;
+            data.vertices.push(vertex);
+
+            (data.vertices.len() - 1) as u32
+        })
+        .collect();
+
+    for k in 0..edge.len() - 1 {
+        let (a, b) = (edge[k], edge[k + 1]);
+        let (lower_a, lower_b) = (lowered[k], lowered[k + 1]);
+
+        let p = |i: u32| Vector3::from(data.vertices[i as usize].position);
+        let facing = (p(lower_a) - p(a)).cross(p(b) - p(a)).dot(outward);
+
+        if facing >= 0.0 {
+            data.indices.extend_from_slice(&[a, lower_a, b, b, lower_a, lower_b]);
+        } else {
+            data.indices.extend_from_slice(&[a, b, lower_a, b, lower_b, lower_a]);
+        }
+    }
+}
+
+fn hash(seed: u32, x: i32, z: i32) -> f32 {
+    let mut h = seed
+        .wrapping_add((x as u32).wrapping_mul(0x27d4_eb2d))
+        .wrapping_add((z as u32).wrapping_mul(0x1656_67b1));
+    h = (h ^ (h >> 15)).wrapping_mul(0x85eb_ca6b);
+    h = (h ^ (h >> 13)).wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+
+    h as f32 / u32::MAX as f32
+}
+
+fn value_noise(seed: u32, x: f32, z: f32) -> f32 {
+    let (ix, iz) = (x.floor() as i32, z.floor() as i32);
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (tx, tz) = (smooth(x - ix as f32), smooth(z - iz as f32));
+
+    let back = hash(seed, ix, iz) * (1.0 - tx) + hash(seed, ix + 1, iz) * tx;
+    let front = hash(seed, ix, iz + 1) * (1.0 - tx) + hash(seed, ix + 1, iz + 1) * tx;
+
+    back * (1.0 - tz) + front * tz
+}
+
+fn fractal_noise(seed: u32, x: f32, z: f32, octaves: u32) -> f32 {
+    let (mut total, mut amplitude, mut frequency, mut max) = (0.0, 1.0, 1.0, 0.0);
+
+    for octave in 0..octaves {
+        total += value_noise(seed.wrapping_add(octave), x * frequency, z * frequency) * amplitude;
+        max += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    total / max
+}
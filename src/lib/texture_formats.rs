@@ -0,0 +1,397 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use vulkano::format::Format;
+use image::{ DynamicImage, GenericImageView, ImageFormat, codecs::hdr::HdrDecoder };
+use ddsfile::{ Dds, D3DFormat, DxgiFormat };
+
+// How 8-bit color channels are interpreted when sampled. Color textures are gamma encoded and
+// converted to linear by the sampler; data (normals, roughness, masks, ...) must be read as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    Linear
+}
+
+// Pixels ready to be copied into an image of `format`. Uncompressed images are always expanded to four channels,
+// since the shaders sample `.rgb`/`.rgba` regardless of what the file stored.
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    pub bytes: Vec<u8>
+}
+
+// Decodes PNG, JPEG, TGA, BMP and Radiance HDR through `image`, and block compressed (BCn) or uncompressed KTX2 and DDS files.
+// Only the base level of files carrying their own mip chain is read.
+pub fn decode(path: &str, color_space: ColorSpace) -> Result<DecodedImage, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Unable to read texture '{}': {}", path, e))?;
+
+    let extension = Path::new(path).extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let result = match extension.as_str() {
+        "ktx2" => decode_ktx2(&bytes, color_space),
+        "dds" => decode_dds(&bytes, color_space),
+        _ => decode_image(&bytes, ImageFormat::from_path(path).ok(), color_space)
+    };
+
+    result.map_err(|e| format!("Unable to decode texture '{}': {}", path, e))
+}
+
+fn decode_image(bytes: &[u8], format: Option<ImageFormat>, color_space: ColorSpace) -> Result<DecodedImage, String> {
+    // `image` would tonemap HDR files down to 8 bits, so they're decoded separately to keep their range.
+    // They're stored as half floats, since 32-bit float images aren't guaranteed to be filterable.
+    if format == Some(ImageFormat::Hdr) {
+        let decoder = HdrDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+        let metadata = decoder.metadata();
+        let pixels = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+
+        let bytes = pixels.iter()
+            .flat_map(|p| [p[0], p[1], p[2], 1.0])
+            .flat_map(|c| f32_to_f16(c).to_ne_bytes())
+            .collect();
+
+        return Ok(DecodedImage {
+            width: metadata.width,
+            height: metadata.height,
+            format: Format::R16G16B16A16Sfloat,
+            bytes
+        });
+    }
+
+    let image = match format {
+        Some(format) => image::load_from_memory_with_format(bytes, format),
+        None => image::load_from_memory(bytes)
+    }.map_err(|e| e.to_string())?;
+
+    let (width, height) = (image.width(), image.height());
+
+    // There is no 16-bit sRGB format, so only linear data keeps its precision. It's converted to half floats, which
+    // unlike 16-bit unorm images can always be filtered and blitted.
+    let is_16_bit = matches!(image,
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+    );
+
+    if is_16_bit && color_space == ColorSpace::Linear {
+        let bytes = image.to_rgba16().into_raw().into_iter()
+            .flat_map(|c| f32_to_f16(c as f32 / 65535.0).to_ne_bytes())
+            .collect();
+
+        return Ok(DecodedImage { width, height, format: Format::R16G16B16A16Sfloat, bytes });
+    }
+
+    Ok(DecodedImage {
+        width,
+        height,
+        format: with_color_space(Format::R8G8B8A8Srgb, color_space),
+        bytes: image.to_rgba8().into_raw()
+    })
+}
+
+fn decode_ktx2(bytes: &[u8], color_space: ColorSpace) -> Result<DecodedImage, String> {
+    use ktx2::Format as Ktx2Format;
+
+    let reader = ktx2::Reader::new(bytes).map_err(|e| format!("{:?}", e))?;
+    let header = reader.header();
+
+    if let Some(scheme) = header.supercompression_scheme {
+        return Err(format!("supercompression ({:?}) isn't supported", scheme));
+    }
+
+    let format = match header.format {
+        Some(Ktx2Format::R8G8B8A8_UNORM) => Format::R8G8B8A8Unorm,
+        Some(Ktx2Format::R8G8B8A8_SRGB) => Format::R8G8B8A8Srgb,
+        Some(Ktx2Format::B8G8R8A8_UNORM) => Format::B8G8R8A8Unorm,
+        Some(Ktx2Format::B8G8R8A8_SRGB) => Format::B8G8R8A8Srgb,
+        Some(Ktx2Format::R16G16B16A16_SFLOAT) => Format::R16G16B16A16Sfloat,
+        Some(Ktx2Format::R32G32B32A32_SFLOAT) => Format::R32G32B32A32Sfloat,
+        Some(Ktx2Format::BC1_RGB_UNORM_BLOCK) => Format::BC1_RGBUnormBlock,
+        Some(Ktx2Format::BC1_RGB_SRGB_BLOCK) => Format::BC1_RGBSrgbBlock,
+        Some(Ktx2Format::BC1_RGBA_UNORM_BLOCK) => Format::BC1_RGBAUnormBlock,
+        Some(Ktx2Format::BC1_RGBA_SRGB_BLOCK) => Format::BC1_RGBASrgbBlock,
+        Some(Ktx2Format::BC2_UNORM_BLOCK) => Format::BC2UnormBlock,
+        Some(Ktx2Format::BC2_SRGB_BLOCK) => Format::BC2SrgbBlock,
+        Some(Ktx2Format::BC3_UNORM_BLOCK) => Format::BC3UnormBlock,
+        Some(Ktx2Format::BC3_SRGB_BLOCK) => Format::BC3SrgbBlock,
+        Some(Ktx2Format::BC4_UNORM_BLOCK) => Format::BC4UnormBlock,
+        Some(Ktx2Format::BC4_SNORM_BLOCK) => Format::BC4SnormBlock,
+        Some(Ktx2Format::BC5_UNORM_BLOCK) => Format::BC5UnormBlock,
+        Some(Ktx2Format::BC5_SNORM_BLOCK) => Format::BC5SnormBlock,
+        Some(Ktx2Format::BC6H_UFLOAT_BLOCK) => Format::BC6HUfloatBlock,
+        Some(Ktx2Format::BC6H_SFLOAT_BLOCK) => Format::BC6HSfloatBlock,
+        Some(Ktx2Format::BC7_UNORM_BLOCK) => Format::BC7UnormBlock,
+        Some(Ktx2Format::BC7_SRGB_BLOCK) => Format::BC7SrgbBlock,
+        Some(format) => return Err(format!("format {:?} isn't supported", format)),
+        None => return Err("Basis Universal textures aren't supported".into())
+    };
+
+    let base_level = reader.levels().next().ok_or("the file has no image data")?;
+
+    base_image(base_level, header.pixel_width, header.pixel_height.max(1), format, color_space)
+}
+
+fn decode_dds(bytes: &[u8], color_space: ColorSpace) -> Result<DecodedImage, String> {
+    let dds = Dds::read(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+
+    let format = if let Some(format) = dds.get_dxgi_format() {
+        match format {
+            DxgiFormat::R8G8B8A8_UNorm => Format::R8G8B8A8Unorm,
+            DxgiFormat::R8G8B8A8_UNorm_sRGB => Format::R8G8B8A8Srgb,
+            DxgiFormat::B8G8R8A8_UNorm => Format::B8G8R8A8Unorm,
+            DxgiFormat::B8G8R8A8_UNorm_sRGB => Format::B8G8R8A8Srgb,
+            DxgiFormat::R16G16B16A16_Float => Format::R16G16B16A16Sfloat,
+            DxgiFormat::R32G32B32A32_Float => Format::R32G32B32A32Sfloat,
+            DxgiFormat::BC1_UNorm => Format::BC1_RGBAUnormBlock,
+            DxgiFormat::BC1_UNorm_sRGB => Format::BC1_RGBASrgbBlock,
+            DxgiFormat::BC2_UNorm => Format::BC2UnormBlock,
+            DxgiFormat::BC2_UNorm_sRGB => Format::BC2SrgbBlock,
+            DxgiFormat::BC3_UNorm => Format::BC3UnormBlock,
+            DxgiFormat::BC3_UNorm_sRGB => Format::BC3SrgbBlock,
+            DxgiFormat::BC4_UNorm => Format::BC4UnormBlock,
+            DxgiFormat::BC4_SNorm => Format::BC4SnormBlock,
+            DxgiFormat::BC5_UNorm => Format::BC5UnormBlock,
+            DxgiFormat::BC5_SNorm => Format::BC5SnormBlock,
+            DxgiFormat::BC6H_UF16 => Format::BC6HUfloatBlock,
+            DxgiFormat::BC6H_SF16 => Format::BC6HSfloatBlock,
+            DxgiFormat::BC7_UNorm => Format::BC7UnormBlock,
+            DxgiFormat::BC7_UNorm_sRGB => Format::BC7SrgbBlock,
+            format => return Err(format!("format {:?} isn't supported", format))
+        }
+    } else {
+        // Legacy headers don't say whether the data is sRGB, which is left to `color_space`
+        match dds.get_d3d_format() {
+            Some(D3DFormat::A8B8G8R8) => Format::R8G8B8A8Unorm,
+            Some(D3DFormat::A8R8G8B8) => Format::B8G8R8A8Unorm,
+            Some(D3DFormat::DXT1) => Format::BC1_RGBAUnormBlock,
+            Some(D3DFormat::DXT2) | Some(D3DFormat::DXT3) => Format::BC2UnormBlock,
+            Some(D3DFormat::DXT4) | Some(D3DFormat::DXT5) => Format::BC3UnormBlock,
+            Some(format) => return Err(format!("format {:?} isn't supported", format)),
+            None => return Err("unknown pixel format".into())
+        }
+    };
+
+    let data = dds.get_data(0).map_err(|e| e.to_string())?;
+
+    base_image(data, dds.get_width(), dds.get_height(), format, color_space)
+}
+
+// The first mip level of `data`, which may be followed by the rest of the chain
+fn base_image(data: &[u8], width: u32, height: u32, format: Format, color_space: ColorSpace) -> Result<DecodedImage, String> {
+    let size = level_size(format, width, height);
+
+    if data.len() < size {
+        return Err(format!("expected {} bytes of image data, found {}", size, data.len()));
+    }
+
+    Ok(DecodedImage {
+        width,
+        height,
+        format: with_color_space(format, color_space),
+        bytes: data[..size].to_vec()
+    })
+}
+
+// Size in bytes of a `width` x `height` image. Block compressed formats store 4x4 texel blocks.
+pub fn level_size(format: Format, width: u32, height: u32) -> usize {
+    let blocks = |block_size: usize| (width as usize).div_ceil(4) * (height as usize).div_ceil(4) * block_size;
+    let texels = (width * height) as usize;
+
+    match format {
+        Format::BC1_RGBUnormBlock | Format::BC1_RGBSrgbBlock | Format::BC1_RGBAUnormBlock | Format::BC1_RGBASrgbBlock
+        | Format::BC4UnormBlock | Format::BC4SnormBlock => blocks(8),
+        Format::BC2UnormBlock | Format::BC2SrgbBlock | Format::BC3UnormBlock | Format::BC3SrgbBlock
+        | Format::BC5UnormBlock | Format::BC5SnormBlock | Format::BC6HUfloatBlock | Format::BC6HSfloatBlock
+        | Format::BC7UnormBlock | Format::BC7SrgbBlock => blocks(16),
+        Format::R16G16B16A16Unorm | Format::R16G16B16A16Sfloat => texels * 8,
+        Format::R32G32B32A32Sfloat => texels * 16,
+        _ => texels * 4
+    }
+}
+
+// Swaps a format for its sRGB or UNORM counterpart. Formats without one (BC4-6, floats, ...) are only ever linear.
+pub fn with_color_space(format: Format, color_space: ColorSpace) -> Format {
+    const PAIRS: [(Format, Format); 7] = [
+        (Format::R8G8B8A8Srgb, Format::R8G8B8A8Unorm),
+        (Format::B8G8R8A8Srgb, Format::B8G8R8A8Unorm),
+        (Format::BC1_RGBSrgbBlock, Format::BC1_RGBUnormBlock),
+        (Format::BC1_RGBASrgbBlock, Format::BC1_RGBAUnormBlock),
+        (Format::BC2SrgbBlock, Format::BC2UnormBlock),
+        (Format::BC3SrgbBlock, Format::BC3UnormBlock),
+        (Format::BC7SrgbBlock, Format::BC7UnormBlock)
+    ];
+
+    for (srgb, linear) in PAIRS {
+        if format == srgb || format == linear {
+            return match color_space {
+                ColorSpace::Srgb => srgb,
+                ColorSpace::Linear => linear
+            };
+        }
+    }
+
+    format
+}
+
+// Formats whose mip chain can be generated by blitting on the GPU
+pub fn can_blit(format: Format) -> bool {
+    matches!(format,
+        Format::R8G8B8A8Srgb | Format::R8G8B8A8Unorm | Format::B8G8R8A8Srgb | Format::B8G8R8A8Unorm
+        | Format::R16G16B16A16Sfloat
+    )
+}
+
+impl DecodedImage {
+    // Every level below this one down to 1x1, downsampled on the CPU with a box filter. The GPU generates mip chains
+    // when textures are uploaded; this is for baking them offline. Returns nothing for formats it can't filter.
+    pub fn mip_chain(&self) -> Vec<DecodedImage> {
+        let mut levels: Vec<DecodedImage> = Vec::new();
+
+        while let Some(level) = levels.last().unwrap_or(self).downsample() {
+            levels.push(level);
+        }
+
+        levels
+    }
+
+    // Halves each dimension (down to 1), averaging the texels it covers. sRGB texels are averaged in linear space,
+    // so the result doesn't darken.
+    pub fn downsample(&self) -> Option<DecodedImage> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+
+        let is_srgb = match self.format {
+            Format::R8G8B8A8Srgb | Format::B8G8R8A8Srgb => true,
+            Format::R8G8B8A8Unorm | Format::B8G8R8A8Unorm => false,
+            _ => return None
+        };
+
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut bytes = Vec::with_capacity((width * height * 4) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                // Odd dimensions leave the last row or column out, like a GPU blit would
+                let sources: Vec<usize> = [(0, 0), (1, 0), (0, 1), (1, 1)].iter()
+                    .map(|(dx, dy)| ((x * 2 + dx).min(self.width - 1), (y * 2 + dy).min(self.height - 1)))
+                    .map(|(sx, sy)| ((sy * self.width + sx) * 4) as usize)
+                    .collect();
+
+                for channel in 0..4 {
+                    let texels = sources.iter().map(|&i| self.bytes[i + channel]);
+
+                    // Alpha is always linear
+                    bytes.push(if is_srgb && channel != 3 {
+                        linear_to_srgb(texels.map(srgb_to_linear).sum::<f32>() / 4.0)
+                    } else {
+                        (texels.map(|c| c as f32).sum::<f32>() / 4.0).round() as u8
+                    });
+                }
+            }
+        }
+
+        Some(DecodedImage { width, height, format: self.format, bytes })
+    }
+
+    // Linear RGB of every texel, row by row. Returns nothing for block compressed formats.
+    pub fn linear_rgb(&self) -> Option<Vec<[f32; 3]>> {
+        let texels = match self.format {
+            Format::R8G8B8A8Srgb | Format::R8G8B8A8Unorm | Format::B8G8R8A8Srgb | Format::B8G8R8A8Unorm => {
+                let is_srgb = matches!(self.format, Format::R8G8B8A8Srgb | Format::B8G8R8A8Srgb);
+                let is_bgr = matches!(self.format, Format::B8G8R8A8Srgb | Format::B8G8R8A8Unorm);
+                let to_linear = |c: u8| if is_srgb { srgb_to_linear(c) } else { c as f32 / 255.0 };
+
+                self.bytes.chunks_exact(4)
+                    .map(|t| if is_bgr { [t[2], t[1], t[0]] } else { [t[0], t[1], t[2]] })
+                    .map(|t| t.map(to_linear))
+                    .collect()
+            },
+            Format::R16G16B16A16Unorm => {
+                self.bytes.chunks_exact(8)
+                    .map(|t| {
+                        let channel = |i: usize| u16::from_ne_bytes([t[i * 2], t[i * 2 + 1]]) as f32 / 65535.0;
+                        [channel(0), channel(1), channel(2)]
+                    })
+                    .collect()
+            },
+            Format::R16G16B16A16Sfloat => {
+                self.bytes.chunks_exact(8)
+                    .map(|t| {
+                        let channel = |i: usize| f16_to_f32(u16::from_ne_bytes([t[i * 2], t[i * 2 + 1]]));
+                        [channel(0), channel(1), channel(2)]
+                    })
+                    .collect()
+            },
+            Format::R32G32B32A32Sfloat => {
+                self.bytes.chunks_exact(16)
+                    .map(|t| {
+                        let channel = |i: usize| f32::from_ne_bytes([t[i * 4], t[i * 4 + 1], t[i * 4 + 2], t[i * 4 + 3]]);
+                        [channel(0), channel(1), channel(2)]
+                    })
+                    .collect()
+            },
+            _ => return None
+        };
+
+        Some(texels)
+    }
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+
+    (c * 255.0).round() as u8
+}
+
+// Rounds to the nearest half float. Values beyond its range are clamped to the largest finite one, so bright HDR
+// texels don't turn into infinities when filtered.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+
+    if exponent <= 0 {
+        // Too small even for a subnormal
+        if exponent < -10 {
+            return sign;
+        }
+
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let rounded = (mantissa >> shift) + ((mantissa >> (shift - 1)) & 1);
+
+        return sign | rounded as u16;
+    }
+
+    // Rounding may carry into the exponent, which is still the nearest half float
+    let rounded = ((exponent as u32) << 10 | mantissa >> 13) + ((mantissa >> 12) & 1);
+
+    sign | rounded.min(0x7bff) as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+
+    let magnitude = match exponent {
+        0 => mantissa as f32 / (1 << 24) as f32,
+        0x1f if mantissa == 0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => f32::from_bits((exponent + 127 - 15) << 23 | mantissa << 13)
+    };
+
+    if half & 0x8000 != 0 { -magnitude } else { magnitude }
+}
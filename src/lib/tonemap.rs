@@ -0,0 +1,204 @@
+// Bins of the luminance histogram auto exposure is measured with, one per invocation of a 16x16 workgroup
+pub const HISTOGRAM_BINS: usize = 256;
+
+// Swapchains with an sRGB format encode this gamma themselves
+const SRGB_GAMMA: f32 = 2.2;
+
+// Curves mapping exposed HDR light into the 0-1 range displays show
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneOperator {
+    // Narkowicz's fit of the ACES filmic curve
+    Aces,
+    // x / (1 + x), which never quite reaches white
+    Reinhard,
+    // Hable's filmic curve from Uncharted 2, with its white point at 11.2. Brighter light saturates.
+    Uncharted2
+}
+
+impl ToneOperator {
+    // The same curves as tonemap_frag.glsl, for tests and offline use. Changes to either have to be copied to the other
+    // by hand, since tests only see this copy.
+    pub fn apply(&self, color: [f32; 3]) -> [f32; 3] {
+        match self {
+            ToneOperator::Aces => color.map(|x| (x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)),
+            ToneOperator::Reinhard => color.map(|x| x / (1.0 + x)),
+            ToneOperator::Uncharted2 => color.map(|x| (hable(2.0 * x) / hable(11.2)).clamp(0.0, 1.0))
+        }
+    }
+
+    // How the tonemapping shader tells the curves apart
+    pub fn index(&self) -> i32 {
+        match self {
+            ToneOperator::Aces => 0,
+            ToneOperator::Reinhard => 1,
+            ToneOperator::Uncharted2 => 2
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exposure {
+    // Light is multiplied by this before the curve
+    Manual(f32),
+    // Adapts to the average brightness of the previous frames
+    Auto(AutoExposure)
+}
+
+// Exposure measured from a histogram of the frame's log luminance. Averaging the histogram, rather than every pixel,
+// lets the darkest and brightest pixels be left out, so a small bright light or a dark corner doesn't swing it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoExposure {
+    // Range the histogram covers. Darker pixels (like the void around a scene) aren't counted; brighter ones land
+    // in the last bin.
+    pub min_luminance: f32,
+    pub max_luminance: f32,
+    // Fractions of the counted pixels left out of the average at the dark and bright ends
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+    // The average luminance is exposed to this, middle grey by default
+    pub key: f32,
+    // How quickly the exposure follows the scene, per second. 0 freezes it.
+    pub speed: f32,
+    pub min_exposure: f32,
+    pub max_exposure: f32
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            min_luminance: 1.0 / 256.0,
+            max_luminance: 256.0,
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+            key: 0.18,
+            speed: 1.5,
+            min_exposure: 1.0 / 16.0,
+            max_exposure: 16.0
+        }
+    }
+}
+
+impl AutoExposure {
+    // The log2 of `min_luminance` and the log2 range up to `max_luminance`, as the histogram shader takes them
+    pub fn log_luminance_range(&self) -> (f32, f32) {
+        let min = self.min_luminance.max(1e-6).log2();
+        let max = self.max_luminance.max(self.min_luminance * 2.0).log2();
+
+        (min, max - min)
+    }
+
+    // The histogram bin a pixel of `luminance` is counted in, like histogram_comp.glsl does. Bin 0 holds the pixels
+    // too dark to count. Kept in step with the shader by hand.
+    pub fn bin(&self, luminance: f32) -> usize {
+        let (min, range) = self.log_luminance_range();
+
+        if luminance < min.exp2() {
+            return 0;
+        }
+
+        let t = ((luminance.log2() - min) / range).clamp(0.0, 1.0);
+        ((t * (HISTOGRAM_BINS - 1) as f32) as usize).min(HISTOGRAM_BINS - 2) + 1
+    }
+
+    // Average luminance of the counted pixels between the percentiles (geometric, since the bins are logarithmic).
+    // `None` when no pixel was counted.
+    pub fn average_luminance(&self, histogram: &[u32]) -> Option<f32> {
+        let (min, range) = self.log_luminance_range();
+        let counted: u64 = histogram.iter().skip(1).map(|&c| c as u64).sum();
+
+        if counted == 0 {
+            return None;
+        }
+
+        let low = counted as f32 * self.low_percentile.clamp(0.0, 1.0);
+        let high = counted as f32 * self.high_percentile.clamp(self.low_percentile.clamp(0.0, 1.0), 1.0);
+
+        let mut below = 0.0;
+        let mut weight = 0.0;
+        let mut log_sum = 0.0;
+
+        for (bin, &count) in histogram.iter().enumerate().skip(1) {
+            // The part of the bin's pixels that falls between the percentiles
+            let count = count as f32;
+            let kept = (below + count).min(high) - below.max(low);
+            below += count;
+
+            if kept > 0.0 {
+                let t = (bin as f32 - 0.5) / (HISTOGRAM_BINS - 1) as f32;
+                log_sum += (min + t * range) * kept;
+                weight += kept;
+            }
+        }
+
+        // Percentiles that close together keep nothing, so the median bin stands in
+        if weight == 0.0 {
+            let t = (self.bin_at(histogram, counted as f32 * self.low_percentile.clamp(0.0, 1.0)) as f32 - 0.5) / (HISTOGRAM_BINS - 1) as f32;
+            return Some((min + t * range).exp2());
+        }
+
+        Some((log_sum / weight).exp2())
+    }
+
+    // The exposure bringing `luminance` to the key
+    pub fn target_exposure(&self, luminance: f32) -> f32 {
+        (self.key / luminance.max(1e-6)).clamp(self.min_exposure, self.max_exposure)
+    }
+
+    // Moves `current` towards `target` over `delta_time` seconds. Eyes adapt to ratios of brightness, so it moves
+    // evenly in stops rather than in linear steps.
+    pub fn adapt(&self, current: f32, target: f32, delta_time: f32) -> f32 {
+        let blend = 1.0 - (-self.speed.max(0.0) * delta_time.max(0.0)).exp();
+        let exposure = current.max(1e-6).log2() + (target.max(1e-6).log2() - current.max(1e-6).log2()) * blend;
+
+        exposure.exp2().clamp(self.min_exposure, self.max_exposure)
+    }
+
+    // The first counted bin reaching `position` pixels
+    fn bin_at(&self, histogram: &[u32], position: f32) -> usize {
+        let mut below = 0.0;
+
+        for (bin, &count) in histogram.iter().enumerate().skip(1) {
+            below += count as f32;
+
+            if below >= position && count > 0 {
+                return bin;
+            }
+        }
+
+        histogram.len().max(2) - 1
+    }
+}
+
+// How the renderer turns the lit HDR image into the final one (`World::tonemapping`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tonemapping {
+    pub operator: ToneOperator,
+    pub exposure: Exposure,
+    // Of the display. sRGB targets already encode 2.2, so only the difference from it is applied to them.
+    pub gamma: f32
+}
+
+impl Default for Tonemapping {
+    fn default() -> Self {
+        Self {
+            operator: ToneOperator::Aces,
+            exposure: Exposure::Auto(AutoExposure::default()),
+            gamma: SRGB_GAMMA
+        }
+    }
+}
+
+impl Tonemapping {
+    // The power the tonemapped colors are raised to, for a target that does or doesn't encode sRGB itself
+    pub fn gamma_power(&self, srgb_target: bool) -> f32 {
+        let gamma = self.gamma.max(1e-3);
+
+        if srgb_target { SRGB_GAMMA / gamma } else { 1.0 / gamma }
+    }
+}
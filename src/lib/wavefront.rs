@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use crate::{
+    buffer_objects::Vertex,
+    mesh_data::{ MeshData, MeshMaterial, SubMesh },
+    logger::{ self, MessageEmitter }
+};
+
+// Vertices are deduplicated on (position, uv, normal, submesh), so two submeshes with
+// different diffuse colors never share a vertex.
+type VertexKey = (usize, Option<usize>, Option<usize>, usize);
+
+struct ObjReader {
+    directory: PathBuf,
+    positions: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<[f32; 3]>,
+    materials: HashMap<String, MeshMaterial>,
+    object: String,
+    material: Option<String>,
+    vertex_lookup: HashMap<VertexKey, usize>,
+    data: MeshData
+}
+
+pub fn load_obj(path: &str) -> Result<MeshData, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("Unable to read '{}': {}", path, e))?;
+    let directory = Path::new(path).parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+    parse_obj(&source, &directory).map_err(|e| format!("Unable to load object data from '{}': {}", path, e))
+}
+
+pub fn parse_obj(source: &str, directory: &Path) -> Result<MeshData, String> {
+    let mut reader = ObjReader {
+        directory: directory.to_path_buf(),
+        positions: Vec::new(),
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        materials: HashMap::new(),
+        object: "default".into(),
+        material: None,
+        vertex_lookup: HashMap::new(),
+        data: MeshData::empty()
+    };
+
+    for (line_number, line) in source.lines().enumerate() {
+        reader.parse_line(line)
+            .map_err(|e| format!("line {}: {}", line_number + 1, e))?;
+    }
+
+    reader.finish_submesh();
+
+    Ok(reader.data)
+}
+
+pub fn parse_mtl(source: &str, directory: &Path) -> HashMap<String, MeshMaterial> {
+    let mut materials = HashMap::new();
+    let mut current: Option<MeshMaterial> = None;
+
+    for line in source.lines() {
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("newmtl") => {
+                if let Some(material) = current.take() {
+                    materials.insert(material.name.clone(), material);
+                }
+
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                current = Some(MeshMaterial::named(&name));
+            },
+            Some("Kd") => {
+                if let Some(material) = current.as_mut() {
+                    let values: Vec<f32> = tokens.filter_map(|t| t.parse().ok()).collect();
+
+                    if values.len() >= 3 {
+                        material.diffuse_color = [values[0], values[1], values[2]];
+                    }
+                }
+            },
+            Some("map_Kd") => {
+                // Texture options (-o, -s, -bm, ...) come before the file name, which is always last
+                if let (Some(material), Some(file)) = (current.as_mut(), tokens.last()) {
+                    material.diffuse_texture = Some(directory.join(file).to_string_lossy().into_owned());
+                }
+            },
+            _ => { }
+        }
+    }
+
+    if let Some(material) = current.take() {
+        materials.insert(material.name.clone(), material);
+    }
+
+    materials
+}
+
+impl ObjReader {
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let v = parse_floats::<3>(tokens)?;
+                self.positions.push(v);
+            },
+            Some("vt") => {
+                let mut values: Vec<f32> = tokens.map(|t| t.parse::<f32>().map_err(|e| e.to_string())).collect::<Result<_, _>>()?;
+                values.resize(2, 0.0);
+                self.uvs.push([values[0], values[1]]);
+            },
+            Some("vn") => {
+                let n = parse_floats::<3>(tokens)?;
+                self.normals.push(n);
+            },
+            Some("f") => {
+                let corners = tokens
+                    .map(|t| self.parse_corner(t))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if corners.len() < 3 {
+                    return Err("face with fewer than 3 vertices".into());
+                }
+
+                // Triangulate polygons as a fan around the first corner
+                for i in 1..corners.len() - 1 {
+                    for &corner in &[corners[0], corners[i], corners[i + 1]] {
+                        let index = self.vertex_index(corner);
+                        self.data.indices.push(index as u32);
+                    }
+                }
+            },
+            Some("o") | Some("g") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+
+                self.finish_submesh();
+                self.object = if name.is_empty() { "default".into() } else { name };
+            },
+            Some("usemtl") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+
+                self.finish_submesh();
+                self.material = Some(name);
+            },
+            Some("mtllib") => {
+                for file in tokens {
+                    let path = self.directory.join(file);
+
+                    match fs::read_to_string(&path) {
+                        Ok(source) => {
+                            let directory = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+                            self.materials.extend(parse_mtl(&source, &directory));
+                        },
+                        Err(_) => {
+                            logger::log_warning(&format!("Unable to read material library '{}'", path.display()), MessageEmitter::World);
+                        }
+                    }
+                }
+            },
+            _ => { }
+        }
+
+        Ok(())
+    }
+
+    fn parse_corner(&self, token: &str) -> Result<(usize, Option<usize>, Option<usize>), String> {
+        let mut parts = token.split('/');
+
+        let position = parts.next()
+            .ok_or_else(|| format!("malformed face vertex '{}'", token))
+            .and_then(|p| resolve_index(p, self.positions.len()))?;
+
+        let uv = match parts.next() {
+            Some(t) if !t.is_empty() => Some(resolve_index(t, self.uvs.len())?),
+            _ => None
+        };
+
+        let normal = match parts.next() {
+            Some(n) if !n.is_empty() => Some(resolve_index(n, self.normals.len())?),
+            _ => None
+        };
+
+        Ok((position, uv, normal))
+    }
+
+    fn vertex_index(&mut self, (position, uv, normal): (usize, Option<usize>, Option<usize>)) -> usize {
+        let submesh = self.data.submeshes.len();
+        let key = (position, uv, normal, submesh);
+
+        if let Some(&index) = self.vertex_lookup.get(&key) {
+            return index;
+        }
+
+        let color = self.current_material()
+            .map(|m| m.diffuse_color)
+            .unwrap_or([1.0; 3]);
+
+        self.data.vertices.push(Vertex {
+            position: self.positions[position],
+            color,
+            normal: normal.map(|n| self.normals[n]).unwrap_or_default(),
+            uv: uv.map(|t| self.uvs[t]).unwrap_or_default(),
+            tangent: [0.0; 4]
+        });
+
+        let index = self.data.vertices.len() - 1;
+        self.vertex_lookup.insert(key, index);
+
+        index
+    }
+
+    fn current_material(&self) -> Option<&MeshMaterial> {
+        self.material.as_ref().and_then(|name| self.materials.get(name))
+    }
+
+    fn finish_submesh(&mut self) {
+        let first_index = self.data.submeshes.last()
+            .map(|s| s.first_index + s.index_count)
+            .unwrap_or(0);
+        let index_count = self.data.indices.len() - first_index;
+
+        if index_count == 0 {
+            return;
+        }
+
+        let material = self.current_material().cloned()
+            .or_else(|| self.material.as_ref().map(|name| MeshMaterial::named(name)));
+
+        self.data.submeshes.push(SubMesh {
+            name: self.object.clone(),
+            first_index,
+            index_count,
+            material
+        });
+    }
+}
+
+fn resolve_index(token: &str, count: usize) -> Result<usize, String> {
+    let index: i64 = token.parse().map_err(|_| format!("invalid index '{}'", token))?;
+
+    // OBJ indices are 1-based, negative indices count back from the most recent element
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+
+    if resolved < 0 || resolved >= count as i64 {
+        Err(format!("index {} out of range", index))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
+fn parse_floats<'a, const N: usize>(tokens: impl Iterator<Item = &'a str>) -> Result<[f32; N], String> {
+    let mut values = [0.0; N];
+    let mut count = 0;
+
+    for (value, token) in values.iter_mut().zip(tokens) {
+        *value = token.parse().map_err(|_| format!("invalid number '{}'", token))?;
+        count += 1;
+    }
+
+    if count < N {
+        Err(format!("expected {} values, found {}", N, count))
+    } else {
+        Ok(values)
+    }
+}
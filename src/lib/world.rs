@@ -1,16 +1,68 @@
 use std::collections::HashMap;
+use std::hash::{ Hash, Hasher };
+use cgmath::Deg;
 
 use crate::{
-    light::DirectionalLight,
+    light::AmbientLight,
+    tonemap::Tonemapping,
     logger::{ self, MessageEmitter },
-    entity::{ Component, EntityBuilder }
+    entity::{ Component, EntityBuilder, Material, MaterialData, Mesh, NormalMap, SamplerDesc, Static, Texture, Transform },
+    lod::LodGroup,
+    mesh_data::MeshData,
+    mesh_export::PlyEncoding
 };
 
+// Static meshes can share a draw call when everything bound alongside their geometry is the same
+#[derive(PartialEq)]
+struct BatchKey {
+    material: Option<MaterialData>,
+    texture: Option<String>,
+    normal_map: Option<String>,
+    // Whether the mesh casts and receives shadows
+    shadows: (bool, bool)
+}
+
+// Entities can be drawn by one instanced draw when they share geometry and everything bound alongside it,
+// identified by asset ids and sampler settings
+#[derive(PartialEq)]
+struct InstanceKey {
+    mesh: usize,
+    sampler: SamplerDesc,
+    texture: Option<(usize, SamplerDesc)>,
+    normal_map: Option<(usize, SamplerDesc)>,
+    material_textures: Vec<Option<(usize, SamplerDesc)>>,
+    shadows: (bool, bool)
+}
+
+// Samplers hold floats, so keys hash their ids alone and equal keys still hash the same. Anisotropies are never NaN.
+impl Eq for InstanceKey { }
+
+impl Hash for InstanceKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mesh.hash(state);
+        self.texture.map(|(id, _)| id).hash(state);
+        self.normal_map.map(|(id, _)| id).hash(state);
+        self.material_textures.iter().map(|t| t.map(|(id, _)| id)).for_each(|id| id.hash(state));
+        self.shadows.hash(state);
+    }
+}
+
+struct Batch {
+    key: BatchKey,
+    sources: Vec<usize>,
+    data: MeshData,
+    textures: HashMap<String, Texture>,
+    material: Option<Material>,
+    texture: Option<Texture>,
+    normal_map: Option<NormalMap>
+}
+
 #[derive(Clone)]
 pub struct World {
     pub entities: HashMap<usize, Vec<Box<dyn Component>>>,
-    pub lights: Vec<DirectionalLight>,
     pub void_color: [f32; 4],
+    pub ambient_light: AmbientLight,
+    pub tonemapping: Tonemapping,
     next_id: usize
 }
 
@@ -20,8 +72,9 @@ impl World {
         
         World {
             entities: HashMap::new(),
-            lights: Vec::new(),
             void_color: [0.01, 0.01, 0.01, 1.0],
+            ambient_light: AmbientLight::default(),
+            tonemapping: Tonemapping::default(),
             next_id: 0
         }
     }
@@ -94,9 +147,179 @@ impl World {
         }
     }
 
-    pub fn add_light(&mut self, light: DirectionalLight) {
-        logger::log_debug("Adding directional light to world.", MessageEmitter::World);
-        
-        self.lights.push(light);
+    // Merges the meshes of `Static` entities that share a material, texture and normal map into one mesh per group,
+    // with their transforms baked in. The merged meshes are added as new entities and the originals lose their
+    // `Mesh`, keeping everything else (logic, etc). Returns the number of batches created.
+    pub fn batch_static_meshes(&mut self) -> usize {
+        let mut ids = self.get_all_ids().unwrap_or_default();
+        ids.sort_unstable();
+
+        let mut batches: Vec<Batch> = Vec::new();
+
+        for id in ids {
+            // Levels of detail swap the mesh data every frame, which a merged mesh can't follow
+            if self.get_component_by_id::<Static>(id).is_none() || self.get_component_by_id::<LodGroup>(id).is_some() {
+                continue;
+            }
+
+            let (mesh, transform) = match (self.get_component_by_id::<Mesh>(id), self.get_component_by_id::<Transform>(id)) {
+                (Some(mesh), Some(transform)) => (mesh, transform),
+                _ => continue
+            };
+
+            // Merging a placeholder would bake it in for good, so meshes still loading are drawn on their own
+            if !mesh.data.is_loaded() {
+                continue;
+            }
+
+            let material = self.get_component_by_id::<Material>(id);
+            let texture = self.get_component_by_id::<Texture>(id);
+            let normal_map = self.get_component_by_id::<NormalMap>(id);
+
+            let key = BatchKey {
+                material: material.map(|m| m.data.read().clone()),
+                texture: texture.map(|t| t.path.clone()),
+                normal_map: normal_map.map(|n| n.texture.path.clone()),
+                shadows: (mesh.cast_shadows, mesh.receive_shadows)
+            };
+
+            let mut data = mesh.data.read().clone();
+            data.transform(transform.model_matrix());
+
+            let batch = match batches.iter().position(|b| b.key == key) {
+                Some(i) => &mut batches[i],
+                None => {
+                    batches.push(Batch {
+                        key,
+                        sources: Vec::new(),
+                        data: MeshData::empty(),
+                        textures: HashMap::new(),
+                        material: material.cloned(),
+                        texture: texture.cloned(),
+                        normal_map: normal_map.cloned()
+                    });
+
+                    batches.last_mut().unwrap()
+                }
+            };
+
+            batch.sources.push(id);
+            batch.data.append(&data);
+            batch.textures.extend(mesh.textures.clone());
+        }
+
+        // A batch of one would only trade the entity's transform for a baked copy of its mesh
+        batches.retain(|b| b.sources.len() > 1);
+
+        let merged: usize = batches.iter().map(|b| b.sources.len()).sum();
+        let count = batches.len();
+
+        for mut batch in batches {
+            for &id in &batch.sources {
+                if let Some(entity) = self.get_entity_mut(id) {
+                    entity.retain(|c| !c.is::<Mesh>());
+                }
+            }
+
+            batch.data.merge_submeshes();
+
+            let mut entity = EntityBuilder::new()
+                .transform([0.0; 3], [1.0; 3], [Deg(0.0); 3])
+                .static_mesh();
+
+            let mut mesh = Mesh::batched(batch.data, batch.textures);
+            mesh.cast_shadows = batch.key.shadows.0;
+            mesh.receive_shadows = batch.key.shadows.1;
+            entity.components.push(Box::new(mesh));
+
+            if let Some(material) = batch.material {
+                entity.components.push(Box::new(material));
+            }
+            if let Some(texture) = batch.texture {
+                entity.components.push(Box::new(texture));
+            }
+            if let Some(normal_map) = batch.normal_map {
+                entity.components.push(Box::new(normal_map));
+            }
+
+            self.add_entity(entity);
+        }
+
+        logger::log_debug(&format!("Merged {} static meshes into {} batches.", merged, count), MessageEmitter::World);
+
+        count
+    }
+
+    // Groups the ids of entities with a transform and a mesh by the draw they can share: same mesh data, textures,
+    // samplers and shadow flags. Material factors and transforms vary per instance. Groups are ordered by their lowest id.
+    pub fn instance_groups(&self) -> Vec<Vec<usize>> {
+        let mut ids = self.get_all_ids().unwrap_or_default();
+        ids.sort_unstable();
+
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut lookup: HashMap<InstanceKey, usize> = HashMap::new();
+
+        for id in ids {
+            let mesh = match (self.get_component_by_id::<Mesh>(id), self.get_component_by_id::<Transform>(id)) {
+                (Some(mesh), Some(_)) => mesh,
+                _ => continue
+            };
+
+            let key = InstanceKey {
+                mesh: mesh.data.id(),
+                sampler: mesh.sampler,
+                texture: self.get_component_by_id::<Texture>(id).map(|t| (t.data.id(), t.sampler)),
+                normal_map: self.get_component_by_id::<NormalMap>(id).map(|n| (n.texture.data.id(), n.texture.sampler)),
+                material_textures: self.get_component_by_id::<Material>(id).map_or_else(Vec::new, |m| m.textures.key()),
+                shadows: (mesh.cast_shadows, mesh.receive_shadows)
+            };
+
+            match lookup.get(&key) {
+                Some(&group) => groups[group].push(id),
+                None => {
+                    lookup.insert(key, groups.len());
+                    groups.push(vec![id]);
+                }
+            }
+        }
+
+        groups
+    }
+
+    // Every mesh in the world with its transform and material color applied, one submesh per entity part
+    pub fn bake_geometry(&self) -> MeshData {
+        let mut ids = self.get_all_ids().unwrap_or_default();
+        ids.sort_unstable();
+
+        let mut baked = MeshData::empty();
+
+        for id in ids {
+            if let (Some(mesh), Some(transform)) = (self.get_component_by_id::<Mesh>(id), self.get_component_by_id::<Transform>(id)) {
+                let mut data = mesh.data.read().clone();
+                data.transform(transform.model_matrix());
+
+                if let Some(material) = self.get_component_by_id::<Material>(id) {
+                    let color = material.color();
+
+                    for vertex in data.vertices.iter_mut() {
+                        vertex.color = [0, 1, 2].map(|i| vertex.color[i] * color[i]);
+                    }
+                }
+
+                baked.append(&data);
+            }
+        }
+
+        baked
+    }
+
+    // Dumps the baked geometry of the whole world to a binary PLY file
+    pub fn export_geometry(&self, path: &str) {
+        let baked = self.bake_geometry();
+
+        match baked.export_ply(path, PlyEncoding::BinaryLittleEndian) {
+            Ok(()) => logger::log_info(&format!("Exported {} triangles to '{}'", baked.indices.len() / 3, path), MessageEmitter::World),
+            Err(e) => logger::log_error(&e, MessageEmitter::World)
+        }
     }
 }
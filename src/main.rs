@@ -4,10 +4,10 @@ use cgmath::Deg;
 use hephaestus_lib::{
     engine::Engine,
     world::World,
-    light::DirectionalLight,
+    light::{ Light, ShadowSettings },
     logger::{ self, MessageEmitter },
     mesh_data::{ MeshType, PrimitiveType },
-    entity::Logic,
+    entity::{ Logic, MaterialData },
     camera,
 };
 
@@ -24,7 +24,7 @@ fn main() {
 
     let ground_plane = world.new_entity()
         .transform([0.0, -1.0, 0.0], [10.0; 3], [Deg(0.0), Deg(0.0), Deg(0.0)])
-        .mesh(MeshType::Primitive(PrimitiveType::Plane))
+        .mesh(MeshType::Primitive(PrimitiveType::Plane { subdivisions: 0 }))
         .material([0.8; 3]);
     world.add_entity(ground_plane);
 
@@ -45,12 +45,14 @@ fn main() {
     let sphere = world.new_entity()
         .transform([2.0, 0.0, -2.0], [0.2; 3], [Deg(0.0); 3])
         .mesh(MeshType::Primitive(PrimitiveType::Sphere(3)))
-        .material([1.0, 0.5, 0.2])
+        .pbr_material(MaterialData { metallic: 1.0, roughness: 0.3, ..MaterialData::new([1.0, 0.5, 0.2]) })
         .logic(Box::new(init), Logic::empty_update());
     world.add_entity(sphere);
     
-    let white_light = DirectionalLight::new([1.0, 2.0, 1.0, 1.0], [0.5, 0.5, 0.5]);
-    world.add_light(white_light);
+    let white_light = world.new_entity()
+        .transform([0.0; 3], [1.0; 3], [Deg(30.0), Deg(-20.0), Deg(0.0)])
+        .light(Light::directional([1.0; 3], 0.5).with_shadows(ShadowSettings::default()));
+    world.add_entity(white_light);
 
     logger::log_debug("debug debug debug", MessageEmitter::Engine);
     logger::log_info("info info info", MessageEmitter::Engine);
@@ -58,7 +60,9 @@ fn main() {
     logger::log_error("error error error", MessageEmitter::Engine);
 
     let event_loop = EventLoop::with_user_event();
-    let engine = Engine::initialize(world, &event_loop);
+    let engine = Engine::initialize_with_loading(world, &event_loop, |progress| {
+        logger::log_debug(&format!("Loading assets: {}/{}", progress.finished, progress.queued), MessageEmitter::Engine);
+    });
     engine.start(event_loop);
 }
 
@@ -2,16 +2,44 @@
 
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_color;
 layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_normal;
+layout(input_attachment_index = 3, set = 0, binding = 3) uniform subpassInput u_material;
+layout(input_attachment_index = 4, set = 0, binding = 4) uniform subpassInput u_emissive;
 
+// The irradiance is stored as order 2 spherical harmonics, one coefficient per vec4 (xyz)
 layout(set = 0, binding = 2) uniform AmbientData {
     vec3 color;
     float intensity;
+    vec4 irradiance[9];
 } ambient;
 
 layout(location = 0) out vec4 f_color;
 
+vec3 irradiance(vec3 n) {
+    return ambient.irradiance[0].xyz * 0.282095
+        + ambient.irradiance[1].xyz * 0.488603 * n.y
+        + ambient.irradiance[2].xyz * 0.488603 * n.z
+        + ambient.irradiance[3].xyz * 0.488603 * n.x
+        + ambient.irradiance[4].xyz * 1.092548 * n.x * n.y
+        + ambient.irradiance[5].xyz * 1.092548 * n.y * n.z
+        + ambient.irradiance[6].xyz * 0.315392 * (3.0 * n.z * n.z - 1.0)
+        + ambient.irradiance[7].xyz * 1.092548 * n.x * n.z
+        + ambient.irradiance[8].xyz * 0.546274 * (n.x * n.x - n.y * n.y);
+}
+
 void main() {
-    vec3 ambient_color = ambient.intensity * ambient.color;
-    vec3 combined_color = ambient_color * subpassLoad(u_color).rgb;
+    // Pixels nothing was drawn to can hold a zero normal
+    vec3 normal = subpassLoad(u_normal).xyz;
+    normal = dot(normal, normal) > 0.0 ? normalize(normal) : vec3(0.0, 1.0, 0.0);
+
+    vec3 ambient_color = ambient.intensity * ambient.color * max(irradiance(normal), vec3(0.0));
+
+    // Without a prefiltered environment, the specular reflection is approximated by the irradiance too, so metals still
+    // pick up ambient light in their own color
+    vec3 base = subpassLoad(u_color).rgb;
+    vec4 material = subpassLoad(u_material);
+    vec3 f0 = mix(vec3(0.04), base, material.x);
+    vec3 reflectance = base * (1.0 - material.x) * (1.0 - f0) + f0;
+
+    vec3 combined_color = ambient_color * reflectance * material.z + subpassLoad(u_emissive).rgb;
     f_color = vec4(combined_color, 1.0);
 }
@@ -0,0 +1,28 @@
+// Shared by the directional, point and spot lighting shaders, which pull it in with #include
+
+const float PI = 3.14159265;
+
+// Cook-Torrance: GGX distribution, Smith-Schlick geometry and Schlick's Fresnel, with the metallic-roughness
+// parameters of glTF. Scaled by pi so a white diffuse surface facing the light reflects all of it, like Lambert did.
+vec3 brdf(vec3 n, vec3 v, vec3 l, vec3 base, float metallic, float roughness) {
+    float n_dot_l = max(dot(n, l), 0.0);
+    float n_dot_v = max(dot(n, v), 1e-4);
+    vec3 h = normalize(v + l);
+    float n_dot_h = max(dot(n, h), 0.0);
+
+    float r = max(roughness, 0.04);
+    float alpha = r * r;
+    float d = n_dot_h * n_dot_h * (alpha * alpha - 1.0) + 1.0;
+    float distribution = alpha * alpha / (PI * d * d);
+
+    float k = (r + 1.0) * (r + 1.0) / 8.0;
+    float geometry = n_dot_l / (n_dot_l * (1.0 - k) + k) * n_dot_v / (n_dot_v * (1.0 - k) + k);
+
+    vec3 f0 = mix(vec3(0.04), base, metallic);
+    vec3 fresnel = f0 + (1.0 - f0) * pow(1.0 - max(dot(h, v), 0.0), 5.0);
+
+    vec3 specular = distribution * geometry * fresnel / (4.0 * n_dot_l * n_dot_v + 1e-4);
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - metallic) * base / PI;
+
+    return (diffuse + specular) * n_dot_l * PI;
+}
@@ -3,13 +3,45 @@
 layout(location = 0) in vec3 in_color;
 layout(location = 1) in vec3 in_normal;
 layout(location = 2) in vec2 texCoord;
+layout(location = 3) in vec4 in_tangent;
+layout(location = 4) flat in float receive_shadows;
+// Metallic, roughness, normal scale and occlusion strength
+layout(location = 5) flat in vec4 in_material;
+layout(location = 6) flat in vec3 in_emissive;
 
 layout(location = 0) out vec4 f_color;
-layout(location = 1) out vec3 f_normal;
+// w tells the lighting passes whether shadows fall on the surface
+layout(location = 1) out vec4 f_normal;
+// Metallic, roughness and ambient occlusion
+layout(location = 2) out vec4 f_material;
+layout(location = 3) out vec4 f_emissive;
 
+// Laid out like glTF: metallic in the blue channel of metallic_roughness, roughness in green, occlusion in red
 layout(set = 2, binding = 0) uniform sampler2D tex;
+layout(set = 2, binding = 1) uniform sampler2D normal_map;
+layout(set = 2, binding = 2) uniform sampler2D metallic_roughness_map;
+layout(set = 2, binding = 3) uniform sampler2D occlusion_map;
+layout(set = 2, binding = 4) uniform sampler2D emissive_map;
 
 void main() {
     f_color = vec4(in_color, 1.0) * texture(tex, texCoord);
-    f_normal = in_normal;
+
+    vec3 normal = normalize(in_normal);
+    vec3 tangent = in_tangent.xyz - normal * dot(normal, in_tangent.xyz);
+
+    if (dot(tangent, tangent) > 1e-8) {
+        tangent = normalize(tangent);
+        vec3 bitangent = cross(normal, tangent) * in_tangent.w;
+        vec3 mapped = texture(normal_map, texCoord).xyz * 2.0 - 1.0;
+        mapped.xy *= in_material.z;
+
+        normal = normalize(mat3(tangent, bitangent, normal) * mapped);
+    }
+
+    f_normal = vec4(normal, receive_shadows);
+
+    vec4 metallic_roughness = texture(metallic_roughness_map, texCoord);
+    float occlusion = 1.0 + in_material.w * (texture(occlusion_map, texCoord).r - 1.0);
+    f_material = vec4(in_material.x * metallic_roughness.b, in_material.y * metallic_roughness.g, occlusion, 1.0);
+    f_emissive = vec4(in_emissive * texture(emissive_map, texCoord).rgb, 1.0);
 }
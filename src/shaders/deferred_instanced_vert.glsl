@@ -0,0 +1,59 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+layout(location = 2) in vec3 normal;
+layout(location = 3) in vec2 uv;
+layout(location = 4) in vec4 tangent;
+
+// Per instance
+layout(location = 5) in vec4 model_0;
+layout(location = 6) in vec4 model_1;
+layout(location = 7) in vec4 model_2;
+layout(location = 8) in vec4 model_3;
+layout(location = 9) in vec3 normals_0;
+layout(location = 10) in vec3 normals_1;
+layout(location = 11) in vec3 normals_2;
+layout(location = 12) in vec3 instance_color;
+// Metallic, roughness, normal scale and occlusion strength
+layout(location = 13) in vec4 instance_material;
+layout(location = 14) in vec3 instance_emissive;
+
+layout(location = 0) out vec3 out_color;
+layout(location = 1) out vec3 out_normal;
+layout(location = 2) out vec2 texCoord;
+layout(location = 3) out vec4 out_tangent;
+layout(location = 4) flat out float out_receive_shadows;
+layout(location = 5) flat out vec4 out_material;
+layout(location = 6) flat out vec3 out_emissive;
+
+layout(set = 0, binding = 0) uniform VPData {
+    mat4 view;
+    mat4 proj;
+} vp;
+
+// Shared by every instance of the draw, applied after each instance's own transform
+layout(set = 1, binding = 0) uniform ModelData {
+    mat4 model;
+    mat4 normals;
+} model;
+
+// Shared by every instance, like the mesh they're drawn from
+layout(push_constant) uniform MeshData {
+    float receive_shadows;
+} mesh;
+
+void main() {
+    mat4 instance_model = model.model * mat4(model_0, model_1, model_2, model_3);
+    mat3 instance_normals = mat3(model.normals) * mat3(normals_0, normals_1, normals_2);
+
+    gl_Position = vp.proj * vp.view * instance_model * vec4(position, 1.0);
+    out_color = color * instance_color;
+    out_normal = instance_normals * normal;
+    // Tangents lie along the surface, so they follow the model matrix itself rather than the normal matrix
+    out_tangent = vec4(mat3(instance_model) * tangent.xyz, tangent.w);
+    texCoord = uv * vec2(1.0, -1.0);
+    out_receive_shadows = mesh.receive_shadows;
+    out_material = instance_material;
+    out_emissive = instance_emissive;
+}
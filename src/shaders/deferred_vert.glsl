@@ -4,10 +4,15 @@ layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 color;
 layout(location = 2) in vec3 normal;
 layout(location = 3) in vec2 uv;
+layout(location = 4) in vec4 tangent;
 
 layout(location = 0) out vec3 out_color;
 layout(location = 1) out vec3 out_normal;
 layout(location = 2) out vec2 texCoord;
+layout(location = 3) out vec4 out_tangent;
+layout(location = 4) flat out float out_receive_shadows;
+layout(location = 5) flat out vec4 out_material;
+layout(location = 6) flat out vec3 out_emissive;
 
 layout(set = 0, binding = 0) uniform VPData {
     mat4 view;
@@ -19,9 +24,26 @@ layout(set = 1, binding = 0) uniform ModelData {
     mat4 normals;
 } model;
 
+// The material's factors, per draw. color tints the vertex color; receive_shadows is 1 for meshes shadows can fall on,
+// 0 otherwise.
+layout(push_constant) uniform MaterialData {
+    vec3 color;
+    float receive_shadows;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normal_scale;
+    float occlusion_strength;
+} material;
+
 void main() {
     gl_Position = vp.proj * vp.view * model.model * vec4(position, 1.0);
-    out_color = color;
+    out_color = color * material.color;
     out_normal = mat3(model.normals) * normal;
+    // Tangents lie along the surface, so they follow the model matrix itself rather than the normal matrix
+    out_tangent = vec4(mat3(model.model) * tangent.xyz, tangent.w);
     texCoord = uv * vec2(1.0, -1.0);
+    out_receive_shadows = material.receive_shadows;
+    out_material = vec4(material.metallic, material.roughness, material.normal_scale, material.occlusion_strength);
+    out_emissive = material.emissive;
 }
@@ -2,18 +2,85 @@
 
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_color;
 layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_normals;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
 
-layout(set = 0, binding = 2) uniform DirectionalData {
+// The cascades' shadow maps lie side by side in one image. splits holds the distance from the camera where each cascade
+// ends, shadow.x the bias and shadow.y the size of a texel within a cascade.
+layout(set = 0, binding = 3) uniform DirectionalData {
     vec4 position;
     vec3 color;
+    int cascade_count;
+    mat4 cascades[4];
+    vec4 splits;
+    vec4 shadow;
 } directional;
 
+layout(set = 0, binding = 4) uniform sampler2DShadow u_shadow_map;
+// Metallic, roughness and ambient occlusion
+layout(input_attachment_index = 3, set = 0, binding = 5) uniform subpassInput u_material;
+
+// Shared by every light
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 inverse_view_projection;
+    mat4 view;
+    vec4 position;
+} camera;
+
+layout(location = 0) in vec2 ndc;
+
 layout(location = 0) out vec4 f_color;
 
+#include "brdf.glsl"
+
+// Fraction of the light reaching `world` through the cascade, filtered over 3x3 texels (PCF)
+float cascade_shadow(vec3 world, int cascade) {
+    vec4 light_clip = directional.cascades[cascade] * vec4(world, 1.0);
+    vec3 light_ndc = light_clip.xyz / light_clip.w;
+    vec2 uv = light_ndc.xy * 0.5 + 0.5;
+
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || light_ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    float texel = directional.shadow.y;
+    float reference = light_ndc.z - directional.shadow.x;
+    float lit = 0.0;
+
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            // Kept inside the cascade's tile, away from its neighbours
+            vec2 offset = clamp(uv + vec2(x, y) * texel, vec2(texel * 0.5), vec2(1.0 - texel * 0.5));
+            vec2 atlas = vec2((offset.x + float(cascade)) / float(directional.cascade_count), offset.y);
+            lit += texture(u_shadow_map, vec3(atlas, reference));
+        }
+    }
+
+    return lit / 9.0;
+}
+
+float shadow(vec3 world) {
+    float distance = -(camera.view * vec4(world, 1.0)).z;
+
+    for (int cascade = 0; cascade < directional.cascade_count; cascade++) {
+        if (distance < directional.splits[cascade]) {
+            return cascade_shadow(world, cascade);
+        }
+    }
+
+    // Beyond the last cascade
+    return 1.0;
+}
+
 void main() {
-    vec3 light_direction = normalize(directional.position.xyz + subpassLoad(u_normals).xyz);
-    float directional_intensity = max(dot(normalize(subpassLoad(u_normals).rgb), light_direction), 0.0);
-    vec3 directional_color = directional_intensity * directional.color;
-    vec3 combined_color = directional_color * subpassLoad(u_color).rgb;
-    f_color = vec4(combined_color, 1.0);
+    vec4 normal = subpassLoad(u_normals);
+    vec4 world = camera.inverse_view_projection * vec4(ndc, subpassLoad(u_depth).x, 1.0);
+    float shadowed = normal.w > 0.5 ? shadow(world.xyz / world.w) : 1.0;
+
+    // position holds the direction towards the light
+    vec3 light_direction = normalize(directional.position.xyz);
+    vec3 view = normalize(camera.position.xyz - world.xyz / world.w);
+    vec4 material = subpassLoad(u_material);
+
+    vec3 reflected = brdf(normalize(normal.xyz), view, light_direction, subpassLoad(u_color).rgb, material.x, material.y);
+    f_color = vec4(reflected * directional.color * shadowed, 1.0);
 }
@@ -0,0 +1,38 @@
+#version 450
+
+layout(local_size_x = 16, local_size_y = 16) in;
+
+layout(set = 0, binding = 0) uniform sampler2D u_hdr;
+
+layout(set = 0, binding = 1) buffer Histogram {
+    uint bins[256];
+} histogram;
+
+// Luminances are binned between min_log_luminance and min_log_luminance + log_luminance_range (log2)
+layout(push_constant) uniform Range {
+    float min_log_luminance;
+    float log_luminance_range;
+} range;
+
+// Counted per workgroup first, so only one atomic per bin reaches the buffer
+shared uint local_bins[256];
+
+void main() {
+    uint index = gl_LocalInvocationIndex;
+    local_bins[index] = 0u;
+    barrier();
+
+    ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+
+    if (all(lessThan(texel, textureSize(u_hdr, 0)))) {
+        float luminance = dot(texelFetch(u_hdr, texel, 0).rgb, vec3(0.2126, 0.7152, 0.0722));
+        float t = (log2(max(luminance, 1e-6)) - range.min_log_luminance) / range.log_luminance_range;
+
+        // Bin 0 holds the pixels too dark to count
+        uint bin = t < 0.0 ? 0u : min(uint(min(t, 1.0) * 255.0), 254u) + 1u;
+        atomicAdd(local_bins[bin], 1u);
+    }
+
+    barrier();
+    atomicAdd(histogram.bins[index], local_bins[index]);
+}
@@ -0,0 +1,45 @@
+#version 450
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_color;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_normals;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
+
+// position.w is the range, color.a the intensity
+layout(set = 0, binding = 3) uniform PointData {
+    mat4 volume;
+    vec4 position;
+    vec4 color;
+} point;
+
+// Metallic, roughness and ambient occlusion
+layout(input_attachment_index = 3, set = 0, binding = 4) uniform subpassInput u_material;
+
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 inverse_view_projection;
+    mat4 view;
+    vec4 position;
+} camera;
+
+layout(location = 0) in vec4 clip_position;
+
+layout(location = 0) out vec4 f_color;
+
+#include "brdf.glsl"
+
+void main() {
+    // World position of the surface covered by this pixel, from its depth
+    vec4 world = camera.inverse_view_projection * vec4(clip_position.xy / clip_position.w, subpassLoad(u_depth).x, 1.0);
+    vec3 to_light = point.position.xyz - world.xyz / world.w;
+    float distance = length(to_light);
+
+    // Inverse square falloff, windowed to reach zero at the light's range
+    float window = clamp(1.0 - pow(distance / point.position.w, 4.0), 0.0, 1.0);
+    float attenuation = window * window / (distance * distance + 1.0);
+
+    vec3 normal = normalize(subpassLoad(u_normals).xyz);
+    vec3 view = normalize(camera.position.xyz - world.xyz / world.w);
+    vec4 material = subpassLoad(u_material);
+    vec3 reflected = brdf(normal, view, to_light / max(distance, 1e-4), subpassLoad(u_color).rgb, material.x, material.y);
+
+    f_color = vec4(reflected * point.color.rgb * point.color.a * attenuation, 1.0);
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(location = 0) out vec4 clip_position;
+
+layout(set = 0, binding = 3) uniform PointData {
+    mat4 volume;
+    vec4 position;
+    vec4 color;
+} point;
+
+void main() {
+    clip_position = point.volume * vec4(position, 1.0);
+    gl_Position = clip_position;
+}
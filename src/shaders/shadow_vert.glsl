@@ -0,0 +1,18 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+// Per instance, laid out like deferred_instanced_vert.glsl's
+layout(location = 5) in vec4 model_0;
+layout(location = 6) in vec4 model_1;
+layout(location = 7) in vec4 model_2;
+layout(location = 8) in vec4 model_3;
+
+// The light's view and projection, shared by every caster in the tile
+layout(push_constant) uniform ShadowData {
+    mat4 light_space;
+} shadow;
+
+void main() {
+    gl_Position = shadow.light_space * mat4(model_0, model_1, model_2, model_3) * vec4(position, 1.0);
+}
@@ -0,0 +1,81 @@
+#version 450
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_color;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_normals;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_depth;
+
+// position.w is the range, color.a the intensity, cone.xy the cosines of the inner and outer cone angles.
+// shadow.x is 1 when the light casts shadows, shadow.y the bias and shadow.z the size of a shadow map texel.
+layout(set = 0, binding = 3) uniform SpotData {
+    mat4 volume;
+    vec4 position;
+    vec4 direction;
+    vec4 color;
+    vec4 cone;
+    mat4 shadow_projection;
+    vec4 shadow;
+} spot;
+
+layout(set = 0, binding = 4) uniform sampler2DShadow u_shadow_map;
+// Metallic, roughness and ambient occlusion
+layout(input_attachment_index = 3, set = 0, binding = 5) uniform subpassInput u_material;
+
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 inverse_view_projection;
+    mat4 view;
+    vec4 position;
+} camera;
+
+layout(location = 0) in vec4 clip_position;
+
+layout(location = 0) out vec4 f_color;
+
+#include "brdf.glsl"
+
+// Fraction of the light reaching `world`, filtered over 3x3 texels (PCF)
+float shadow(vec3 world) {
+    vec4 light_clip = spot.shadow_projection * vec4(world, 1.0);
+    vec3 light_ndc = light_clip.xyz / light_clip.w;
+    vec2 uv = light_ndc.xy * 0.5 + 0.5;
+
+    if (light_clip.w <= 0.0 || light_ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    float texel = spot.shadow.z;
+    float reference = light_ndc.z - spot.shadow.y;
+    float lit = 0.0;
+
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(u_shadow_map, vec3(clamp(uv + vec2(x, y) * texel, 0.0, 1.0), reference));
+        }
+    }
+
+    return lit / 9.0;
+}
+
+void main() {
+    // World position of the surface covered by this pixel, from its depth
+    vec4 world = camera.inverse_view_projection * vec4(clip_position.xy / clip_position.w, subpassLoad(u_depth).x, 1.0);
+    vec3 to_light = spot.position.xyz - world.xyz / world.w;
+    float distance = length(to_light);
+    vec3 light_direction = to_light / max(distance, 1e-4);
+
+    // Inverse square falloff, windowed to reach zero at the light's range
+    float window = clamp(1.0 - pow(distance / spot.position.w, 4.0), 0.0, 1.0);
+    float attenuation = window * window / (distance * distance + 1.0);
+
+    // Fades out between the inner and outer cone
+    float cone = smoothstep(spot.cone.y, spot.cone.x, dot(-light_direction, spot.direction.xyz));
+
+    vec4 normals = subpassLoad(u_normals);
+    vec3 normal = normalize(normals.xyz);
+    float shadowed = spot.shadow.x > 0.5 && normals.w > 0.5 ? shadow(world.xyz / world.w) : 1.0;
+
+    vec3 view = normalize(camera.position.xyz - world.xyz / world.w);
+    vec4 material = subpassLoad(u_material);
+    vec3 reflected = brdf(normal, view, light_direction, subpassLoad(u_color).rgb, material.x, material.y);
+
+    f_color = vec4(reflected * spot.color.rgb * spot.color.a * attenuation * cone * shadowed, 1.0);
+}
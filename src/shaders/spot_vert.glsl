@@ -0,0 +1,20 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(location = 0) out vec4 clip_position;
+
+layout(set = 0, binding = 3) uniform SpotData {
+    mat4 volume;
+    vec4 position;
+    vec4 direction;
+    vec4 color;
+    vec4 cone;
+    mat4 shadow_projection;
+    vec4 shadow;
+} spot;
+
+void main() {
+    clip_position = spot.volume * vec4(position, 1.0);
+    gl_Position = clip_position;
+}
@@ -0,0 +1,35 @@
+#version 450
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_hdr;
+
+// tonemapper is 0 for ACES, 1 for Reinhard and 2 for Uncharted 2. The curve's output is raised to gamma_power.
+layout(push_constant) uniform ToneData {
+    float exposure;
+    float gamma_power;
+    int tonemapper;
+} tone;
+
+layout(location = 0) out vec4 f_color;
+
+vec3 aces(vec3 x) {
+    return clamp(x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 hable(vec3 x) {
+    const float a = 0.15, b = 0.50, c = 0.10, d = 0.20, e = 0.02, f = 0.30;
+    return (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f;
+}
+
+void main() {
+    vec3 color = max(subpassLoad(u_hdr).rgb, vec3(0.0)) * tone.exposure;
+
+    if (tone.tonemapper == 1) {
+        color = color / (1.0 + color);
+    } else if (tone.tonemapper == 2) {
+        color = clamp(hable(2.0 * color) / hable(vec3(11.2)), 0.0, 1.0);
+    } else {
+        color = aces(color);
+    }
+
+    f_color = vec4(pow(color, vec3(tone.gamma_power)), 1.0);
+}